@@ -1,8 +1,9 @@
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::default::Default;
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -16,11 +17,42 @@ use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 
+use serde::de::{DeserializeSeed, EnumAccess, MapAccess, VariantAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserializer, Serializer};
+
 use blake2;
-use sha2::Sha512_256;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512_256};
 
 use crate::base64;
 use crate::error;
+use crate::mac::{self, EngineMac, MacKey};
+use crate::magic;
+use crate::sparse;
+
+mod archive;
+mod cbor;
+mod compare;
+mod copies;
+mod duplicates;
+mod export;
+mod import;
+mod indexed;
+mod mtree;
+mod recover;
+mod schema;
+mod selfcheck;
+
+pub use self::archive::ArchiveFormat;
+pub use self::copies::{CopyFinder, CopyPlan, CopySource};
+pub use self::duplicates::{DuplicateFile, DuplicateFinder, DuplicateGroup, Duplicates};
+pub use self::export::ListFormat;
+pub use self::import::Importer;
+pub use self::indexed::IndexedDatabase;
+pub use self::recover::{Lost, RecoveryReport};
+pub use self::selfcheck::{Position, Problem, SelfCheckReport};
 
 type Blake2b32 = blake2::Blake2b<U32>;
 
@@ -28,6 +60,8 @@ type Blake2b32 = blake2::Blake2b<U32>;
 pub struct Features {
     pub sha2: bool,
     pub blake2b: bool,
+    pub sha256: bool,        // Plain SHA-256, for compatibility with other tools
+    pub blocks: Option<u64>, // Block size for per-block hashes, if enabled
 }
 
 impl Default for Features {
@@ -35,6 +69,8 @@ impl Default for Features {
         Features {
             sha2: true,
             blake2b: false,
+            sha256: false,
+            blocks: None,
         }
     }
 }
@@ -44,7 +80,61 @@ impl Features {
         Features {
             sha2: checksum.sha2.is_some(),
             blake2b: checksum.blake2b.is_some(),
+            sha256: false,
+            blocks: None,
+        }
+    }
+
+    fn infer_from_metrics(metrics: &Metrics) -> Features {
+        Features {
+            sha2: metrics.sha2.is_some(),
+            blake2b: metrics.blake2b.is_some(),
+            sha256: metrics.sha256.is_some(),
+            blocks: metrics.blocks.as_ref().map(|blocks| blocks.size),
+        }
+    }
+}
+
+// Hashes that are only computed to verify files imported from checksum
+// lists (see import.rs), and never chosen for new databases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Legacy {
+    md5: bool,
+    sha1: bool,
+    crc32: bool,
+}
+
+impl Legacy {
+    fn infer_from_metrics(metrics: &Metrics) -> Legacy {
+        Legacy {
+            md5: metrics.md5.is_some(),
+            sha1: metrics.sha1.is_some(),
+            crc32: metrics.crc32.is_some(),
+        }
+    }
+}
+
+// Finds the hashes needed to check a database: files imported from
+// checksum lists can only be checked with the hashes in the list, so
+// those are computed whatever features were asked for
+struct Required {
+    features: Features,
+    legacy: Legacy,
+}
+
+impl EntryVisitor for Required {
+    fn visit_file(&mut self, _path: &Path, metrics: &Metrics) {
+        if !metrics.hashes_only {
+            return;
         }
+        let features = Features::infer_from_metrics(metrics);
+        self.features.sha2 |= features.sha2;
+        self.features.blake2b |= features.blake2b;
+        self.features.sha256 |= features.sha256;
+        let legacy = Legacy::infer_from_metrics(metrics);
+        self.legacy.md5 |= legacy.md5;
+        self.legacy.sha1 |= legacy.sha1;
+        self.legacy.crc32 |= legacy.crc32;
     }
 }
 
@@ -56,15 +146,62 @@ pub struct DatabaseChecksum {
     #[serde(skip_serializing_if = "Option::is_none")]
     blake2b: Option<HashSum>,
     size: u64,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac: Option<DatabaseMac>, // Keyed MAC of the database, if a key was used
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool, // Was the database recovered from a damaged one?
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatabaseMac {
+    algorithm: String,
+    tag: HashSum,
 }
 
 impl DatabaseChecksum {
+    // Partial databases lack whatever could not be recovered, so files
+    // missing from them may still exist
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    // Checksums written before the SHA2 fallback may hold only a size,
+    // which says nothing about the contents
+    pub fn has_hash(&self) -> bool {
+        self.sha2.is_some() || self.blake2b.is_some()
+    }
+
     fn diff(&self, new: &Self) -> bool {
         let changed = self.size != new.size;
         let changed =
             changed || (self.sha2.is_some() && new.sha2.is_some() && self.sha2 != new.sha2);
         changed || (self.blake2b.is_some() && new.blake2b.is_some() && self.blake2b != new.blake2b)
     }
+
+    // Prepares to check the MAC of the database when given a key. A
+    // database without a MAC is then refused.
+    fn mac_engine(&self, key: Option<&MacKey>) -> Result<Option<EngineMac>, error::Error> {
+        match (key, &self.mac) {
+            (None, _) => Ok(None),
+            (Some(key), Some(mac)) => Ok(Some(EngineMac::new(&mac.algorithm, key)?)),
+            (Some(_), None) => Err(error::Error::MissingMac),
+        }
+    }
+
+    fn verify_mac(&self, engine: Option<EngineMac>) -> Result<(), error::Error> {
+        match (engine, &self.mac) {
+            (Some(engine), Some(mac)) => {
+                if engine.verify(&mac.tag.0) {
+                    Ok(())
+                } else {
+                    Err(error::Error::MacMismatch)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl From<Metrics> for DatabaseChecksum {
@@ -73,6 +210,8 @@ impl From<Metrics> for DatabaseChecksum {
             sha2: metrics.sha2,
             blake2b: metrics.blake2b,
             size: metrics.size,
+            mac: None,
+            partial: false,
         }
     }
 }
@@ -82,31 +221,548 @@ pub struct Database(Entry);
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Entry {
-    Directory(BTreeMap<PathBuf, Entry>),
+    Directory(Directory),
     File(Metrics),
 }
 
 impl Default for Entry {
     fn default() -> Entry {
-        Entry::Directory(BTreeMap::default())
+        Entry::Directory(Directory::default())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Directory {
+    digest: Option<DirectoryDigest>,
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+// The digest is stored alongside the entries of a directory under the
+// key ".", which can never be the name of an entry. Databases written
+// before digests were introduced simply lack this key.
+const DIGEST_KEY: &str = ".";
+
+impl serde::Serialize for Directory {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let len = self.entries.len() + self.digest.is_some() as usize;
+        let mut map = serializer.serialize_map(Some(len))?;
+        if let Some(digest) = &self.digest {
+            map.serialize_entry(DIGEST_KEY, digest)?;
+        }
+        for (key, entry) in self.entries.iter() {
+            map.serialize_entry(key, entry)?;
+        }
+        map.end()
     }
 }
 
+impl<'de> serde::Deserialize<'de> for Directory {
+    fn deserialize<D>(deserializer: D) -> Result<Directory, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DirectoryVisitor;
+
+        impl<'de> Visitor<'de> for DirectoryVisitor {
+            type Value = Directory;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of directory entries")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Directory, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut directory = Directory::default();
+                while let Some(key) = map.next_key::<PathBuf>()? {
+                    if key.as_os_str() == DIGEST_KEY {
+                        directory.digest = Some(map.next_value()?);
+                    } else {
+                        directory.entries.insert(key, map.next_value()?);
+                    }
+                }
+                Ok(directory)
+            }
+        }
+
+        deserializer.deserialize_map(DirectoryVisitor)
+    }
+}
+
+// Digest over the names and contents of everything in a directory.
+// Two directories with matching digests have identical contents.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectoryDigest {
+    #[serde(rename = "sha2-512/256")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha2: Option<HashSum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blake2b: Option<HashSum>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<HashSum>,
+    files: u64, // Number of files in the directory and its subdirectories
+    #[serde(default)]
+    directories: u64, // Number of subdirectories, at any depth
+}
+
+impl DirectoryDigest {
+    // Do the digests share at least one algorithm, and agree on all
+    // algorithms they share?
+    fn matches(&self, other: &Self) -> bool {
+        let sha2 = self.sha2.is_some() && other.sha2.is_some();
+        let blake2b = self.blake2b.is_some() && other.blake2b.is_some();
+        let sha256 = self.sha256.is_some() && other.sha256.is_some();
+        (sha2 || blake2b || sha256)
+            && (!sha2 || self.sha2 == other.sha2)
+            && (!blake2b || self.blake2b == other.blake2b)
+            && (!sha256 || self.sha256 == other.sha256)
+            && self.files == other.files
+    }
+}
+
+impl std::fmt::Display for DirectoryDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(sha2) = &self.sha2 {
+            writeln!(f, "sha2-512/256: {}", ::base64::encode(&sha2.0))?;
+        }
+        if let Some(blake2b) = &self.blake2b {
+            writeln!(f, "blake2b: {}", ::base64::encode(&blake2b.0))?;
+        }
+        if let Some(sha256) = &self.sha256 {
+            writeln!(f, "sha256: {}", ::base64::encode(&sha256.0))?;
+        }
+        write!(f, "files: {}", self.files)
+    }
+}
+
+impl Directory {
+    // Computes digests bottom-up. Each entry, in sorted order,
+    // contributes the bytes of its name, a NUL byte, and then either 'F'
+    // followed by the file's size (u64, big endian) and hash, or 'D'
+    // followed by the subdirectory's digest, using the same algorithm
+    // throughout. If any entry lacks a hash for an algorithm, the
+    // digest omits that algorithm.
+    fn update_digest(&mut self) {
+        for entry in self.entries.values_mut() {
+            if let Entry::Directory(subdirectory) = entry {
+                subdirectory.update_digest();
+            }
+        }
+        self.update_own_digest();
+    }
+
+    // As above, assuming the digests of subdirectories are up to date
+    fn update_own_digest(&mut self) {
+        let (mut files, mut directories) = (0, 0);
+        for entry in self.entries.values() {
+            match entry {
+                Entry::Directory(subdirectory) => {
+                    let digest = subdirectory.digest.as_ref();
+                    files += digest.map_or(0, |d| d.files);
+                    directories += 1 + digest.map_or(0, |d| d.directories);
+                }
+                Entry::File(_) => files += 1,
+            }
+        }
+        self.digest = Some(DirectoryDigest {
+            sha2: self.compute_digest::<Sha512_256>(|m| m.sha2.as_ref(), |d| d.sha2.as_ref()),
+            blake2b: self
+                .compute_digest::<Blake2b32>(|m| m.blake2b.as_ref(), |d| d.blake2b.as_ref()),
+            sha256: self.compute_digest::<Sha256>(|m| m.sha256.as_ref(), |d| d.sha256.as_ref()),
+            files,
+            directories,
+        });
+    }
+
+    fn show_unchanged(&self, w: &mut dyn Write, path: &Path, depth: usize) -> io::Result<()> {
+        writeln!(
+            w,
+            "{}{}: 0 changed, 0 added, 0 removed, {} unchanged",
+            "| ".repeat(depth),
+            path.display(),
+            self.digest.as_ref().map_or(0, |d| d.files)
+        )?;
+        for (key, entry) in self.entries.iter() {
+            match entry {
+                Entry::Directory(subdirectory) => subdirectory.show_unchanged(w, key, depth + 1)?,
+                Entry::File(_) => {
+                    writeln!(w, "{}{} unchanged", "| ".repeat(depth + 1), key.display())?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compute_digest<D: Digest>(
+        &self,
+        file_hash: impl Fn(&Metrics) -> Option<&HashSum>,
+        directory_hash: impl Fn(&DirectoryDigest) -> Option<&HashSum>,
+    ) -> Option<HashSum> {
+        let mut engine = D::new();
+        for (name, entry) in self.entries.iter() {
+            engine.update(export::path_bytes(name));
+            engine.update([0]);
+            match entry {
+                Entry::File(metrics) => {
+                    engine.update(b"F");
+                    engine.update(metrics.size.to_be_bytes());
+                    engine.update(&file_hash(metrics)?.0);
+                }
+                Entry::Directory(subdirectory) => {
+                    engine.update(b"D");
+                    engine.update(&directory_hash(subdirectory.digest.as_ref()?)?.0);
+                }
+            }
+        }
+        Some(HashSum(Vec::from(engine.finalize().as_slice())))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct Metrics {
     #[serde(rename = "sha2-512/256")]
     #[serde(skip_serializing_if = "Option::is_none")]
     sha2: Option<HashSum>,
     #[serde(skip_serializing_if = "Option::is_none")]
     blake2b: Option<HashSum>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<HashSum>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    md5: Option<HashSum>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha1: Option<HashSum>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crc32: Option<HashSum>,
     size: u64,      // File size
     nul: bool,      // Does the file contain a NUL byte?
     nonascii: bool, // Does the file contain non-ASCII bytes?
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line_endings: Option<LineEndings>, // Counts of each newline sequence
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized: Option<HashSum>, // BLAKE2b of the contents with every newline made LF
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entropy: Option<u32>, // Shannon entropy of bytes, in millibits per byte
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>, // File format sniffed from the leading bytes
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blocks: Option<Box<Blocks>>, // Hashes of fixed-size blocks of the file, if enabled
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    zeros: Option<Zeros>, // Zero-filled and sparse regions of the file
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    hashes_only: bool, // Imported from a checksum list, so only the hashes are known
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    size_known: bool, // Imported along with its size (from an mtree specification)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive: Option<Box<ArchiveMetadata>>, // Metadata stored with the file in an archive
+}
+
+// Metadata of a file read from an archive (or an mtree specification),
+// as stored there. It is shown with changes, but never compared itself.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ArchiveMetadata {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>, // Permission bits
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gid: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mtime: Option<u64>, // Modification time, in seconds since the Unix epoch
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>, // Target of a hard link, whose contents the file shares
+}
+
+// Size of the aligned blocks checked for being entirely zero, and the
+// minimum run of such blocks (or bytes of holes) that is considered a
+// large zero region.
+const ZERO_BLOCK: u64 = 4096;
+const ZERO_MIN_RUN: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Zeros {
+    blocks: u64,      // Number of aligned blocks that are entirely zero
+    longest_run: u64, // Longest run of consecutive zero blocks
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    holes: Option<u64>, // Bytes in sparse holes, if the platform reports them
+}
+
+impl Zeros {
+    // Holes are only counted for contents known to have changed, since
+    // copying a file may make it sparse without altering a single byte
+    fn gained_region(old: &Self, new: &Self, changed_content: bool) -> bool {
+        let longer_run = new.longest_run >= ZERO_MIN_RUN && new.longest_run > old.longest_run;
+        let more_holes = match (old.holes, new.holes) {
+            (Some(old_h), Some(new_h)) if changed_content => {
+                new_h >= old_h + ZERO_MIN_RUN * ZERO_BLOCK
+            }
+            _ => false,
+        };
+        longer_run || more_holes
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Blocks {
+    size: u64, // Size of each block (the last block may be shorter)
+    #[serde(rename = "sha2-512/256")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha2: Option<Vec<HashSum>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blake2b: Option<Vec<HashSum>>,
+}
+
+impl Blocks {
+    fn diff(&self, new: &Self, old_size: u64, new_size: u64) -> Option<BlockDiff> {
+        if self.size != new.size {
+            return None;
+        }
+        let (old_sums, new_sums) = match (&self.sha2, &new.sha2, &self.blake2b, &new.blake2b) {
+            (Some(old), Some(new), _, _) => (old, new),
+            (_, _, Some(old), Some(new)) => (old, new),
+            _ => return None,
+        };
+
+        let end = old_size.max(new_size);
+        let total = old_sums.len().max(new_sums.len());
+        let mut changed: Vec<(u64, u64)> = Vec::new();
+        for i in 0..total {
+            if old_sums.get(i) == new_sums.get(i) {
+                continue;
+            }
+            let start = i as u64 * self.size;
+            let stop = (start + self.size).min(end);
+            match changed.last_mut() {
+                Some(last) if last.1 == start => last.1 = stop,
+                _ => changed.push((start, stop)),
+            }
+        }
+        if changed.is_empty() {
+            return None;
+        }
+        let blocks = changed
+            .iter()
+            .map(|(start, stop)| (stop - start).div_ceil(self.size))
+            .sum();
+        Some(BlockDiff {
+            blocks,
+            total: total as u64,
+            ranges: changed,
+        })
+    }
+}
+
+// Entropy (in millibits per byte) above which content looks compressed,
+// encrypted or random, and the minimum rise needed to get there before
+// the change is considered suspicious.
+const ENTROPY_HIGH: u32 = 7500;
+const ENTROPY_JUMP: u32 = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct LineEndings {
+    lf: u64,             // Number of LF (\n) sequences
+    crlf: u64,           // Number of CRLF (\r\n) sequences
+    cr: u64,             // Number of lone CR (\r) sequences
+    final_newline: bool, // Does the file end with a newline?
+}
+
+impl LineEndings {
+    fn total(&self) -> u64 {
+        self.lf + self.crlf + self.cr
+    }
+
+    // Name of the newline sequence that is used exclusively, if any.
+    fn style(&self) -> Option<&'static str> {
+        match (self.lf > 0, self.crlf > 0, self.cr > 0) {
+            (true, false, false) => Some("LF"),
+            (false, true, false) => Some("CRLF"),
+            (false, false, true) => Some("CR"),
+            _ => None,
+        }
+    }
+
+    // A conversion rewrites newline sequences without touching
+    // anything else: the number of lines and the presence of a final
+    // newline stay the same, and the size changes by exactly one byte
+    // for each CRLF gained or lost. (The caller also checks that the
+    // contents match once every newline is made LF.)
+    fn is_conversion(old: &Self, new: &Self, old_size: u64, new_size: u64) -> bool {
+        old != new
+            && old.total() == new.total()
+            && old.final_newline == new.final_newline
+            && new_size as i128 - old_size as i128 == new.crlf as i128 - old.crlf as i128
+    }
+
+    fn describe(&self) -> String {
+        if self.total() == 0 {
+            return "none".to_owned();
+        }
+        match self.style() {
+            Some(style) => style.to_owned(),
+            None => format!("mixed ({} LF, {} CRLF, {} CR)", self.lf, self.crlf, self.cr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct HashSum(#[serde(with = "base64")] Vec<u8>);
 
+impl HashSum {
+    fn hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn describe(&self) -> String {
+        format!("{} ({})", self.hex(), ::base64::encode(&self.0))
+    }
+}
+
+impl Metrics {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The size, unless the file was imported without it
+    pub(super) fn known_size(&self) -> Option<u64> {
+        Some(self.size).filter(|_| !self.hashes_only || self.size_known)
+    }
+
+    // Do the sizes differ, where both are known?
+    pub(super) fn size_differs(&self, other: &Metrics) -> bool {
+        match (self.known_size(), other.known_size()) {
+            (Some(size), Some(other)) => size != other,
+            _ => false,
+        }
+    }
+
+    // Prints every recorded metric, showing both values where they differ
+    fn show_changes(
+        w: &mut dyn Write,
+        old: &Metrics,
+        new: &Metrics,
+        prefix: &str,
+    ) -> io::Result<()> {
+        let mut field = |name: &str, old: Option<String>, new: Option<String>| match (old, new) {
+            (None, None) => Ok(()),
+            (Some(old), Some(new)) if old == new => writeln!(w, "{}{}: {}", prefix, name, old),
+            (old, new) => writeln!(
+                w,
+                "{}{}: {} -> {}",
+                prefix,
+                name,
+                old.unwrap_or_else(|| "none".to_owned()),
+                new.unwrap_or_else(|| "none".to_owned())
+            ),
+        };
+        let size = |m: &Metrics| m.known_size().map(|size| size.to_string());
+        let sha2 = |m: &Metrics| m.sha2.as_ref().map(HashSum::describe);
+        let blake2b = |m: &Metrics| m.blake2b.as_ref().map(HashSum::describe);
+        let sha256 = |m: &Metrics| m.sha256.as_ref().map(HashSum::describe);
+        let md5 = |m: &Metrics| m.md5.as_ref().map(HashSum::describe);
+        let sha1 = |m: &Metrics| m.sha1.as_ref().map(HashSum::describe);
+        let crc32 = |m: &Metrics| m.crc32.as_ref().map(HashSum::describe);
+        let nul = |m: &Metrics| Some(m.nul.to_string());
+        let nonascii = |m: &Metrics| Some(m.nonascii.to_string());
+        let line_endings = |m: &Metrics| {
+            m.line_endings.map(|le| {
+                let final_newline = if le.final_newline {
+                    "final newline"
+                } else {
+                    "no final newline"
+                };
+                format!("{}, {}", le.describe(), final_newline)
+            })
+        };
+        let entropy = |m: &Metrics| {
+            m.entropy
+                .map(|e| format!("{:.2} bits per byte", e as f64 / 1000.0))
+        };
+        let format = |m: &Metrics| m.format.clone();
+        let zeros = |m: &Metrics| {
+            m.zeros.map(|z| {
+                let holes = match z.holes {
+                    Some(holes) => format!(", {} bytes in holes", holes),
+                    None => String::new(),
+                };
+                format!(
+                    "{} zero blocks, longest run {}{}",
+                    z.blocks, z.longest_run, holes
+                )
+            })
+        };
+        let blocks = |m: &Metrics| {
+            m.blocks
+                .as_ref()
+                .map(|b| format!("hashed in blocks of {} bytes", b.size))
+        };
+        let mode = |m: &Metrics| Some(format!("{:04o}", m.archive.as_ref()?.mode?));
+        let owner = |m: &Metrics| {
+            let archive = m.archive.as_ref()?;
+            let id = |name: &Option<String>, id: Option<u64>| match (name, id) {
+                (Some(name), Some(id)) => format!("{} ({})", name, id),
+                (Some(name), None) => name.clone(),
+                (None, Some(id)) => id.to_string(),
+                (None, None) => "unknown".to_owned(),
+            };
+            Some(format!(
+                "{}:{}",
+                id(&archive.user, archive.uid),
+                id(&archive.group, archive.gid)
+            ))
+        };
+        let mtime = |m: &Metrics| Some(m.archive.as_ref()?.mtime?.to_string());
+        let link = |m: &Metrics| m.archive.as_ref()?.link.clone();
+        field("size", size(old), size(new))?;
+        field("sha2-512/256", sha2(old), sha2(new))?;
+        field("blake2b", blake2b(old), blake2b(new))?;
+        field("sha256", sha256(old), sha256(new))?;
+        field("md5", md5(old), md5(new))?;
+        field("sha1", sha1(old), sha1(new))?;
+        field("crc32", crc32(old), crc32(new))?;
+        field("nul", nul(old), nul(new))?;
+        field("nonascii", nonascii(old), nonascii(new))?;
+        field("line endings", line_endings(old), line_endings(new))?;
+        field("entropy", entropy(old), entropy(new))?;
+        field("format", format(old), format(new))?;
+        field("zeros", zeros(old), zeros(new))?;
+        field("blocks", blocks(old), blocks(new))?;
+        field("mode", mode(old), mode(new))?;
+        field("owner", owner(old), owner(new))?;
+        field("mtime", mtime(old), mtime(new))?;
+        field("hard link to", link(old), link(new))?;
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 struct EngineSize(u64);
 impl EngineSize {
@@ -121,6 +777,7 @@ impl EngineSize {
 #[derive(Default)]
 struct EngineNul(bool);
 impl EngineNul {
+    #[allow(clippy::manual_contains)] // Kept as originally written
     fn input(&mut self, input: &[u8]) {
         self.0 = self.0 || input.iter().any(|x| *x == 0);
     }
@@ -140,16 +797,211 @@ impl EngineNonascii {
     }
 }
 
+#[derive(Default)]
+struct EngineLineEndings {
+    counts: LineEndings,
+    pending_cr: bool, // Was the last byte of the previous buffer a CR?
+    last: Option<u8>,
+    normalized: Blake2b32,
+    buffer: Vec<u8>, // Normalized copy of the current input
+}
+impl EngineLineEndings {
+    fn input(&mut self, input: &[u8]) {
+        self.buffer.clear();
+        for &b in input {
+            if self.pending_cr {
+                self.pending_cr = false;
+                self.buffer.push(b'\n');
+                if b == b'\n' {
+                    self.counts.crlf += 1;
+                    continue;
+                }
+                self.counts.cr += 1;
+            }
+            if b == b'\r' {
+                self.pending_cr = true;
+            } else {
+                if b == b'\n' {
+                    self.counts.lf += 1;
+                }
+                self.buffer.push(b);
+            }
+        }
+        self.normalized.update(&self.buffer);
+        if let Some(&b) = input.last() {
+            self.last = Some(b);
+        }
+    }
+    fn result(mut self) -> (LineEndings, HashSum) {
+        if self.pending_cr {
+            self.counts.cr += 1;
+            self.normalized.update(b"\n");
+        }
+        self.counts.final_newline = matches!(self.last, Some(b'\n') | Some(b'\r'));
+        let normalized = HashSum(Vec::from(self.normalized.finalize().as_slice()));
+        (self.counts, normalized)
+    }
+}
+
+struct EngineEntropy([u64; 256]);
+impl Default for EngineEntropy {
+    fn default() -> EngineEntropy {
+        EngineEntropy([0; 256])
+    }
+}
+impl EngineEntropy {
+    fn input(&mut self, input: &[u8]) {
+        for &b in input {
+            self.0[b as usize] += 1;
+        }
+    }
+    fn result(self) -> u32 {
+        let total: u64 = self.0.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let total = total as f64;
+        let entropy: f64 = self
+            .0
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / total;
+                -p * p.log2()
+            })
+            .sum();
+        (entropy * 1000.0).round() as u32
+    }
+}
+
+#[derive(Default)]
+struct EngineFormat(Vec<u8>);
+impl EngineFormat {
+    fn input(&mut self, input: &[u8]) {
+        if self.0.len() < magic::HEAD_LEN {
+            let n = input.len().min(magic::HEAD_LEN - self.0.len());
+            self.0.extend_from_slice(&input[..n]);
+        }
+    }
+    fn result(self) -> String {
+        magic::sniff(&self.0).to_owned()
+    }
+}
+
+struct EngineBlocks {
+    size: u64,
+    filled: u64, // Bytes hashed so far in the current block
+    sha2: Option<(Sha512_256, Vec<HashSum>)>,
+    blake2b: Option<(Blake2b32, Vec<HashSum>)>,
+}
+impl EngineBlocks {
+    fn new(size: u64, features: Features) -> EngineBlocks {
+        EngineBlocks {
+            size,
+            filled: 0,
+            sha2: if features.sha2 {
+                Some((Sha512_256::default(), Vec::new()))
+            } else {
+                None
+            },
+            blake2b: if features.blake2b {
+                Some((Blake2b32::new(), Vec::new()))
+            } else {
+                None
+            },
+        }
+    }
+    fn input(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            let n = (self.size - self.filled).min(input.len() as u64) as usize;
+            let (head, tail) = input.split_at(n);
+            self.sha2.iter_mut().for_each(|(e, _)| e.update(head));
+            self.blake2b.iter_mut().for_each(|(e, _)| e.update(head));
+            self.filled += n as u64;
+            if self.filled == self.size {
+                self.finish_block();
+            }
+            input = tail;
+        }
+    }
+    fn finish_block(&mut self) {
+        if let Some((e, sums)) = &mut self.sha2 {
+            sums.push(HashSum(Vec::from(e.finalize_reset().as_slice())));
+        }
+        if let Some((e, sums)) = &mut self.blake2b {
+            sums.push(HashSum(Vec::from(e.finalize_reset().as_slice())));
+        }
+        self.filled = 0;
+    }
+    fn result(mut self) -> Blocks {
+        if self.filled > 0 {
+            self.finish_block();
+        }
+        Blocks {
+            size: self.size,
+            sha2: self.sha2.map(|(_, sums)| sums),
+            blake2b: self.blake2b.map(|(_, sums)| sums),
+        }
+    }
+}
+
+#[derive(Default)]
+struct EngineZeros {
+    counts: Zeros,
+    run: u64,      // Current run of zero blocks
+    filled: u64,   // Bytes seen so far in the current block
+    nonzero: bool, // Does the current block contain a nonzero byte?
+}
+impl EngineZeros {
+    fn input(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            let n = (ZERO_BLOCK - self.filled).min(input.len() as u64) as usize;
+            let (head, tail) = input.split_at(n);
+            self.nonzero = self.nonzero || head.iter().any(|&b| b != 0);
+            self.filled += n as u64;
+            if self.filled == ZERO_BLOCK {
+                if self.nonzero {
+                    self.run = 0;
+                } else {
+                    self.counts.blocks += 1;
+                    self.run += 1;
+                    self.counts.longest_run = self.counts.longest_run.max(self.run);
+                }
+                self.filled = 0;
+                self.nonzero = false;
+            }
+            input = tail;
+        }
+    }
+    fn result(self) -> Zeros {
+        // A trailing partial block is never counted
+        self.counts
+    }
+}
+
 struct Engines {
     sha2: Option<Sha512_256>,
     blake2b: Option<Blake2b32>,
+    sha256: Option<Sha256>,
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    crc32: Option<crc32fast::Hasher>,
     size: EngineSize,
     nul: EngineNul,
     nonascii: EngineNonascii,
+    line_endings: EngineLineEndings,
+    entropy: EngineEntropy,
+    format: EngineFormat,
+    blocks: Option<EngineBlocks>,
+    zeros: EngineZeros,
 }
 
 impl Engines {
     fn new(features: Features) -> Engines {
+        Engines::with_legacy(features, Legacy::default())
+    }
+
+    fn with_legacy(features: Features, legacy: Legacy) -> Engines {
         Engines {
             sha2: if features.sha2 {
                 Some(Sha512_256::default())
@@ -161,9 +1013,31 @@ impl Engines {
             } else {
                 None
             },
+            sha256: if features.sha256 {
+                Some(Sha256::new())
+            } else {
+                None
+            },
+            md5: if legacy.md5 { Some(Md5::new()) } else { None },
+            sha1: if legacy.sha1 { Some(Sha1::new()) } else { None },
+            crc32: if legacy.crc32 {
+                Some(crc32fast::Hasher::new())
+            } else {
+                None
+            },
             size: EngineSize::default(),
             nul: EngineNul::default(),
             nonascii: EngineNonascii::default(),
+            line_endings: EngineLineEndings::default(),
+            entropy: EngineEntropy::default(),
+            format: EngineFormat::default(),
+            blocks: match features.blocks {
+                Some(size) if features.sha2 || features.blake2b => {
+                    Some(EngineBlocks::new(size, features))
+                }
+                _ => None,
+            },
+            zeros: EngineZeros::default(),
         }
     }
 }
@@ -172,11 +1046,21 @@ impl Engines {
     fn input(&mut self, input: &[u8]) {
         self.sha2.iter_mut().for_each(|e| e.update(input));
         self.blake2b.iter_mut().for_each(|e| e.update(input));
+        self.sha256.iter_mut().for_each(|e| e.update(input));
+        self.md5.iter_mut().for_each(|e| e.update(input));
+        self.sha1.iter_mut().for_each(|e| e.update(input));
+        self.crc32.iter_mut().for_each(|e| e.update(input));
         self.size.input(input);
         self.nul.input(input);
         self.nonascii.input(input);
+        self.line_endings.input(input);
+        self.entropy.input(input);
+        self.format.input(input);
+        self.blocks.iter_mut().for_each(|e| e.input(input));
+        self.zeros.input(input);
     }
     fn result(self) -> Metrics {
+        let (line_endings, normalized) = self.line_endings.result();
         Metrics {
             sha2: self
                 .sha2
@@ -184,21 +1068,62 @@ impl Engines {
             blake2b: self
                 .blake2b
                 .map(|e| HashSum(Vec::from(e.finalize().as_slice()))),
+            sha256: self
+                .sha256
+                .map(|e| HashSum(Vec::from(e.finalize().as_slice()))),
+            md5: self
+                .md5
+                .map(|e| HashSum(Vec::from(e.finalize().as_slice()))),
+            sha1: self
+                .sha1
+                .map(|e| HashSum(Vec::from(e.finalize().as_slice()))),
+            crc32: self
+                .crc32
+                .map(|e| HashSum(e.finalize().to_be_bytes().to_vec())),
             size: self.size.result(),
             nul: self.nul.result(),
             nonascii: self.nonascii.result(),
+            line_endings: Some(line_endings),
+            normalized: Some(normalized),
+            entropy: Some(self.entropy.result()),
+            format: Some(self.format.result()),
+            blocks: self.blocks.map(|e| Box::new(e.result())),
+            zeros: Some(self.zeros.result()),
+            hashes_only: false,
+            size_known: false,
+            archive: None,
         }
     }
 }
 
-fn compute_metrics(path: impl AsRef<Path>, features: Features) -> Result<Metrics, error::Error> {
+fn compute_metrics(
+    path: impl AsRef<Path>,
+    features: Features,
+    legacy: Legacy,
+) -> Result<Metrics, error::Error> {
     let mut f = File::open(path)?;
 
-    let mut engines = Engines::new(features);
+    // Probe for holes first, since this moves the file offset
+    let holes = sparse::hole_bytes(&f);
+    f.seek(SeekFrom::Start(0))?;
+
+    let mut metrics = compute_metrics_from(f, features, legacy)?;
+    if let Some(zeros) = &mut metrics.zeros {
+        zeros.holes = holes;
+    }
+    Ok(metrics)
+}
+
+fn compute_metrics_from(
+    mut r: impl Read,
+    features: Features,
+    legacy: Legacy,
+) -> Result<Metrics, error::Error> {
+    let mut engines = Engines::with_legacy(features, legacy);
 
     let mut buffer = [0; 4096];
     loop {
-        let n = f.read(&mut buffer[..])?;
+        let n = r.read(&mut buffer[..])?;
         if n == 0 {
             break;
         }
@@ -234,7 +1159,7 @@ impl Entry {
         // any duplicates. (And the database, after construction, is
         // always immutable.)
         match self {
-            Entry::Directory(entries) => {
+            Entry::Directory(Directory { entries, .. }) => {
                 let mut components = path.components();
                 let count = components.clone().count();
                 let first =
@@ -251,13 +1176,36 @@ impl Entry {
         }
     }
 
+    fn kind(&self) -> &'static str {
+        match self {
+            Entry::Directory(_) => "directory",
+            Entry::File(_) => "file",
+        }
+    }
+
+    fn count_files(&self) -> u64 {
+        match self {
+            Entry::Directory(directory) => match &directory.digest {
+                Some(digest) => digest.files,
+                None => directory.entries.values().map(Entry::count_files).sum(),
+            },
+            Entry::File(_) => 1,
+        }
+    }
+
+    fn describe_contents(&self) -> String {
+        match self {
+            Entry::Directory(_) => format!(" (directory with {} files)", self.count_files()),
+            Entry::File(_) => String::new(),
+        }
+    }
+
     fn lookup(&self, path: &Path) -> Option<&Entry> {
         match self {
-            Entry::Directory(entries) => {
+            Entry::Directory(Directory { entries, .. }) => {
                 let mut components = path.components();
                 let count = components.clone().count();
-                let first =
-                    Path::new(components.next().expect("unreachable").as_os_str()).to_owned();
+                let first = Path::new(components.next()?.as_os_str()).to_owned();
                 let rest = components.as_path().to_owned();
                 if count > 1 {
                     entries
@@ -267,16 +1215,37 @@ impl Entry {
                     entries.get(&first)
                 }
             }
-            Entry::File(_) => unreachable!(),
+            Entry::File(_) => None,
+        }
+    }
+
+    fn walk(&self, path: &mut PathBuf, visitor: &mut impl EntryVisitor) {
+        match self {
+            Entry::Directory(directory) => {
+                visitor.enter_directory(path);
+                if let Some(digest) = &directory.digest {
+                    visitor.visit_digest(path, digest);
+                }
+                for (key, entry) in directory.entries.iter() {
+                    path.push(key);
+                    entry.walk(path, visitor);
+                    path.pop();
+                }
+                visitor.leave_directory(path);
+            }
+            Entry::File(metrics) => visitor.visit_file(path, metrics),
         }
     }
 }
 
 #[derive(Debug)]
-pub enum EntryDiff {
-    Directory(BTreeMap<PathBuf, EntryDiff>, DirectoryDiff),
-    File(MetricsDiff),
-    KindChanged,
+pub enum EntryDiff<'a> {
+    Directory(BTreeMap<PathBuf, EntryDiff<'a>>, DirectoryDiff),
+    Identical(&'a Directory), // Subtree known to be unchanged from its digest
+    File(Box<MetricsDiff<'a>>),
+    KindChanged(&'a Entry, &'a Entry),
+    Added(&'a Entry),
+    Removed(&'a Entry),
 }
 
 #[derive(Debug)]
@@ -288,26 +1257,171 @@ pub struct DirectoryDiff {
 }
 
 #[derive(Debug)]
-pub struct MetricsDiff {
+pub struct MetricsDiff<'a> {
+    old: &'a Metrics,
+    new: &'a Metrics,
     changed_content: bool,
     zeroed: bool,
     changed_nul: bool,
     changed_nonascii: bool,
+    converted_line_endings: Option<(LineEndings, LineEndings)>,
+    increased_entropy: Option<(u32, u32)>,
+    changed_format: Option<(String, String)>,
+    mismatched_extension: Option<(String, &'static str)>,
+    changed_blocks: Option<BlockDiff>,
+    gained_zeros: Option<(Zeros, Zeros)>,
+}
+
+#[derive(Debug)]
+pub struct BlockDiff {
+    blocks: u64,             // Number of blocks that differ
+    total: u64,              // Number of blocks in the larger version
+    ranges: Vec<(u64, u64)>, // Byte ranges covered by differing blocks
+}
+
+// Maximum number of byte ranges to print for a single file
+const BLOCK_RANGES_SHOWN: usize = 8;
+
+impl BlockDiff {
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+}
+
+impl std::fmt::Display for BlockDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} blocks differ at bytes ",
+            self.blocks, self.total
+        )?;
+        let shown: Vec<_> = self
+            .ranges
+            .iter()
+            .take(BLOCK_RANGES_SHOWN)
+            .map(|(start, stop)| format!("{}..{}", start, stop))
+            .collect();
+        write!(f, "{}", shown.join(", "))?;
+        if self.ranges.len() > BLOCK_RANGES_SHOWN {
+            write!(f, " and {} more", self.ranges.len() - BLOCK_RANGES_SHOWN)?;
+        }
+        Ok(())
+    }
+}
+
+impl MetricsDiff<'_> {
+    pub fn changed_blocks(&self) -> Option<&BlockDiff> {
+        self.changed_blocks.as_ref()
+    }
+
+    fn reason(&self) -> String {
+        if self.old.size_differs(self.new) {
+            format!(
+                "size changed from {} to {} bytes",
+                self.old.size, self.new.size
+            )
+        } else if self.old.hashes_only || self.new.hashes_only {
+            "contents differ".to_owned()
+        } else if self.changed_content {
+            "contents differ, size unchanged".to_owned()
+        } else {
+            "contents differ".to_owned()
+        }
+    }
+
+    fn is_suspicious(&self) -> bool {
+        self.zeroed
+            || self.changed_nul
+            || self.changed_nonascii
+            || self.increased_entropy.is_some()
+            || self.changed_format.is_some()
+            || self.mismatched_extension.is_some()
+            || self.gained_zeros.is_some()
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum DiffSummary {
     NoChanges,
     Changes,
+    ConvertedLineEndings,
     Suspicious,
+    MassModification,
 }
 
-impl EntryDiff {
-    fn show_diff(&self, path: &Path, depth: usize) {
+// Minimum number of files (or directories) that must be affected
+// before a tree-wide pattern is reported as a mass modification.
+const MASS_MIN_FILES: u64 = 3;
+const MASS_MIN_DIRECTORIES: u64 = 3;
+
+#[derive(Debug)]
+pub enum MassModification {
+    EntropyRise {
+        directory: PathBuf,
+        files: u64,
+        total: u64,
+    },
+    NewExtension {
+        extension: OsString,
+        files: u64,
+    },
+    RepeatedNewFile {
+        name: OsString,
+        directories: u64,
+    },
+}
+
+impl std::fmt::Display for MassModification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MassModification::EntropyRise {
+                directory,
+                files,
+                total,
+            } => write!(
+                f,
+                "{} of {} files in {} now have high-entropy content",
+                files,
+                total,
+                directory.display()
+            ),
+            MassModification::NewExtension { extension, files } => write!(
+                f,
+                "{} files were renamed to gain the extension .{}",
+                files,
+                extension.to_string_lossy()
+            ),
+            MassModification::RepeatedNewFile { name, directories } => write!(
+                f,
+                "new file {} appeared in {} directories",
+                name.to_string_lossy(),
+                directories
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MassModificationStats {
+    directories: u64,
+    new_extensions: BTreeMap<OsString, u64>,
+    new_names: BTreeMap<OsString, u64>,
+    findings: Vec<MassModification>,
+}
+
+impl EntryDiff<'_> {
+    fn show_diff(
+        &self,
+        w: &mut dyn Write,
+        path: &Path,
+        depth: usize,
+        verbosity: u64,
+    ) -> io::Result<()> {
         match self {
             EntryDiff::Directory(entries, diff) => {
-                if diff.changed > 0 || diff.added > 0 || diff.removed > 0 {
-                    println!(
+                if diff.changed > 0 || diff.added > 0 || diff.removed > 0 || verbosity >= 2 {
+                    writeln!(
+                        w,
                         "{}{}: {} changed, {} added, {} removed, {} unchanged",
                         "| ".repeat(depth),
                         path.display(),
@@ -315,40 +1429,215 @@ impl EntryDiff {
                         diff.added,
                         diff.removed,
                         diff.unchanged
-                    );
+                    )?;
                     for (key, entry) in entries.iter() {
-                        entry.show_diff(key, depth + 1);
+                        entry.show_diff(w, key, depth + 1, verbosity)?;
                     }
                 }
             }
+            EntryDiff::Identical(directory) => {
+                if verbosity >= 2 {
+                    directory.show_unchanged(w, path, depth)?;
+                }
+            }
             EntryDiff::File(diff) => {
-                if diff.zeroed || diff.changed_nul || diff.changed_nonascii {
-                    println!("{}{} changed", "| ".repeat(depth), path.display());
-                    if diff.zeroed {
-                        println!("{}> suspicious: file was truncated", "##".repeat(depth));
+                let indent = "| ".repeat(depth);
+                let details = format!("{}  ", indent);
+                if !diff.changed_content && !diff.is_suspicious() {
+                    if verbosity >= 2 {
+                        writeln!(w, "{}{} unchanged", indent, path.display())?;
                     }
-                    if diff.changed_nul {
-                        println!(
+                    return Ok(());
+                }
+                writeln!(w, "{}{} changed: {}", indent, path.display(), diff.reason())?;
+                if verbosity >= 1 {
+                    Metrics::show_changes(w, diff.old, diff.new, &details)?;
+                }
+                if let Some(blocks) = &diff.changed_blocks {
+                    writeln!(w, "{}> {}", details, blocks)?;
+                }
+                if diff.zeroed {
+                    writeln!(w, "{}> suspicious: file was truncated", details)?;
+                }
+                if diff.changed_nul {
+                    if diff.old.nul {
+                        writeln!(
+                            w,
+                            "{}> suspicious: original had NUL bytes, but now does not",
+                            details
+                        )?;
+                    } else {
+                        writeln!(
+                            w,
                             "{}> suspicious: original had no NUL bytes, but now does",
-                            "##".repeat(depth)
-                        );
+                            details
+                        )?;
                     }
-                    if diff.changed_nonascii {
-                        println!(
+                }
+                if diff.changed_nonascii {
+                    if diff.old.nonascii {
+                        writeln!(
+                            w,
+                            "{}> suspicious: original had non-ASCII bytes, but now does not",
+                            details
+                        )?;
+                    } else {
+                        writeln!(
+                            w,
                             "{}> suspicious: original had no non-ASCII bytes, but now does",
-                            "##".repeat(depth)
-                        );
+                            details
+                        )?;
+                    }
+                }
+                if let Some((old, new)) = diff.converted_line_endings {
+                    writeln!(
+                        w,
+                        "{}> line endings converted from {} to {}, content otherwise consistent",
+                        details,
+                        old.describe(),
+                        new.describe()
+                    )?;
+                }
+                if let Some((old, new)) = diff.increased_entropy {
+                    writeln!(
+                        w,
+                        "{}> suspicious: entropy rose from {:.2} to {:.2} bits per byte",
+                        details,
+                        old as f64 / 1000.0,
+                        new as f64 / 1000.0
+                    )?;
+                }
+                if let Some((old, new)) = &diff.changed_format {
+                    writeln!(
+                        w,
+                        "{}> suspicious: file format changed from {} to {}",
+                        details, old, new
+                    )?;
+                }
+                if let Some((old, new)) = diff.gained_zeros {
+                    let holes = match new.holes {
+                        Some(holes) if holes > 0 => format!(", {} bytes in holes", holes),
+                        _ => String::new(),
+                    };
+                    writeln!(
+                        w,
+                        "{}> suspicious: zero-filled regions grew from {} to {} bytes{}",
+                        details,
+                        old.blocks * ZERO_BLOCK,
+                        new.blocks * ZERO_BLOCK,
+                        holes
+                    )?;
+                }
+                if let Some((new, expected)) = &diff.mismatched_extension {
+                    writeln!(
+                        w,
+                        "{}> suspicious: file format is now {}, but its extension implies {}",
+                        details, new, expected
+                    )?;
+                }
+            }
+            EntryDiff::KindChanged(old, new) => {
+                writeln!(
+                    w,
+                    "{}{} changed: was a {}, now a {}",
+                    "| ".repeat(depth),
+                    path.display(),
+                    old.kind(),
+                    new.kind()
+                )?;
+            }
+            EntryDiff::Added(entry) => {
+                writeln!(
+                    w,
+                    "{}{} added{}",
+                    "| ".repeat(depth),
+                    path.display(),
+                    entry.describe_contents()
+                )?;
+            }
+            EntryDiff::Removed(entry) => {
+                writeln!(
+                    w,
+                    "{}{} removed{}",
+                    "| ".repeat(depth),
+                    path.display(),
+                    entry.describe_contents()
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_mass_modification(&self, path: &Path, stats: &mut MassModificationStats) {
+        if let EntryDiff::Directory(entries, _) = self {
+            stats.directories += 1;
+            let mut files = 0;
+            let mut high_entropy = 0;
+            for (key, entry) in entries.iter() {
+                match entry {
+                    EntryDiff::Directory(_, _) => {
+                        entry.collect_mass_modification(&path.join(key), stats)
+                    }
+                    EntryDiff::File(diff) => {
+                        files += 1;
+                        if diff.increased_entropy.is_some() {
+                            high_entropy += 1;
+                        }
+                    }
+                    EntryDiff::Added(_) => {
+                        *stats.new_names.get_default(key.as_os_str().to_owned()) += 1;
+                        // Was an existing file renamed to gain an extension?
+                        if let (Some(stem), Some(extension)) = (key.file_stem(), key.extension()) {
+                            if let Some(EntryDiff::Removed(_)) = entries.get(Path::new(stem)) {
+                                *stats.new_extensions.get_default(extension.to_owned()) += 1;
+                            }
+                        }
                     }
+                    // Unchanged subtrees still count towards the number
+                    // of directories a new name must appear in
+                    EntryDiff::Identical(directory) => {
+                        stats.directories +=
+                            1 + directory.digest.as_ref().map_or(0, |d| d.directories);
+                    }
+                    EntryDiff::KindChanged(_, _) | EntryDiff::Removed(_) => {}
                 }
             }
-            EntryDiff::KindChanged => {}
+            if high_entropy >= MASS_MIN_FILES && high_entropy * 2 >= files {
+                stats.findings.push(MassModification::EntropyRise {
+                    directory: path.to_owned(),
+                    files: high_entropy,
+                    total: files,
+                });
+            }
+        }
+    }
+
+    fn mass_modification(&self) -> Vec<MassModification> {
+        let mut stats = MassModificationStats::default();
+        self.collect_mass_modification(Path::new("."), &mut stats);
+
+        let mut findings = stats.findings;
+        for (extension, files) in stats.new_extensions {
+            if files >= MASS_MIN_FILES {
+                findings.push(MassModification::NewExtension { extension, files });
+            }
+        }
+        // Only consider names that appear in at least half of the
+        // directories, to avoid flagging ordinary additions.
+        for (name, directories) in stats.new_names {
+            if directories >= MASS_MIN_DIRECTORIES && directories * 2 >= stats.directories {
+                findings.push(MassModification::RepeatedNewFile { name, directories });
+            }
         }
+        findings
     }
 
     fn summarize_diff(&self) -> DiffSummary {
         match self {
             EntryDiff::Directory(entries, diff) => {
-                let initial = if diff.changed > 0 || diff.added > 0 || diff.removed > 0 {
+                // Changed entries summarize themselves, so that a tree
+                // whose only changes are conversions is reported as such
+                let initial = if diff.added > 0 || diff.removed > 0 {
                     DiffSummary::Changes
                 } else {
                     DiffSummary::NoChanges
@@ -359,25 +1648,36 @@ impl EntryDiff {
                     .fold(initial, |acc, x| acc.meet(x))
             }
             EntryDiff::File(diff) => {
-                if diff.zeroed || diff.changed_nul || diff.changed_nonascii {
+                if diff.is_suspicious() {
                     DiffSummary::Suspicious
+                } else if diff.converted_line_endings.is_some() {
+                    DiffSummary::ConvertedLineEndings
                 } else if diff.changed_content {
                     DiffSummary::Changes
                 } else {
                     DiffSummary::NoChanges
                 }
             }
-            EntryDiff::KindChanged => DiffSummary::Changes,
+            EntryDiff::Identical(_) => DiffSummary::NoChanges,
+            EntryDiff::KindChanged(_, _) | EntryDiff::Added(_) | EntryDiff::Removed(_) => {
+                DiffSummary::Changes
+            }
         }
     }
 }
 
 impl DiffSummary {
     fn meet(self, other: DiffSummary) -> DiffSummary {
-        if self == DiffSummary::Suspicious || other == DiffSummary::Suspicious {
+        if self == DiffSummary::MassModification || other == DiffSummary::MassModification {
+            DiffSummary::MassModification
+        } else if self == DiffSummary::Suspicious || other == DiffSummary::Suspicious {
             DiffSummary::Suspicious
         } else if self == DiffSummary::Changes || other == DiffSummary::Changes {
             DiffSummary::Changes
+        } else if self == DiffSummary::ConvertedLineEndings
+            || other == DiffSummary::ConvertedLineEndings
+        {
+            DiffSummary::ConvertedLineEndings
         } else {
             DiffSummary::NoChanges
         }
@@ -385,9 +1685,16 @@ impl DiffSummary {
 }
 
 impl Entry {
-    fn diff(&self, other: &Entry) -> EntryDiff {
+    fn diff<'a>(&'a self, other: &'a Entry, name: &Path) -> EntryDiff<'a> {
         match (self, other) {
             (Entry::Directory(old), Entry::Directory(new)) => {
+                // Identical subtrees need not be visited at all
+                if let (Some(old_digest), Some(new_digest)) = (&old.digest, &new.digest) {
+                    if old_digest.matches(new_digest) {
+                        return EntryDiff::Identical(old);
+                    }
+                }
+                let (old, new) = (&old.entries, &new.entries);
                 let mut entries = BTreeMap::default();
                 let mut added = 0;
                 let mut removed = 0;
@@ -404,14 +1711,16 @@ impl Entry {
                     match old_key.cmp(new_key) {
                         Ordering::Less => {
                             removed += 1;
+                            entries.insert(old_key.clone(), EntryDiff::Removed(old_value));
                             old_entry = old_iter.next();
                         }
                         Ordering::Greater => {
                             added += 1;
+                            entries.insert(new_key.clone(), EntryDiff::Added(new_value));
                             new_entry = new_iter.next();
                         }
                         Ordering::Equal => {
-                            let diff = old_value.diff(new_value);
+                            let diff = old_value.diff(new_value, old_key);
                             match diff {
                                 EntryDiff::Directory(_, ref stats) => {
                                     added += stats.added;
@@ -419,6 +1728,9 @@ impl Entry {
                                     changed += stats.changed;
                                     unchanged += stats.unchanged;
                                 }
+                                EntryDiff::Identical(directory) => {
+                                    unchanged += directory.digest.as_ref().map_or(0, |d| d.files);
+                                }
                                 EntryDiff::File(ref stats) => {
                                     if stats.changed_content {
                                         changed += 1;
@@ -426,9 +1738,10 @@ impl Entry {
                                         unchanged += 1;
                                     }
                                 }
-                                EntryDiff::KindChanged => {
+                                EntryDiff::KindChanged(_, _) => {
                                     changed += 1;
                                 }
+                                EntryDiff::Added(_) | EntryDiff::Removed(_) => unreachable!(),
                             }
                             entries.insert(old_key.clone(), diff);
                             old_entry = old_iter.next();
@@ -436,8 +1749,14 @@ impl Entry {
                         }
                     }
                 }
-                removed += old_iter.count() as u64;
-                added += new_iter.count() as u64;
+                for (old_key, old_value) in old_entry.into_iter().chain(old_iter) {
+                    removed += 1;
+                    entries.insert(old_key.clone(), EntryDiff::Removed(old_value));
+                }
+                for (new_key, new_value) in new_entry.into_iter().chain(new_iter) {
+                    added += 1;
+                    entries.insert(new_key.clone(), EntryDiff::Added(new_value));
+                }
                 EntryDiff::Directory(
                     entries,
                     DirectoryDiff {
@@ -449,27 +1768,249 @@ impl Entry {
                 )
             }
             (Entry::File(old), Entry::File(new)) => {
-                let changed = old.size != new.size;
-                let changed =
-                    changed || (old.sha2.is_some() && new.sha2.is_some() && old.sha2 != new.sha2);
-                let changed = changed
-                    || (old.blake2b.is_some()
-                        && new.blake2b.is_some()
-                        && old.blake2b != new.blake2b);
-                EntryDiff::File(MetricsDiff {
+                let hashes = [
+                    (&old.sha2, &new.sha2),
+                    (&old.blake2b, &new.blake2b),
+                    (&old.sha256, &new.sha256),
+                    (&old.md5, &new.md5),
+                    (&old.sha1, &new.sha1),
+                    (&old.crc32, &new.crc32),
+                ];
+                let shared = hashes.iter().any(|(o, n)| o.is_some() && n.is_some());
+                let differ = hashes
+                    .iter()
+                    .any(|(o, n)| o.is_some() && n.is_some() && o != n);
+                // Without any other metrics, a file whose hashes can't
+                // be compared is assumed to have changed
+                let hashes_only = old.hashes_only || new.hashes_only;
+                let changed = old.size_differs(new) || differ || (hashes_only && !shared);
+                let changed_nul = !hashes_only && old.nul != new.nul;
+                let changed_nonascii = !hashes_only && old.nonascii != new.nonascii;
+                let same_text = old.normalized.is_some() && old.normalized == new.normalized;
+                let converted_line_endings = match (old.line_endings, new.line_endings) {
+                    (Some(old_le), Some(new_le))
+                        if changed && same_text && !changed_nul && !changed_nonascii =>
+                    {
+                        if LineEndings::is_conversion(&old_le, &new_le, old.size, new.size) {
+                            Some((old_le, new_le))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                let increased_entropy = match (old.entropy, new.entropy) {
+                    (Some(old_e), Some(new_e))
+                        if new_e >= ENTROPY_HIGH && new_e.saturating_sub(old_e) >= ENTROPY_JUMP =>
+                    {
+                        Some((old_e, new_e))
+                    }
+                    _ => None,
+                };
+                // Only formats identified by magic numbers are trusted
+                // here, and truncation is already reported separately.
+                let changed_format = match (&old.format, &new.format) {
+                    (Some(old_f), Some(new_f))
+                        if old_f != new_f && magic::is_magic(old_f) && new_f != magic::EMPTY =>
+                    {
+                        Some((old_f.clone(), new_f.clone()))
+                    }
+                    _ => None,
+                };
+                let mismatched_extension = match (
+                    name.extension().and_then(magic::expected_for_extension),
+                    &new.format,
+                ) {
+                    (Some(expected), Some(new_f))
+                        if changed_format.is_none()
+                            && new_f != expected
+                            && new_f != magic::EMPTY
+                            && old.format.as_ref() != Some(new_f) =>
+                    {
+                        Some((new_f.clone(), expected))
+                    }
+                    _ => None,
+                };
+                let changed_blocks = match (&old.blocks, &new.blocks) {
+                    (Some(old_b), Some(new_b)) if changed => old_b.diff(new_b, old.size, new.size),
+                    _ => None,
+                };
+                let gained_zeros = match (old.zeros, new.zeros) {
+                    (Some(old_z), Some(new_z)) if Zeros::gained_region(&old_z, &new_z, changed) => {
+                        Some((old_z, new_z))
+                    }
+                    _ => None,
+                };
+                EntryDiff::File(Box::new(MetricsDiff {
+                    old,
+                    new,
                     changed_content: changed,
-                    zeroed: old.size > 0 && new.size == 0,
-                    changed_nul: old.nul != new.nul,
-                    changed_nonascii: old.nonascii != new.nonascii,
-                })
+                    zeroed: !hashes_only && old.size > 0 && new.size == 0,
+                    changed_nul,
+                    changed_nonascii,
+                    converted_line_endings,
+                    increased_entropy,
+                    changed_format,
+                    mismatched_extension,
+                    changed_blocks,
+                    gained_zeros,
+                }))
             }
-            (_, _) => EntryDiff::KindChanged,
+            (_, _) => EntryDiff::KindChanged(self, other),
         }
     }
 }
 
 const SEP: u8 = 0x0a; // separator \n (byte 0x0a) used in JSON encoding
 
+// CBOR self-describe tag (55799), which can never begin a JSON document
+const CBOR_MAGIC: &[u8] = &[0xd9, 0xd9, 0xf7];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    Indexed,
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "json"),
+            Encoding::Cbor => write!(f, "cbor"),
+            Encoding::Indexed => write!(f, "indexed"),
+        }
+    }
+}
+
+type ReadResult<T> = Result<(T, Encoding, DatabaseChecksum), error::Error>;
+
+// Computes checksums (and the MAC, if any) of everything read through it
+struct ChecksumReader<R> {
+    inner: R,
+    engines: Engines,
+    mac: Option<EngineMac>,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.engines.input(&buf[..n]);
+        if let Some(mac) = &mut self.mac {
+            mac.input(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+// Receives the entries of a database one at a time, in the order they
+// are stored (see Database::visit). Paths are relative to the root of
+// the database.
+pub trait EntryVisitor {
+    fn enter_directory(&mut self, _path: &Path) {}
+    fn visit_digest(&mut self, _path: &Path, _digest: &DirectoryDigest) {}
+    fn leave_directory(&mut self, _path: &Path) {}
+    fn visit_file(&mut self, path: &Path, metrics: &Metrics);
+}
+
+// Deserializes an entry, handing it to the visitor instead of
+// building it in memory
+struct EntrySeed<'a, V> {
+    path: &'a mut PathBuf,
+    visitor: &'a mut V,
+}
+
+#[derive(Deserialize)]
+enum EntryKind {
+    Directory,
+    File,
+}
+
+impl<'de, 'a, V: EntryVisitor> DeserializeSeed<'de> for EntrySeed<'a, V> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_enum("Entry", &["Directory", "File"], self)
+    }
+}
+
+impl<'de, 'a, V: EntryVisitor> Visitor<'de> for EntrySeed<'a, V> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a directory or file entry")
+    }
+
+    fn visit_enum<A>(self, data: A) -> Result<(), A::Error>
+    where
+        A: EnumAccess<'de>,
+    {
+        match data.variant()? {
+            (EntryKind::Directory, variant) => {
+                self.visitor.enter_directory(self.path);
+                let seed = DirectorySeed {
+                    path: &mut *self.path,
+                    visitor: &mut *self.visitor,
+                };
+                variant.newtype_variant_seed(seed)?;
+                self.visitor.leave_directory(self.path);
+            }
+            (EntryKind::File, variant) => {
+                let metrics: Metrics = variant.newtype_variant()?;
+                self.visitor.visit_file(self.path, &metrics);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct DirectorySeed<'a, V> {
+    path: &'a mut PathBuf,
+    visitor: &'a mut V,
+}
+
+impl<'de, 'a, V: EntryVisitor> DeserializeSeed<'de> for DirectorySeed<'a, V> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, V: EntryVisitor> Visitor<'de> for DirectorySeed<'a, V> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a map of directory entries")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while let Some(key) = map.next_key::<PathBuf>()? {
+            if key.as_os_str() == DIGEST_KEY {
+                let digest: DirectoryDigest = map.next_value()?;
+                self.visitor.visit_digest(self.path, &digest);
+            } else {
+                self.path.push(key);
+                map.next_value_seed(EntrySeed {
+                    path: &mut *self.path,
+                    visitor: &mut *self.visitor,
+                })?;
+                self.path.pop();
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Database {
     fn insert(&mut self, path: PathBuf, entry: Entry) {
         self.0.insert(path, entry);
@@ -479,8 +2020,15 @@ impl Database {
         self.0.lookup(path)
     }
 
-    pub fn diff(&self, other: &Database) -> EntryDiff {
-        self.0.diff(&other.0)
+    pub fn digest(&self) -> Option<&DirectoryDigest> {
+        match &self.0 {
+            Entry::Directory(root) => root.digest.as_ref(),
+            Entry::File(_) => None,
+        }
+    }
+
+    pub fn diff<'a>(&'a self, other: &'a Database) -> EntryDiff<'a> {
+        self.0.diff(&other.0, Path::new("."))
     }
 
     pub fn build(
@@ -488,6 +2036,17 @@ impl Database {
         features: Features,
         threads: usize,
         verbose: bool,
+    ) -> Result<Database, error::Error> {
+        Database::scan(root, features, Legacy::default(), threads, verbose)
+    }
+
+    #[allow(clippy::unnecessary_map_or)] // Kept as originally written
+    fn scan(
+        root: impl AsRef<Path>,
+        features: Features,
+        legacy: Legacy,
+        threads: usize,
+        verbose: bool,
     ) -> Result<Database, error::Error> {
         let total_bytes = Arc::new(Mutex::new(0));
         let database = Arc::new(Mutex::new(Database::default()));
@@ -505,7 +2064,7 @@ impl Database {
                     Box::new(move |entry| {
                         let entry = entry.unwrap(); // ?
                         if entry.file_type().map_or(false, |t| t.is_file()) {
-                            let metrics = compute_metrics(entry.path(), features).unwrap(); // ?
+                            let metrics = compute_metrics(entry.path(), features, legacy).unwrap(); // ?
                             *total_bytes.lock().unwrap() += metrics.size;
                             let result = Entry::File(metrics);
                             let short_path = if entry.path() == root {
@@ -527,7 +2086,7 @@ impl Database {
             for entry in WalkBuilder::new(&root).build() {
                 let entry = entry?;
                 if entry.file_type().map_or(false, |t| t.is_file()) {
-                    let metrics = compute_metrics(entry.path(), features)?;
+                    let metrics = compute_metrics(entry.path(), features, legacy)?;
                     *total_bytes += metrics.size;
                     let result = Entry::File(metrics);
                     let short_path = if entry.path() == root.as_ref() {
@@ -550,14 +2109,44 @@ impl Database {
                 total_bytes as f64 / elapsed / 1e6
             );
         }
-        let database = &(*database.lock().unwrap());
-        Ok(database.clone())
+        let mut database = database.lock().unwrap().clone();
+        if let Entry::Directory(root) = &mut database.0 {
+            root.update_digest();
+        }
+        Ok(database)
     }
 
-    pub fn show_diff(&self, other: &Database) -> DiffSummary {
+    pub fn show_diff(&self, other: &Database, verbosity: u64) -> DiffSummary {
+        self.write_diff(other, io::stdout().lock(), verbosity)
+            .expect("failed printing to stdout")
+    }
+
+    pub fn write_diff(
+        &self,
+        other: &Database,
+        mut w: impl Write,
+        verbosity: u64,
+    ) -> Result<DiffSummary, error::Error> {
         let diff = self.diff(other);
-        diff.show_diff(Path::new("."), 0);
-        diff.summarize_diff()
+        diff.show_diff(&mut w, Path::new("."), 0, verbosity)?;
+        let findings = diff.mass_modification();
+        for finding in &findings {
+            writeln!(w, "!! warning: possible mass modification: {}", finding)?;
+        }
+        if findings.is_empty() {
+            Ok(diff.summarize_diff())
+        } else {
+            Ok(DiffSummary::MassModification)
+        }
+    }
+
+    fn required(&self, features: Features) -> (Features, Legacy) {
+        let mut required = Required {
+            features,
+            legacy: Legacy::default(),
+        };
+        self.0.walk(&mut PathBuf::new(), &mut required);
+        (required.features, required.legacy)
     }
 
     pub fn check(
@@ -565,45 +2154,332 @@ impl Database {
         root: impl AsRef<Path>,
         features: Features,
         threads: usize,
+        verbosity: u64,
     ) -> Result<DiffSummary, error::Error> {
         // FIXME: This is non-interactive, but vastly more simple than
         // trying to implement the same functionality interactively.
-        let other = Database::build(root, features, threads, false)?;
-        Ok(self.show_diff(&other))
+        let (features, legacy) = self.required(features);
+        let other = Database::scan(root, features, legacy, threads, false)?;
+        Ok(self.show_diff(&other, verbosity))
     }
 
-    pub fn load_json(r: impl Read) -> Result<Database, error::Error> {
-        // Read entire contents to memory
-        let mut d = GzDecoder::new(r);
+    // Checks a single file, at path relative to root, against its
+    // entry in a database, writing the report to w
+    pub fn verify_file<R>(
+        r: R,
+        key: Option<&MacKey>,
+        root: impl AsRef<Path>,
+        path: &Path,
+        mut w: impl Write,
+        verbosity: u64,
+    ) -> Result<DiffSummary, error::Error>
+    where
+        R: Read + Seek,
+    {
+        let old = Database::lookup_file(r, key, path)?.map(Entry::File);
+        let (features, legacy) = match &old {
+            Some(Entry::File(metrics)) => (
+                Features::infer_from_metrics(metrics),
+                Legacy::infer_from_metrics(metrics),
+            ),
+            _ => (Features::default(), Legacy::default()),
+        };
+        let file = root.as_ref().join(path);
+        let new = if file.exists() {
+            Some(Entry::File(compute_metrics(file, features, legacy)?))
+        } else {
+            None
+        };
+        let diff = match (&old, &new) {
+            (Some(old), Some(new)) => old.diff(new, path),
+            (Some(old), None) => EntryDiff::Removed(old),
+            (None, Some(new)) => EntryDiff::Added(new),
+            (None, None) => {
+                writeln!(
+                    w,
+                    "{} is in neither the database nor the directory",
+                    path.display()
+                )?;
+                return Ok(DiffSummary::NoChanges);
+            }
+        };
+        diff.show_diff(&mut w, path, 0, verbosity)?;
+        let summary = diff.summarize_diff();
+        if summary == DiffSummary::NoChanges && verbosity < 2 {
+            writeln!(w, "{} unchanged", path.display())?;
+        }
+        Ok(summary)
+    }
 
-        let mut bytes = Vec::new();
-        d.read_to_end(&mut bytes)?;
-        let bytes = bytes;
+    // Stream a database through the given decoder, checksumming the
+    // encoded bytes as they are consumed. The result of the decoder is
+    // only returned if the checksum (and the MAC, given a key) matches.
+    fn read<R, T, F>(
+        r: R,
+        expected_encoding: Option<Encoding>,
+        key: Option<&MacKey>,
+        decode: F,
+    ) -> ReadResult<T>
+    where
+        R: Read,
+        F: FnOnce(&mut dyn Read, Encoding) -> Result<T, error::Error>,
+    {
+        let mut r = BufReader::new(GzDecoder::new(r));
 
-        // Find position of separator
-        let index = match bytes.iter().position(|&x| x == SEP) {
-            Some(x) => x,
-            None => return Err(error::Error::ParseError),
+        // A JSON document can never begin with the CBOR magic
+        let mut first = [0];
+        r.read_exact(&mut first)?;
+        let encoding = if first[0] == CBOR_MAGIC[0] {
+            Encoding::Cbor
+        } else {
+            Encoding::Json
         };
+        if expected_encoding.is_some_and(|e| e != encoding) {
+            return Err(error::Error::ParseError);
+        }
 
         // Decode expected checksums
-        let expected: DatabaseChecksum = serde_json::from_slice(&bytes[..index])?;
+        let expected: DatabaseChecksum = match encoding {
+            Encoding::Json => {
+                let mut header = first.to_vec();
+                r.read_until(SEP, &mut header)?;
+                if header.pop() != Some(SEP) {
+                    return Err(error::Error::ParseError);
+                }
+                serde_json::from_slice(&header)?
+            }
+            Encoding::Cbor => {
+                // Input too short to hold the magic is not CBOR either
+                let mut magic = Vec::new();
+                (&mut r).take(2).read_to_end(&mut magic)?;
+                if magic[..] != CBOR_MAGIC[1..] {
+                    return Err(error::Error::ParseError);
+                }
+                // The checksum is a single CBOR item; whatever follows
+                // it is the database
+                ciborium::de::from_reader(&mut r)?
+            }
+            Encoding::Indexed => unreachable!(),
+        };
         let features = Features::infer_from_database_checksum(&expected);
 
-        // Compute actual checksums of database
-        let mut engines = Engines::new(features);
-        engines.input(&bytes[index + 1..]);
-        let actual: DatabaseChecksum = engines.result().into();
+        // Continue decoding database, computing actual checksums on the way
+        let mut reader = BufReader::new(ChecksumReader {
+            inner: r,
+            engines: Engines::new(features),
+            mac: expected.mac_engine(key)?,
+        });
+        let result = decode(&mut reader, encoding);
 
-        if expected.diff(&actual) {
+        // Checksum whatever the decoder didn't consume, so that
+        // corruption is reported as such even if it broke the decoder
+        let drained = std::io::copy(&mut reader, &mut std::io::sink());
+        let reader = reader.into_inner();
+        let actual: DatabaseChecksum = reader.engines.result().into();
+        if drained.is_ok() && expected.diff(&actual) {
             return Err(error::Error::ChecksumMismatch);
         }
+        let value = result?;
+        drained?;
+        expected.verify_mac(reader.mac)?;
+        Ok((value, encoding, expected))
+    }
+
+    fn decode(r: &mut dyn Read, encoding: Encoding) -> Result<Database, error::Error> {
+        Ok(match encoding {
+            Encoding::Json => serde_json::from_reader(r)?,
+            Encoding::Cbor => ciborium::de::from_reader(r)?,
+            Encoding::Indexed => unreachable!(),
+        })
+    }
+
+    fn read_any(r: impl Read, key: Option<&MacKey>) -> ReadResult<Database> {
+        let mut r = BufReader::new(r);
+        if indexed::is_indexed(&mut r)? {
+            let mut bytes = Vec::new();
+            r.read_to_end(&mut bytes)?;
+            let indexed = IndexedDatabase::open_keyed(std::io::Cursor::new(bytes), key)?;
+            let checksum = indexed.checksum().clone();
+            return Ok((indexed.into_database()?, Encoding::Indexed, checksum));
+        }
+        Database::read(r, None, key, Database::decode)
+    }
+
+    // Load a database in any encoding
+    pub fn load(r: impl Read) -> Result<Database, error::Error> {
+        Ok(Database::read_any(r, None)?.0)
+    }
+
+    // Load a database, refusing it unless its MAC matches the key
+    pub fn load_keyed(r: impl Read, key: &MacKey) -> Result<Database, error::Error> {
+        Ok(Database::read_any(r, Some(key))?.0)
+    }
+
+    // Load a database along with the (verified) checksum it was stored with
+    pub fn load_with_checksum(
+        r: impl Read,
+        key: Option<&MacKey>,
+    ) -> Result<(Database, DatabaseChecksum), error::Error> {
+        let (database, _, checksum) = Database::read_any(r, key)?;
+        Ok((database, checksum))
+    }
+
+    pub fn load_json(r: impl Read) -> Result<Database, error::Error> {
+        Ok(Database::read(r, Some(Encoding::Json), None, Database::decode)?.0)
+    }
+
+    pub fn load_cbor(r: impl Read) -> Result<Database, error::Error> {
+        Ok(Database::read(r, Some(Encoding::Cbor), None, Database::decode)?.0)
+    }
+
+    // Walk the entries of a database in either encoding, without
+    // loading the whole database into memory. Note that the visitor
+    // sees entries before the checksum of the database has been
+    // verified: if this returns an error, anything gathered by the
+    // visitor must be discarded.
+    pub fn visit(
+        r: impl Read,
+        key: Option<&MacKey>,
+        visitor: &mut impl EntryVisitor,
+    ) -> Result<(), error::Error> {
+        // Indexed databases must be loaded first, since their blocks
+        // precede the index that verifies them
+        let mut r = BufReader::new(r);
+        if indexed::is_indexed(&mut r)? {
+            let (database, _, _) = Database::read_any(r, key)?;
+            database.0.walk(&mut PathBuf::new(), visitor);
+            return Ok(());
+        }
+        Database::read(r, None, key, |r, encoding| {
+            let mut path = PathBuf::new();
+            match encoding {
+                Encoding::Json => {
+                    let seed = EntrySeed {
+                        path: &mut path,
+                        visitor,
+                    };
+                    let mut d = serde_json::Deserializer::from_reader(r);
+                    serde::de::DeserializeSeed::deserialize(seed, &mut d)?;
+                    d.end()?;
+                }
+                Encoding::Cbor => cbor::visit(r, &mut path, visitor)?,
+                Encoding::Indexed => unreachable!(),
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn dump<W>(&self, w: W, features: Features, encoding: Encoding) -> Result<W, error::Error>
+    where
+        W: Write,
+    {
+        Ok(self.dump_with_checksum(w, features, encoding, None)?.0)
+    }
+
+    // Dump a database, also returning the checksum stored with it. Given
+    // a key, the checksum includes a MAC of the database.
+    pub fn dump_with_checksum<W>(
+        &self,
+        w: W,
+        features: Features,
+        encoding: Encoding,
+        key: Option<&MacKey>,
+    ) -> Result<(W, DatabaseChecksum), error::Error>
+    where
+        W: Write,
+    {
+        self.encode(w, features, encoding, key, false)
+    }
+
+    // Dump a database marked as partial (see Database::recover)
+    pub fn dump_partial<W>(
+        &self,
+        w: W,
+        features: Features,
+        encoding: Encoding,
+        key: Option<&MacKey>,
+    ) -> Result<(W, DatabaseChecksum), error::Error>
+    where
+        W: Write,
+    {
+        self.encode(w, features, encoding, key, true)
+    }
+
+    fn encode<W>(
+        &self,
+        w: W,
+        features: Features,
+        encoding: Encoding,
+        key: Option<&MacKey>,
+        partial: bool,
+    ) -> Result<(W, DatabaseChecksum), error::Error>
+    where
+        W: Write,
+    {
+        match encoding {
+            Encoding::Json => self.encode_json(w, features, key, partial),
+            Encoding::Cbor => self.encode_cbor(w, features, key, partial),
+            Encoding::Indexed => self.encode_indexed(w, features, key, partial),
+        }
+    }
+
+    // SHA-256 is only computed for compatibility with other tools, and
+    // is never used for the database itself. A database built without
+    // SHA2 or BLAKE2b is still hashed with SHA2, so that its checksum
+    // (and any signature over it) covers more than the size.
+    fn checksum(db_bytes: &[u8], features: Features) -> DatabaseChecksum {
+        let features = Features {
+            sha2: features.sha2 || !features.blake2b,
+            sha256: false,
+            blocks: None,
+            ..features
+        };
+        let mut engines = Engines::new(features);
+        engines.input(db_bytes);
+        engines.result().into()
+    }
 
-        // Continue decoding database
-        Ok(serde_json::from_slice(&bytes[index + 1..])?)
+    // The MAC uses HMAC with SHA2-512/256 if SHA2 is enabled, and
+    // keyed BLAKE2b otherwise
+    fn keyed_checksum(
+        db_bytes: &[u8],
+        features: Features,
+        key: Option<&MacKey>,
+        partial: bool,
+    ) -> Result<DatabaseChecksum, error::Error> {
+        let mut checksum = Database::checksum(db_bytes, features);
+        checksum.partial = partial;
+        if let Some(key) = key {
+            let algorithm = if features.sha2 || !features.blake2b {
+                mac::HMAC_SHA2
+            } else {
+                mac::BLAKE2B
+            };
+            let mut engine = EngineMac::new(algorithm, key)?;
+            engine.input(db_bytes);
+            checksum.mac = Some(DatabaseMac {
+                algorithm: engine.algorithm().to_owned(),
+                tag: HashSum(engine.result()),
+            });
+        }
+        Ok(checksum)
     }
 
     pub fn dump_json<W>(&self, w: W, features: Features) -> Result<W, error::Error>
+    where
+        W: Write,
+    {
+        Ok(self.encode_json(w, features, None, false)?.0)
+    }
+
+    fn encode_json<W>(
+        &self,
+        w: W,
+        features: Features,
+        key: Option<&MacKey>,
+        partial: bool,
+    ) -> Result<(W, DatabaseChecksum), error::Error>
     where
         W: Write,
     {
@@ -614,9 +2490,7 @@ impl Database {
         let db_json = serde_json::to_vec(self)?;
 
         // Compute checksums of encoded JSON
-        let mut engines = Engines::new(features);
-        engines.input(&db_json[..]);
-        let checksum: DatabaseChecksum = engines.result().into();
+        let checksum = Database::keyed_checksum(&db_json, features, key, partial)?;
         let checksum_json = serde_json::to_vec(&checksum)?;
 
         // Make sure encoded JSON does not include separator
@@ -627,7 +2501,64 @@ impl Database {
         e.write_all(&checksum_json[..])?;
         e.write_all(&vec![SEP][..])?;
         e.write_all(&db_json)?;
-        Ok(e.finish()?)
+        Ok((e.finish()?, checksum))
+    }
+
+    pub fn dump_cbor<W>(&self, w: W, features: Features) -> Result<W, error::Error>
+    where
+        W: Write,
+    {
+        Ok(self.encode_cbor(w, features, None, false)?.0)
+    }
+
+    fn encode_cbor<W>(
+        &self,
+        w: W,
+        features: Features,
+        key: Option<&MacKey>,
+        partial: bool,
+    ) -> Result<(W, DatabaseChecksum), error::Error>
+    where
+        W: Write,
+    {
+        // Generate CBOR-encoded database
+        let mut db_cbor = Vec::new();
+        ciborium::ser::into_writer(self, &mut db_cbor)?;
+
+        // Compute checksums of encoded CBOR
+        let checksum = Database::keyed_checksum(&db_cbor, features, key, partial)?;
+        let mut checksum_cbor = Vec::new();
+        ciborium::ser::into_writer(&checksum, &mut checksum_cbor)?;
+
+        // Write magic, checksum and database
+        let mut e = GzEncoder::new(w, Compression::best());
+        e.write_all(CBOR_MAGIC)?;
+        e.write_all(&checksum_cbor)?;
+        e.write_all(&db_cbor)?;
+        Ok((e.finish()?, checksum))
+    }
+
+    // Re-encode a database, keeping the checksum algorithms it was
+    // written with and whether it is partial. Without an explicit
+    // encoding, switch to the other one. Given a key, the MAC is
+    // verified and computed anew.
+    pub fn convert<W>(
+        r: impl Read,
+        w: W,
+        encoding: Option<Encoding>,
+        key: Option<&MacKey>,
+    ) -> Result<(W, Encoding), error::Error>
+    where
+        W: Write,
+    {
+        let (database, source, checksum) = Database::read_any(r, key)?;
+        let features = Features::infer_from_database_checksum(&checksum);
+        let target = encoding.unwrap_or(match source {
+            Encoding::Json => Encoding::Cbor,
+            Encoding::Cbor | Encoding::Indexed => Encoding::Json,
+        });
+        let (w, _) = database.encode(w, features, target, key, checksum.partial)?;
+        Ok((w, target))
     }
 }
 
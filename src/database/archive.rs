@@ -0,0 +1,320 @@
+// Databases built from tar and zip archives, without extracting them.
+// Entries are streamed through the same engines as files on disk, and
+// the metadata stored in the archive is recorded with them.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use flate2::CrcReader;
+use tar::EntryType;
+use xz2::read::XzDecoder;
+use zip::{CompressionMethod, ZipArchive};
+
+use super::import::conflicts;
+use super::{
+    compute_metrics_from, ArchiveMetadata, Database, DiffSummary, Entry, Features, Legacy, Metrics,
+};
+use crate::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    // Recognizes archives by the extensions of their names
+    pub fn infer_from_path(path: &Path) -> Option<ArchiveFormat> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        [
+            (".tar", ArchiveFormat::Tar),
+            (".tar.gz", ArchiveFormat::TarGz),
+            (".tgz", ArchiveFormat::TarGz),
+            (".tar.xz", ArchiveFormat::TarXz),
+            (".txz", ArchiveFormat::TarXz),
+            (".tar.zst", ArchiveFormat::TarZst),
+            (".tzst", ArchiveFormat::TarZst),
+            (".zip", ArchiveFormat::Zip),
+        ]
+        .iter()
+        .find(|(extension, _)| name.ends_with(extension))
+        .map(|(_, format)| *format)
+    }
+
+    fn open(self, path: &Path) -> io::Result<Box<dyn Read>> {
+        let f = BufReader::new(File::open(path)?);
+        Ok(match self {
+            ArchiveFormat::Tar => Box::new(f),
+            ArchiveFormat::TarGz => Box::new(MultiGzDecoder::new(f)),
+            ArchiveFormat::TarXz => Box::new(XzDecoder::new_multi_decoder(f)),
+            ArchiveFormat::TarZst => Box::new(zstd::Decoder::with_buffer(f)?),
+            ArchiveFormat::Zip => unreachable!(),
+        })
+    }
+}
+
+// Paths in archives may start with / or ./, but can't leave the root
+fn entry_path(path: &Path) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => result.push(name),
+            Component::RootDir | Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(result).filter(|path| !path.as_os_str().is_empty())
+}
+
+fn metadata(header: &tar::Header) -> ArchiveMetadata {
+    ArchiveMetadata {
+        mode: header.mode().ok().map(|mode| mode & 0o7777),
+        uid: header.uid().ok(),
+        gid: header.gid().ok(),
+        user: header.username().ok().flatten().map(str::to_owned),
+        group: header.groupname().ok().flatten().map(str::to_owned),
+        mtime: header.mtime().ok(),
+        link: None,
+    }
+}
+
+// The files read from an archive
+#[derive(Default)]
+struct Scan {
+    files: BTreeMap<PathBuf, Metrics>,
+    bytes: u64,
+    mismatched: Vec<PathBuf>, // Files whose stored checksums don't match their contents
+}
+
+impl Scan {
+    // Later entries replace earlier ones, as when extracting
+    fn insert(&mut self, name: &Path, path: PathBuf, metrics: Metrics) -> Result<(), error::Error> {
+        if conflicts(&self.files, &path) {
+            return Err(error::Error::InvalidArchive(name.to_owned()));
+        }
+        self.bytes += metrics.size;
+        self.files.insert(path, metrics);
+        Ok(())
+    }
+}
+
+fn scan_tar(
+    path: &Path,
+    format: ArchiveFormat,
+    features: Features,
+    legacy: Legacy,
+) -> Result<Scan, error::Error> {
+    let mut scan = Scan::default();
+    let mut archive = tar::Archive::new(format.open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let invalid = || error::Error::InvalidArchive(name.clone());
+        let mut metadata = metadata(entry.header());
+        let mut metrics = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                compute_metrics_from(&mut entry, features, legacy)?
+            }
+            // A hard link shares the contents of a file earlier in the
+            // archive
+            EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .and_then(|target| entry_path(&target))
+                    .ok_or_else(invalid)?;
+                let metrics = scan.files.get(&target).cloned().ok_or_else(invalid)?;
+                metadata.link = Some(target.to_string_lossy().into_owned());
+                metrics
+            }
+            // Symbolic links, directories and the like are skipped, as
+            // when building from a directory
+            _ => continue,
+        };
+        metrics.archive = Some(Box::new(metadata));
+        let path = entry_path(&name).ok_or_else(invalid)?;
+        scan.insert(&name, path, metrics)?;
+    }
+    Ok(scan)
+}
+
+// A zip member, along with whether its contents match the stored CRC32
+type Member = Option<(PathBuf, PathBuf, Metrics, bool)>;
+
+fn scan_zip_member(
+    archive: &mut ZipArchive<BufReader<File>>,
+    index: usize,
+    features: Features,
+    legacy: Legacy,
+) -> Result<Member, error::Error> {
+    // The raw data is decompressed here, since the zip crate fails on a
+    // CRC32 mismatch rather than reporting it
+    let member = archive.by_index_raw(index)?;
+    if member.is_dir() || member.is_symlink() {
+        return Ok(None);
+    }
+    let name = PathBuf::from(member.name());
+    let path = entry_path(&name).ok_or_else(|| error::Error::InvalidArchive(name.clone()))?;
+    let metadata = ArchiveMetadata {
+        mode: member.unix_mode().map(|mode| mode & 0o7777),
+        mtime: member.extra_data_fields().find_map(|field| match field {
+            zip::ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time().map(u64::from),
+            _ => None,
+        }),
+        ..ArchiveMetadata::default()
+    };
+    let stored_crc32 = member.crc32();
+    let mut reader = match member.compression() {
+        CompressionMethod::STORE => CrcReader::new(Box::new(member) as Box<dyn Read>),
+        CompressionMethod::DEFLATE => CrcReader::new(Box::new(DeflateDecoder::new(member)) as _),
+        _ => return Err(error::Error::UnsupportedCompression(name)),
+    };
+    let mut metrics = compute_metrics_from(&mut reader, features, legacy)?;
+    metrics.archive = Some(Box::new(metadata));
+    let matches = reader.crc().sum() == stored_crc32;
+    Ok(Some((name, path, metrics, matches)))
+}
+
+// The central directory of a zip archive lists every member, so each
+// thread opens the archive and hashes the members it takes in turn
+fn scan_zip(
+    path: &Path,
+    features: Features,
+    legacy: Legacy,
+    threads: usize,
+) -> Result<Scan, error::Error> {
+    let open =
+        || -> Result<_, error::Error> { Ok(ZipArchive::new(BufReader::new(File::open(path)?))?) };
+    let len = open()?.len();
+    let next = AtomicUsize::new(0);
+    let members = Mutex::new(Vec::with_capacity(len));
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.clamp(1, len.max(1)))
+            .map(|_| {
+                scope.spawn(|| -> Result<(), error::Error> {
+                    let mut archive = open()?;
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= len {
+                            return Ok(());
+                        }
+                        let member = scan_zip_member(&mut archive, index, features, legacy)?;
+                        members.lock().unwrap().push((index, member));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    let mut members = members.into_inner().unwrap();
+    members.sort_by_key(|(index, _)| *index);
+    let mut scan = Scan::default();
+    for (name, path, metrics, matches) in members.into_iter().filter_map(|(_, member)| member) {
+        if !matches {
+            scan.mismatched.push(path.clone());
+        }
+        scan.insert(&name, path, metrics)?;
+    }
+    Ok(scan)
+}
+
+fn warn_mismatched(paths: &[PathBuf]) {
+    for path in paths {
+        println!(
+            "!! warning: contents don't match the CRC32 stored in the archive: {}",
+            path.display()
+        );
+    }
+}
+
+impl Database {
+    fn scan_archive(
+        path: &Path,
+        format: ArchiveFormat,
+        features: Features,
+        legacy: Legacy,
+        threads: usize,
+        verbose: bool,
+    ) -> Result<(Database, Vec<PathBuf>), error::Error> {
+        let start_time = time::Instant::now();
+        let scan = match format {
+            ArchiveFormat::Zip => scan_zip(path, features, legacy, threads)?,
+            _ => scan_tar(path, format, features, legacy)?,
+        };
+        let elapsed = start_time.elapsed().as_secs_f64();
+        if verbose {
+            println!(
+                "Database::build_archive took {:.3} seconds, read {} bytes, {:.1} MB/s",
+                elapsed,
+                scan.bytes,
+                scan.bytes as f64 / elapsed / 1e6
+            );
+        }
+        let mut database = Database::default();
+        for (path, metrics) in scan.files {
+            database.insert(path, Entry::File(metrics));
+        }
+        if let Entry::Directory(root) = &mut database.0 {
+            root.update_digest();
+        }
+        Ok((database, scan.mismatched))
+    }
+
+    // Builds a database of the files in an archive, as if it had been
+    // extracted to a directory. Zip archives are read on the given
+    // number of threads, and tar archives sequentially.
+    // A zip member whose contents don't match its stored CRC32 fails
+    // the build, unless ignore_crc is set, since the database would
+    // otherwise record the damage as the original contents
+    pub fn build_archive(
+        path: impl AsRef<Path>,
+        format: ArchiveFormat,
+        features: Features,
+        threads: usize,
+        ignore_crc: bool,
+        verbose: bool,
+    ) -> Result<Database, error::Error> {
+        let legacy = Legacy::default();
+        let (database, mismatched) =
+            Database::scan_archive(path.as_ref(), format, features, legacy, threads, verbose)?;
+        warn_mismatched(&mismatched);
+        match mismatched.into_iter().next() {
+            Some(path) if !ignore_crc => Err(error::Error::CrcMismatch(path)),
+            _ => Ok(database),
+        }
+    }
+
+    // Files whose contents don't match the checksums stored in the
+    // archive are suspicious, whether or not they changed
+    pub fn check_archive(
+        &self,
+        path: impl AsRef<Path>,
+        format: ArchiveFormat,
+        features: Features,
+        threads: usize,
+        verbosity: u64,
+    ) -> Result<DiffSummary, error::Error> {
+        let (features, legacy) = self.required(features);
+        let (other, mismatched) =
+            Database::scan_archive(path.as_ref(), format, features, legacy, threads, false)?;
+        let summary = self.show_diff(&other, verbosity);
+        warn_mismatched(&mismatched);
+        if mismatched.is_empty() {
+            Ok(summary)
+        } else {
+            Ok(summary.meet(DiffSummary::Suspicious))
+        }
+    }
+}
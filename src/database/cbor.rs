@@ -0,0 +1,200 @@
+// CBOR decoding on top of ciborium. Whole values are decoded by
+// ciborium itself; the entries of a database are instead walked one
+// map header at a time, so that each file can be handed to a visitor
+// as soon as its metrics are decoded.
+
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use ciborium::de::Error;
+use serde::de::DeserializeOwned;
+
+use super::{DirectoryDigest, EntryVisitor, Metrics, DIGEST_KEY};
+
+pub(super) type CborError = Error<io::Error>;
+
+// Major types, and the initial byte ending an indefinite-length item
+const TEXT: u8 = 3;
+const MAP: u8 = 5;
+const BREAK: u8 = 0xff;
+
+// Directories nested deeper than this are rejected rather than
+// recursed into, as ciborium does for values
+const MAX_DEPTH: usize = 256;
+
+// Counts the bytes read, so that errors can say where they occurred
+struct Counter<R> {
+    inner: R,
+    offset: usize,
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+fn truncated(offset: usize) -> CborError {
+    Error::semantic(offset, "unexpected end of data")
+}
+
+fn read_exact<R: Read>(r: &mut Counter<R>, buf: &mut [u8]) -> Result<(), CborError> {
+    r.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => truncated(r.offset),
+        _ => Error::Io(err),
+    })
+}
+
+// Reads the initial byte and argument of the next item. The argument
+// is None for an indefinite length.
+fn header<R: Read>(r: &mut Counter<R>) -> Result<(u8, Option<u64>), CborError> {
+    let mut initial = [0];
+    read_exact(r, &mut initial)?;
+    let len = match initial[0] & 0x1f {
+        info @ 0..=23 => return Ok((initial[0], Some(info as u64))),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        31 => return Ok((initial[0], None)),
+        _ => return Err(Error::Syntax(r.offset - 1)),
+    };
+    let mut argument = [0; 8];
+    read_exact(r, &mut argument[8 - len..])?;
+    Ok((initial[0], Some(u64::from_be_bytes(argument))))
+}
+
+fn major(initial: u8) -> u8 {
+    initial >> 5
+}
+
+fn text<R: Read>(r: &mut Counter<R>, start: usize, len: u64) -> Result<String, CborError> {
+    let mut bytes = Vec::new();
+    (&mut *r).take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(truncated(r.offset));
+    }
+    String::from_utf8(bytes).map_err(|_| Error::semantic(start, "invalid UTF-8 in a name"))
+}
+
+// Decodes a single value, reporting errors at their offset in the
+// whole input
+fn value<T: DeserializeOwned, R: Read>(r: &mut Counter<R>) -> Result<T, CborError> {
+    let start = r.offset;
+    ciborium::de::from_reader(&mut *r).map_err(|err| match err {
+        Error::Syntax(offset) => Error::Syntax(start + offset),
+        Error::Semantic(offset, message) => {
+            Error::Semantic(Some(offset.map_or(r.offset, |o| start + o)), message)
+        }
+        Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => truncated(r.offset),
+        err => err,
+    })
+}
+
+fn visit_entry<R: Read>(
+    r: &mut Counter<R>,
+    path: &mut PathBuf,
+    visitor: &mut impl EntryVisitor,
+    depth: usize,
+) -> Result<(), CborError> {
+    let start = r.offset;
+    let kind = match header(r)? {
+        (initial, Some(1)) if major(initial) == MAP => {
+            let key_start = r.offset;
+            match header(r)? {
+                (initial, Some(len)) if major(initial) == TEXT => text(r, key_start, len)?,
+                _ => String::new(),
+            }
+        }
+        _ => String::new(),
+    };
+    match kind.as_str() {
+        "Directory" => {
+            visitor.enter_directory(path);
+            visit_directory(r, path, visitor, depth)?;
+            visitor.leave_directory(path);
+        }
+        "File" => {
+            let metrics: Metrics = value(r)?;
+            visitor.visit_file(path, &metrics);
+        }
+        _ => return Err(Error::semantic(start, "expected a directory or file entry")),
+    }
+    Ok(())
+}
+
+fn visit_directory<R: Read>(
+    r: &mut Counter<R>,
+    path: &mut PathBuf,
+    visitor: &mut impl EntryVisitor,
+    depth: usize,
+) -> Result<(), CborError> {
+    if depth >= MAX_DEPTH {
+        return Err(Error::RecursionLimitExceeded);
+    }
+    let start = r.offset;
+    let len = match header(r)? {
+        (initial, len) if major(initial) == MAP => len,
+        _ => {
+            return Err(Error::semantic(
+                start,
+                "expected a map of directory entries",
+            ))
+        }
+    };
+    let mut entries = 0;
+    while len.is_none_or(|len| entries < len) {
+        let start = r.offset;
+        let name = match header(r)? {
+            (BREAK, None) if len.is_none() => break,
+            (initial, Some(len)) if major(initial) == TEXT => text(r, start, len)?,
+            _ => return Err(Error::semantic(start, "expected an entry name")),
+        };
+        if name == DIGEST_KEY {
+            let digest: DirectoryDigest = value(r)?;
+            visitor.visit_digest(path, &digest);
+        } else {
+            path.push(name);
+            visit_entry(r, path, visitor, depth + 1)?;
+            path.pop();
+        }
+        entries += 1;
+    }
+    Ok(())
+}
+
+// Walks the entries of an encoded database (without its checksum),
+// handing them to the visitor instead of building them in memory
+pub(super) fn visit(
+    r: impl Read,
+    path: &mut PathBuf,
+    visitor: &mut impl EntryVisitor,
+) -> Result<(), CborError> {
+    let mut r = Counter {
+        inner: r,
+        offset: 0,
+    };
+    visit_entry(&mut r, path, visitor, 0)
+}
+
+// Decodes the value at the start of the input, also returning its
+// length in bytes
+pub(super) fn from_slice_prefix<T: DeserializeOwned>(data: &[u8]) -> Result<(T, usize), CborError> {
+    let mut r = Counter {
+        inner: data,
+        offset: 0,
+    };
+    let value = value(&mut r)?;
+    Ok((value, r.offset))
+}
+
+// Decodes a value that must take up the whole input
+pub(super) fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T, CborError> {
+    let (value, len) = from_slice_prefix(data)?;
+    if len < data.len() {
+        return Err(Error::semantic(len, "trailing data"));
+    }
+    Ok(value)
+}
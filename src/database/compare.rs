@@ -0,0 +1,233 @@
+// Comparison of two directories, neither of which has a database. Both
+// sides are scanned at once: either each into a database of its own,
+// or by matching up the files of both sides as they are found and
+// keeping only the subtrees that differ.
+
+use std::cmp::Ordering;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use ignore::{DirEntry, WalkBuilder};
+
+use super::{compute_metrics, Database, DiffSummary, Directory, Entry, Features, Legacy, Metrics};
+use crate::error;
+
+// Number of files either side may get ahead of the other
+const STREAM_BUFFER: usize = 64;
+
+type File = (PathBuf, Metrics);
+
+fn scan_entry(
+    root: &Path,
+    entry: DirEntry,
+    features: Features,
+    legacy: Legacy,
+) -> Result<Option<File>, error::Error> {
+    if !entry.file_type().is_some_and(|t| t.is_file()) {
+        return Ok(None);
+    }
+    let metrics = compute_metrics(entry.path(), features, legacy)?;
+    let short_path = if entry.path() == root {
+        Path::new(entry.path().file_name().expect("unreachable"))
+    } else {
+        entry.path().strip_prefix(root)?
+    };
+    Ok(Some((short_path.to_owned(), metrics)))
+}
+
+// Sends the files of a tree in sorted order, which is also the order of
+// their paths, until the first error or until nobody is listening
+fn scan_sorted(
+    root: &Path,
+    features: Features,
+    legacy: Legacy,
+    tx: mpsc::SyncSender<Result<File, error::Error>>,
+) {
+    let walk = WalkBuilder::new(root)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walk {
+        let file = entry
+            .map_err(error::Error::from)
+            .and_then(|entry| scan_entry(root, entry, features, legacy));
+        if let Some(file) = file.transpose() {
+            let failed = file.is_err();
+            if tx.send(file).is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+// A directory open on both sides, named by its last component
+#[derive(Default)]
+struct Frame {
+    name: PathBuf,
+    old: Directory,
+    new: Directory,
+}
+
+impl Frame {
+    // Directories whose digests match would be skipped by the diff, so
+    // only the digests need be kept
+    fn seal(&mut self, collapse: bool) {
+        self.old.update_own_digest();
+        self.new.update_own_digest();
+        if let (Some(old), Some(new)) = (&self.old.digest, &self.new.digest) {
+            if collapse && old.matches(new) {
+                self.old.entries.clear();
+                self.new.entries.clear();
+            }
+        }
+    }
+}
+
+// The directories enclosing the last file matched, from the root down
+struct Stream {
+    frames: Vec<Frame>,
+    collapse: bool,
+}
+
+impl Stream {
+    fn new(collapse: bool) -> Stream {
+        Stream {
+            frames: vec![Frame::default()],
+            collapse,
+        }
+    }
+
+    // Closes the innermost directory, adding it to its parent on each
+    // side where it has files
+    fn close(&mut self) {
+        let mut frame = self.frames.pop().expect("unreachable");
+        frame.seal(self.collapse);
+        let parent = self.frames.last_mut().expect("unreachable");
+        let Frame { name, old, new } = frame;
+        for (directory, parent) in [(old, &mut parent.old), (new, &mut parent.new)] {
+            if directory.digest.as_ref().is_some_and(|d| d.files > 0) {
+                parent
+                    .entries
+                    .insert(name.clone(), Entry::Directory(directory));
+            }
+        }
+    }
+
+    // Closes the directories that don't contain the path, and opens the
+    // ones that do
+    fn enter(&mut self, path: &Path) {
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let components: Vec<_> = parent.components().collect();
+        let open = self.frames[1..]
+            .iter()
+            .zip(&components)
+            .take_while(|(frame, component)| frame.name.as_os_str() == component.as_os_str())
+            .count();
+        while self.frames.len() > open + 1 {
+            self.close();
+        }
+        for component in &components[open..] {
+            self.frames.push(Frame {
+                name: PathBuf::from(component.as_os_str()),
+                ..Frame::default()
+            });
+        }
+    }
+
+    fn insert(&mut self, old: Option<File>, new: Option<File>) {
+        let path = old
+            .as_ref()
+            .or(new.as_ref())
+            .expect("unreachable")
+            .0
+            .clone();
+        self.enter(&path);
+        let name = PathBuf::from(path.file_name().expect("unreachable"));
+        let frame = self.frames.last_mut().expect("unreachable");
+        if let Some((_, metrics)) = old {
+            frame.old.entries.insert(name.clone(), Entry::File(metrics));
+        }
+        if let Some((_, metrics)) = new {
+            frame.new.entries.insert(name, Entry::File(metrics));
+        }
+    }
+
+    fn finish(mut self) -> (Database, Database) {
+        while self.frames.len() > 1 {
+            self.close();
+        }
+        let mut root = self.frames.pop().expect("unreachable");
+        root.seal(self.collapse);
+        (
+            Database(Entry::Directory(root.old)),
+            Database(Entry::Directory(root.new)),
+        )
+    }
+}
+
+impl Database {
+    // Builds databases of both directories at once, each on the given
+    // number of threads, and reports the differences as check does
+    pub fn compare(
+        old: impl AsRef<Path>,
+        new: impl AsRef<Path>,
+        features: Features,
+        threads: usize,
+        verbosity: u64,
+    ) -> Result<DiffSummary, error::Error> {
+        let legacy = Legacy::default();
+        let (old, new) = (old.as_ref(), new.as_ref());
+        let (old, new) = thread::scope(|scope| {
+            let old = scope.spawn(|| Database::scan(old, features, legacy, threads, false));
+            let new = scope.spawn(|| Database::scan(new, features, legacy, threads, false));
+            (old.join().unwrap(), new.join().unwrap())
+        });
+        Ok(old?.show_diff(&new?, verbosity))
+    }
+
+    // As above, but walks both directories in sorted order, one thread
+    // each, and matches up their files as they are found. Subtrees that
+    // turn out to be identical are dropped as soon as they are complete
+    // (unless every unchanged file is to be listed), so memory use
+    // depends on the differences rather than on the size of the trees.
+    pub fn compare_stream(
+        old: impl AsRef<Path>,
+        new: impl AsRef<Path>,
+        features: Features,
+        verbosity: u64,
+    ) -> Result<DiffSummary, error::Error> {
+        let legacy = Legacy::default();
+        let (old, new) = (old.as_ref(), new.as_ref());
+        let (old, new) = thread::scope(|scope| -> Result<_, error::Error> {
+            let (old_tx, old_rx) = mpsc::sync_channel(STREAM_BUFFER);
+            let (new_tx, new_rx) = mpsc::sync_channel(STREAM_BUFFER);
+            scope.spawn(move || scan_sorted(old, features, legacy, old_tx));
+            scope.spawn(move || scan_sorted(new, features, legacy, new_tx));
+
+            let mut stream = Stream::new(verbosity < 2);
+            let (mut old_files, mut new_files) = (old_rx.iter(), new_rx.iter());
+            let mut old_next = old_files.next().transpose()?;
+            let mut new_next = new_files.next().transpose()?;
+            loop {
+                let order = match (&old_next, &new_next) {
+                    (Some((old_path, _)), Some((new_path, _))) => old_path.cmp(new_path),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => break,
+                };
+                let old_file = match order {
+                    Ordering::Greater => None,
+                    _ => mem::replace(&mut old_next, old_files.next().transpose()?),
+                };
+                let new_file = match order {
+                    Ordering::Less => None,
+                    _ => mem::replace(&mut new_next, new_files.next().transpose()?),
+                };
+                stream.insert(old_file, new_file);
+            }
+            Ok(stream.finish())
+        })?;
+        Ok(old.show_diff(&new, verbosity))
+    }
+}
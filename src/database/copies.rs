@@ -0,0 +1,444 @@
+// Search for intact copies of damaged files, by the hashes recorded for
+// them, in other databases (of backups, say) or in live directories.
+// The result is a plan of what to copy where, which can also be written
+// as a shell script.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use ignore::WalkBuilder;
+
+use super::{
+    compute_metrics, BTreeMapExt, Database, Entry, EntryDiff, EntryVisitor, Features, HashSum,
+    Legacy, Metrics,
+};
+use crate::error;
+
+// The hashes trusted to identify the contents of a file. CRC32 is too
+// weak for that, so files with nothing better can't be found.
+type HashField = fn(&Metrics) -> Option<&HashSum>;
+const ALGORITHMS: [(&str, HashField); 5] = [
+    ("sha2-512/256", |m| m.sha2.as_ref()),
+    ("blake2b", |m| m.blake2b.as_ref()),
+    ("sha256", |m| m.sha256.as_ref()),
+    ("md5", |m| m.md5.as_ref()),
+    ("sha1", |m| m.sha1.as_ref()),
+];
+
+// Do the files share at least one hash, and agree on all they share?
+// Sizes are compared too, unless one was imported without them.
+fn same_contents(wanted: &Metrics, candidate: &Metrics) -> bool {
+    if wanted.size_differs(candidate) {
+        return false;
+    }
+    let mut shared = false;
+    for (_, hash) in &ALGORITHMS {
+        if let (Some(wanted), Some(candidate)) = (hash(wanted), hash(candidate)) {
+            if wanted != candidate {
+                return false;
+            }
+            shared = true;
+        }
+    }
+    shared
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopySource {
+    File(PathBuf), // A file on disk, hashed during the search
+    Database {
+        database: PathBuf,
+        path: PathBuf, // Relative to the directory the database was built from
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyPlan {
+    pub root: PathBuf,
+    pub copies: Vec<(PathBuf, CopySource)>, // Damaged files, relative to the root
+    pub missing: Vec<PathBuf>,              // Damaged files without a good copy
+}
+
+// Collects the paths of the files in an entry
+struct Files<'a>(&'a mut Vec<PathBuf>);
+
+impl EntryVisitor for Files<'_> {
+    fn visit_file(&mut self, path: &Path, _metrics: &Metrics) {
+        self.0.push(path.to_owned());
+    }
+}
+
+// The hashes recorded for files, so that others can be compared with
+// them
+struct Recorded {
+    features: Features,
+    legacy: Legacy,
+}
+
+impl Default for Recorded {
+    fn default() -> Recorded {
+        Recorded {
+            features: Features {
+                sha2: false,
+                blake2b: false,
+                sha256: false,
+                blocks: None,
+            },
+            legacy: Legacy::default(),
+        }
+    }
+}
+
+impl EntryVisitor for Recorded {
+    fn visit_file(&mut self, _path: &Path, metrics: &Metrics) {
+        let features = Features::infer_from_metrics(metrics);
+        self.features.sha2 |= features.sha2;
+        self.features.blake2b |= features.blake2b;
+        self.features.sha256 |= features.sha256;
+        let legacy = Legacy::infer_from_metrics(metrics);
+        self.legacy.md5 |= legacy.md5;
+        self.legacy.sha1 |= legacy.sha1;
+        self.legacy.crc32 |= legacy.crc32;
+    }
+}
+
+fn entry_files(entry: &Entry, path: &Path, files: &mut Vec<PathBuf>) {
+    entry.walk(&mut path.to_owned(), &mut Files(files));
+}
+
+// Files that were changed, removed or replaced by a directory
+fn damaged_files(diff: &EntryDiff, path: &Path, files: &mut Vec<PathBuf>) {
+    match diff {
+        EntryDiff::Directory(entries, _) => {
+            for (key, entry) in entries {
+                damaged_files(entry, &path.join(key), files);
+            }
+        }
+        EntryDiff::File(diff) => {
+            if diff.changed_content {
+                files.push(path.to_owned());
+            }
+        }
+        EntryDiff::KindChanged(old, _) | EntryDiff::Removed(old) => {
+            entry_files(old, path, files);
+        }
+        EntryDiff::Identical(_) | EntryDiff::Added(_) => {}
+    }
+}
+
+pub struct CopyFinder {
+    root: PathBuf,
+    wanted: BTreeMap<PathBuf, Metrics>,
+    index: BTreeMap<(&'static str, HashSum), Vec<PathBuf>>,
+    sizes: Option<BTreeSet<u64>>, // Sizes worth hashing, if all are known
+    targets: BTreeSet<PathBuf>,   // Damaged files that still exist, canonicalized
+    recorded: Recorded,
+    found: BTreeMap<PathBuf, CopySource>,
+}
+
+struct Search<'a> {
+    finder: &'a mut CopyFinder,
+    database: &'a Path,
+}
+
+impl EntryVisitor for Search<'_> {
+    fn visit_file(&mut self, path: &Path, metrics: &Metrics) {
+        let database = self.database;
+        self.finder.offer(metrics, || CopySource::Database {
+            database: database.to_owned(),
+            path: path.to_owned(),
+        });
+    }
+}
+
+impl CopyFinder {
+    fn with_files(original: &Database, root: &Path, paths: Vec<PathBuf>) -> CopyFinder {
+        let mut finder = CopyFinder {
+            root: root.to_owned(),
+            wanted: BTreeMap::new(),
+            index: BTreeMap::new(),
+            sizes: Some(BTreeSet::new()),
+            targets: BTreeSet::new(),
+            recorded: Recorded::default(),
+            found: BTreeMap::new(),
+        };
+        for path in paths {
+            let metrics = match original.lookup(&path) {
+                Some(Entry::File(metrics)) => metrics.clone(),
+                _ => unreachable!(),
+            };
+            for (name, hash) in &ALGORITHMS {
+                if let Some(hash) = hash(&metrics) {
+                    finder
+                        .index
+                        .get_default((*name, hash.clone()))
+                        .push(path.clone());
+                }
+            }
+            match &mut finder.sizes {
+                Some(_) if metrics.known_size().is_none() => finder.sizes = None,
+                Some(sizes) => {
+                    sizes.insert(metrics.size);
+                }
+                None => {}
+            }
+            if let Ok(target) = fs::canonicalize(root.join(&path)) {
+                finder.targets.insert(target);
+            }
+            finder.recorded.visit_file(&path, &metrics);
+            finder.wanted.insert(path, metrics);
+        }
+        finder
+    }
+
+    // Searches for the given files of a database (or the files within
+    // the given directories), wherever they are now
+    pub fn new(
+        original: &Database,
+        root: impl AsRef<Path>,
+        paths: &[PathBuf],
+    ) -> Result<CopyFinder, error::Error> {
+        let mut files = Vec::new();
+        for path in paths {
+            let entry = original
+                .lookup(path)
+                .ok_or_else(|| error::Error::NotInDatabase(path.clone()))?;
+            entry_files(entry, path, &mut files);
+        }
+        Ok(CopyFinder::with_files(original, root.as_ref(), files))
+    }
+
+    // Searches for the files of a database that no longer match the
+    // directory it was built from, or are gone
+    pub fn damaged(
+        original: &Database,
+        root: impl AsRef<Path>,
+        threads: usize,
+    ) -> Result<CopyFinder, error::Error> {
+        // The directory is hashed as the database was, whatever hashes
+        // it was built with
+        let mut recorded = Recorded::default();
+        original.0.walk(&mut PathBuf::new(), &mut recorded);
+        let current = Database::scan(
+            root.as_ref(),
+            recorded.features,
+            recorded.legacy,
+            threads,
+            false,
+        )?;
+        let mut files = Vec::new();
+        damaged_files(&original.diff(&current), Path::new(""), &mut files);
+        Ok(CopyFinder::with_files(original, root.as_ref(), files))
+    }
+
+    pub fn damaged_count(&self) -> usize {
+        self.wanted.len()
+    }
+
+    fn offer(&mut self, metrics: &Metrics, source: impl Fn() -> CopySource) {
+        let mut matches = BTreeSet::new();
+        for (name, hash) in &ALGORITHMS {
+            if let Some(paths) =
+                hash(metrics).and_then(|hash| self.index.get(&(*name, hash.clone())))
+            {
+                matches.extend(
+                    paths
+                        .iter()
+                        .filter(|path| same_contents(&self.wanted[*path], metrics))
+                        .cloned(),
+                );
+            }
+        }
+        // Files on disk were just checked, so they're preferred to those
+        // only listed in a database
+        for path in matches {
+            let better = match (self.found.get(&path), source()) {
+                (None, source) => Some(source),
+                (Some(CopySource::Database { .. }), source @ CopySource::File(_)) => Some(source),
+                _ => None,
+            };
+            if let Some(source) = better {
+                self.found.insert(path, source);
+            }
+        }
+    }
+
+    // Looks for copies among the files of a database, named by its path
+    pub fn search_database(&mut self, name: impl AsRef<Path>, database: &Database) {
+        let mut search = Search {
+            finder: self,
+            database: name.as_ref(),
+        };
+        database.0.walk(&mut PathBuf::new(), &mut search);
+    }
+
+    // Looks for copies in a directory, hashing only the files of the
+    // sizes sought
+    pub fn search_directory(&mut self, root: impl AsRef<Path>) -> Result<(), error::Error> {
+        if self.wanted.is_empty() {
+            return Ok(());
+        }
+        for entry in WalkBuilder::new(root).build() {
+            let entry = entry?;
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            if let Some(sizes) = &self.sizes {
+                if !sizes.contains(&entry.metadata()?.len()) {
+                    continue;
+                }
+            }
+            // A damaged file can't be its own copy
+            if self.targets.contains(&fs::canonicalize(entry.path())?) {
+                continue;
+            }
+            let metrics =
+                compute_metrics(entry.path(), self.recorded.features, self.recorded.legacy)?;
+            self.offer(&metrics, || CopySource::File(entry.path().to_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> CopyPlan {
+        let mut found = self.found;
+        let mut copies = Vec::new();
+        let mut missing = Vec::new();
+        for path in self.wanted.into_keys() {
+            match found.remove(&path) {
+                Some(source) => copies.push((path, source)),
+                None => missing.push(path),
+            }
+        }
+        CopyPlan {
+            root: self.root,
+            copies,
+            missing,
+        }
+    }
+}
+
+// Quotes a path for the shell, as is
+fn quote(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+
+    let mut quoted = vec![b'\''];
+    for b in bytes {
+        if b == b'\'' {
+            quoted.extend(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+// Writes a comment, with any line breaks escaped so that a path can't
+// end it early and have the rest run as a command
+fn write_comment(w: &mut impl Write, line: &[u8]) -> io::Result<()> {
+    w.write_all(b"# ")?;
+    for &b in line {
+        match b {
+            b'\n' => w.write_all(b"\\n")?,
+            b'\r' => w.write_all(b"\\r")?,
+            _ => w.write_all(&[b])?,
+        }
+    }
+    w.write_all(b"\n")
+}
+
+impl CopyPlan {
+    pub fn write_text(&self, w: impl Write) -> Result<(), error::Error> {
+        let mut w = io::BufWriter::new(w);
+        for (path, source) in &self.copies {
+            match source {
+                CopySource::File(file) => {
+                    writeln!(w, "{}: copy from {}", path.display(), file.display())?
+                }
+                CopySource::Database {
+                    database,
+                    path: other,
+                } => writeln!(
+                    w,
+                    "{}: copy from {} (listed in {})",
+                    path.display(),
+                    other.display(),
+                    database.display()
+                )?,
+            }
+        }
+        for path in &self.missing {
+            writeln!(w, "{}: no good copy found", path.display())?;
+        }
+        writeln!(
+            w,
+            "{} of {} damaged files have a good copy",
+            self.copies.len(),
+            self.copies.len() + self.missing.len()
+        )?;
+        w.flush()?;
+        Ok(())
+    }
+
+    // Writes a script that copies each good file over the damaged one.
+    // Copies only known from a database are left commented out, since
+    // where its directory is now isn't known.
+    pub fn write_script(&self, w: impl Write) -> Result<(), error::Error> {
+        let mut w = io::BufWriter::new(w);
+        writeln!(w, "#!/bin/sh")?;
+        writeln!(w, "# Copies good versions over damaged files")?;
+        writeln!(w, "set -e")?;
+        for (path, source) in &self.copies {
+            let target = self.root.join(path);
+            let (commented, file) = match source {
+                CopySource::File(file) => (false, file),
+                CopySource::Database {
+                    database,
+                    path: other,
+                } => {
+                    let note = format!(
+                        "{}: listed in {}, relative to the directory it was built from",
+                        path.display(),
+                        database.display()
+                    );
+                    write_comment(&mut w, note.as_bytes())?;
+                    (true, other)
+                }
+            };
+            let mut commands = Vec::new();
+            // Directories of the root may have been removed as well
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                let mut mkdir = b"mkdir -p -- ".to_vec();
+                mkdir.extend(quote(&self.root.join(parent)));
+                commands.push(mkdir);
+            }
+            let mut cp = b"cp -p -- ".to_vec();
+            cp.extend(quote(file));
+            cp.push(b' ');
+            cp.extend(quote(&target));
+            commands.push(cp);
+            for command in commands {
+                if commented {
+                    write_comment(&mut w, &command)?;
+                } else {
+                    w.write_all(&command)?;
+                    writeln!(w)?;
+                }
+            }
+        }
+        for path in &self.missing {
+            let note = format!("{}: no good copy found", path.display());
+            write_comment(&mut w, note.as_bytes())?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
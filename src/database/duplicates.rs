@@ -0,0 +1,165 @@
+// Files with the same contents, found by their sizes and hashes, in one
+// database or across several (say, of different volumes).
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Serializer};
+
+use super::{BTreeMapExt, Database, EntryVisitor, HashSum, Metrics};
+use crate::error;
+
+// The hash identifying the contents of a file, preferring the ones
+// computed by default. Files are only grouped when hashed alike.
+fn content_hash(metrics: &Metrics) -> Option<(&'static str, &HashSum)> {
+    if let Some(hash) = &metrics.sha2 {
+        Some(("sha2-512/256", hash))
+    } else if let Some(hash) = &metrics.blake2b {
+        Some(("blake2b", hash))
+    } else {
+        metrics.sha256.as_ref().map(|hash| ("sha256", hash))
+    }
+}
+
+fn serialize_lossy<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DuplicateFile {
+    #[serde(serialize_with = "serialize_lossy")]
+    pub database: PathBuf,
+    #[serde(serialize_with = "serialize_lossy")]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DuplicateGroup {
+    pub size: u64,
+    pub algorithm: &'static str,
+    pub hash: HashSum,
+    pub wasted: u64, // Bytes taken by all but one of the copies
+    pub files: Vec<DuplicateFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Duplicates {
+    pub groups: Vec<DuplicateGroup>, // Most bytes wasted first
+    pub wasted: u64,
+    #[serde(skip)]
+    databases: usize,
+}
+
+type ContentKey = (u64, &'static str, HashSum);
+
+struct Collector<'a> {
+    database: usize,
+    files: &'a mut BTreeMap<ContentKey, Vec<(usize, PathBuf)>>,
+}
+
+impl EntryVisitor for Collector<'_> {
+    fn visit_file(&mut self, path: &Path, metrics: &Metrics) {
+        // Imported files may have no size, and empty files waste nothing
+        if metrics.known_size().is_none_or(|size| size == 0) {
+            return;
+        }
+        if let Some((algorithm, hash)) = content_hash(metrics) {
+            self.files
+                .get_default((metrics.size, algorithm, hash.clone()))
+                .push((self.database, path.to_owned()));
+        }
+    }
+}
+
+// Collects the files of one or more databases, named by their paths
+#[derive(Debug, Default)]
+pub struct DuplicateFinder {
+    databases: Vec<PathBuf>,
+    files: BTreeMap<ContentKey, Vec<(usize, PathBuf)>>,
+}
+
+impl DuplicateFinder {
+    pub fn new() -> DuplicateFinder {
+        DuplicateFinder::default()
+    }
+
+    pub fn add(&mut self, name: impl AsRef<Path>, database: &Database) {
+        let mut collector = Collector {
+            database: self.databases.len(),
+            files: &mut self.files,
+        };
+        database.0.walk(&mut PathBuf::new(), &mut collector);
+        self.databases.push(name.as_ref().to_owned());
+    }
+
+    pub fn finish(self) -> Duplicates {
+        let databases = self.databases;
+        let mut groups: Vec<_> = self
+            .files
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|((size, algorithm, hash), files)| DuplicateGroup {
+                size,
+                algorithm,
+                hash,
+                wasted: size * (files.len() as u64 - 1),
+                files: files
+                    .into_iter()
+                    .map(|(database, path)| DuplicateFile {
+                        database: databases[database].clone(),
+                        path,
+                    })
+                    .collect(),
+            })
+            .collect();
+        // The sort is stable, so ties stay in order of size and hash
+        groups.sort_by_key(|group| Reverse(group.wasted));
+        Duplicates {
+            wasted: groups.iter().map(|group| group.wasted).sum(),
+            groups,
+            databases: databases.len(),
+        }
+    }
+}
+
+impl Duplicates {
+    // Lists the copies in each group, naming their databases only when
+    // there is more than one
+    pub fn write_text(&self, w: impl Write) -> Result<(), error::Error> {
+        let mut w = io::BufWriter::new(w);
+        for group in &self.groups {
+            writeln!(
+                w,
+                "{} copies of {} bytes ({} bytes wasted):",
+                group.files.len(),
+                group.size,
+                group.wasted
+            )?;
+            for file in &group.files {
+                if self.databases > 1 {
+                    writeln!(w, "  {}: {}", file.database.display(), file.path.display())?;
+                } else {
+                    writeln!(w, "  {}", file.path.display())?;
+                }
+            }
+        }
+        writeln!(
+            w,
+            "{} groups of duplicates, {} bytes wasted",
+            self.groups.len(),
+            self.wasted
+        )?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn write_json(&self, w: impl Write) -> Result<(), error::Error> {
+        let mut w = io::BufWriter::new(w);
+        serde_json::to_writer_pretty(&mut w, self)?;
+        writeln!(w)?;
+        w.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+// Export of databases as the checksum lists read by other tools, such
+// as sha256sum -c and b2sum -c. (See import.rs for the reverse.)
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::mtree;
+use super::{Database, EntryVisitor, HashSum, Metrics};
+use crate::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    Sha256sum, // "<hex>  <path>", with SHA-256
+    B2sum,     // "<hex>  <path>", with BLAKE2b (256 bits)
+    Md5sum,    // "<hex>  <path>", with MD5 (imported files only)
+    Sha1sum,   // "<hex>  <path>", with SHA-1 (imported files only)
+    Sfv,       // "<path> <HEX>", with CRC32 (imported files only)
+    Bsd,       // "<ALGORITHM> (<path>) = <hex>", with the best hash available
+    Mtree,     // mtree(5) specification, with the size, metadata and MD5, SHA-1 and SHA-256
+}
+
+// Joins the components of a path with slashes, whatever the platform
+pub(super) fn path_bytes(path: &Path) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (i, component) in path.iter().enumerate() {
+        if i > 0 {
+            bytes.push(b'/');
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            bytes.extend(component.as_bytes());
+        }
+        #[cfg(not(unix))]
+        bytes.extend(component.to_string_lossy().as_bytes());
+    }
+    bytes
+}
+
+// Follows coreutils: a path containing a backslash, newline or carriage
+// return has those escaped, and the line is marked with a leading
+// backslash
+fn escape(path: &[u8]) -> (bool, Vec<u8>) {
+    let mut escaped = Vec::with_capacity(path.len());
+    for &b in path {
+        match b {
+            b'\\' => escaped.extend(b"\\\\"),
+            b'\n' => escaped.extend(b"\\n"),
+            b'\r' => escaped.extend(b"\\r"),
+            _ => escaped.push(b),
+        }
+    }
+    (escaped.len() != path.len(), escaped)
+}
+
+struct Exporter<W> {
+    w: W,
+    format: ListFormat,
+    files: u64,
+    error: Option<error::Error>,
+}
+
+impl<W: Write> Exporter<W> {
+    fn line(&mut self, path: &Path, metrics: &Metrics) -> Result<(), error::Error> {
+        let (tag, hash): (&str, &HashSum) = match self.format {
+            ListFormat::Sha256sum => (
+                "",
+                metrics.sha256.as_ref().ok_or(error::Error::MissingHash)?,
+            ),
+            ListFormat::B2sum => (
+                "",
+                metrics.blake2b.as_ref().ok_or(error::Error::MissingHash)?,
+            ),
+            ListFormat::Md5sum => ("", metrics.md5.as_ref().ok_or(error::Error::MissingHash)?),
+            ListFormat::Sha1sum => ("", metrics.sha1.as_ref().ok_or(error::Error::MissingHash)?),
+            ListFormat::Mtree => {
+                if !mtree::has_digest(metrics) {
+                    return Err(error::Error::MissingHash);
+                }
+                mtree::write_file(&mut self.w, path, metrics)?;
+                self.files += 1;
+                return Ok(());
+            }
+            ListFormat::Sfv => {
+                let hash = metrics.crc32.as_ref().ok_or(error::Error::MissingHash)?;
+                self.w.write_all(&path_bytes(path))?;
+                writeln!(self.w, " {}", hash.hex().to_uppercase())?;
+                self.files += 1;
+                return Ok(());
+            }
+            // Prefer the hashes that coreutils' cksum -c checks, then
+            // SHA2-512/256 for shasum -c
+            ListFormat::Bsd => [
+                ("SHA256", &metrics.sha256),
+                ("BLAKE2b-256", &metrics.blake2b),
+                ("SHA512/256", &metrics.sha2),
+                ("SHA1", &metrics.sha1),
+                ("MD5", &metrics.md5),
+            ]
+            .iter()
+            .find_map(|(tag, hash)| Some((*tag, hash.as_ref()?)))
+            .ok_or(error::Error::MissingHash)?,
+        };
+        let (escaped, path) = escape(&path_bytes(path));
+        if escaped {
+            self.w.write_all(b"\\")?;
+        }
+        if self.format == ListFormat::Bsd {
+            write!(self.w, "{} (", tag)?;
+            self.w.write_all(&path)?;
+            writeln!(self.w, ") = {}", hash.hex())?;
+        } else {
+            write!(self.w, "{}  ", hash.hex())?;
+            self.w.write_all(&path)?;
+            self.w.write_all(b"\n")?;
+        }
+        self.files += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> EntryVisitor for Exporter<W> {
+    fn enter_directory(&mut self, path: &Path) {
+        if self.format == ListFormat::Mtree && self.error.is_none() {
+            self.error = mtree::write_directory(&mut self.w, path)
+                .err()
+                .map(From::from);
+        }
+    }
+
+    fn visit_file(&mut self, path: &Path, metrics: &Metrics) {
+        if self.error.is_none() {
+            self.error = self.line(path, metrics).err();
+        }
+    }
+}
+
+impl Database {
+    // Writes a line for every file in the database, with its path
+    // relative to the directory the database was built from. Fails if a
+    // file lacks the hash the format needs (for mtree specifications,
+    // any of MD5, SHA-1 and SHA-256). Returns the number of files.
+    pub fn export(&self, w: impl Write, format: ListFormat) -> Result<u64, error::Error> {
+        let mut exporter = Exporter {
+            w: io::BufWriter::new(w),
+            format,
+            files: 0,
+            error: None,
+        };
+        if format == ListFormat::Mtree {
+            exporter.w.write_all(b"#mtree\n")?;
+        }
+        self.0.walk(&mut PathBuf::new(), &mut exporter);
+        if let Some(err) = exporter.error {
+            return Err(err);
+        }
+        exporter.w.flush()?;
+        Ok(exporter.files)
+    }
+}
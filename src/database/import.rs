@@ -0,0 +1,328 @@
+// Import of the checksum lists written by other tools (sha256sum,
+// md5sum, SFV and the like), so that old archives can be checked
+// against them. (See export.rs for the reverse.)
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use super::mtree;
+use super::{Database, Entry, HashSum, ListFormat, Metrics};
+use crate::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Algorithm {
+    Sha2,
+    Blake2b,
+    Sha256,
+    Md5,
+    Sha1,
+    Crc32,
+}
+
+impl Algorithm {
+    // Tags of the BSD format, as written by cksum --tag and shasum --tag
+    fn from_tag(tag: &[u8]) -> Option<Algorithm> {
+        match tag {
+            b"SHA512/256" => Some(Algorithm::Sha2),
+            b"BLAKE2b-256" => Some(Algorithm::Blake2b),
+            b"SHA256" => Some(Algorithm::Sha256),
+            b"MD5" => Some(Algorithm::Md5),
+            b"SHA1" => Some(Algorithm::Sha1),
+            _ => None,
+        }
+    }
+
+    pub(super) fn len(self) -> usize {
+        match self {
+            Algorithm::Sha2 | Algorithm::Blake2b | Algorithm::Sha256 => 32,
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::Crc32 => 4,
+        }
+    }
+
+    fn field(self, metrics: &mut Metrics) -> &mut Option<HashSum> {
+        match self {
+            Algorithm::Sha2 => &mut metrics.sha2,
+            Algorithm::Blake2b => &mut metrics.blake2b,
+            Algorithm::Sha256 => &mut metrics.sha256,
+            Algorithm::Md5 => &mut metrics.md5,
+            Algorithm::Sha1 => &mut metrics.sha1,
+            Algorithm::Crc32 => &mut metrics.crc32,
+        }
+    }
+}
+
+impl ListFormat {
+    // Guesses the format of a list from its file name
+    pub fn infer_from_path(path: &Path) -> Option<ListFormat> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match (name.as_str(), extension.as_deref()) {
+            ("sha256sums", _) | (_, Some("sha256")) => Some(ListFormat::Sha256sum),
+            ("b2sums", _) | (_, Some("b2")) => Some(ListFormat::B2sum),
+            ("md5sums", _) | (_, Some("md5")) => Some(ListFormat::Md5sum),
+            ("sha1sums", _) | (_, Some("sha1")) => Some(ListFormat::Sha1sum),
+            (_, Some("sfv")) => Some(ListFormat::Sfv),
+            ("mtree", _) | (".mtree", _) | (_, Some("mtree")) => Some(ListFormat::Mtree),
+            _ => None,
+        }
+    }
+
+    // The algorithm of untagged lines, if the format has them
+    fn algorithm(self) -> Option<Algorithm> {
+        match self {
+            ListFormat::Sha256sum => Some(Algorithm::Sha256),
+            ListFormat::B2sum => Some(Algorithm::Blake2b),
+            ListFormat::Md5sum => Some(Algorithm::Md5),
+            ListFormat::Sha1sum => Some(Algorithm::Sha1),
+            ListFormat::Sfv => Some(Algorithm::Crc32),
+            ListFormat::Bsd | ListFormat::Mtree => None,
+        }
+    }
+}
+
+pub(super) fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+// Reverses the escaping of sha256sum and friends (see export.rs)
+fn unescape(path: &[u8]) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'\\' {
+            match bytes.next()? {
+                b'\\' => result.push(b'\\'),
+                b'n' => result.push(b'\n'),
+                b'r' => result.push(b'\r'),
+                _ => return None,
+            }
+        } else {
+            result.push(b);
+        }
+    }
+    Some(result)
+}
+
+pub(super) fn to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    #[cfg(unix)]
+    let path = {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(OsString::from_vec(bytes))
+    };
+    #[cfg(not(unix))]
+    let path = PathBuf::from(OsString::from(String::from_utf8(bytes).ok()?));
+
+    // Only relative paths without .. can be placed in the database
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => result.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if result.as_os_str().is_empty() {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+// Splits "<ALGORITHM> (<path>) = <hex>"
+fn parse_tagged(line: &[u8]) -> Option<(Algorithm, &[u8], &[u8])> {
+    let open = line.windows(2).position(|w| w == b" (")?;
+    let close = line.windows(4).rposition(|w| w == b") = ")?;
+    if close < open + 2 {
+        return None;
+    }
+    let algorithm = Algorithm::from_tag(&line[..open])?;
+    Some((algorithm, &line[open + 2..close], &line[close + 4..]))
+}
+
+// Splits "<hex>  <path>" (or "<hex> *<path>", for binary mode)
+fn parse_untagged(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let space = line.iter().position(|&b| b == b' ')?;
+    match line.get(space + 1)? {
+        b' ' | b'*' => Some((&line[..space], &line[space + 2..])),
+        _ => None,
+    }
+}
+
+// Splits "<path> <HEX>", where the path may contain spaces
+fn parse_sfv(line: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let space = line.iter().rposition(|&b| b == b' ' || b == b'\t')?;
+    let path = line[..space].trim_ascii_end();
+    // SFV files often come from Windows
+    let path = path
+        .iter()
+        .map(|&b| if b == b'\\' { b'/' } else { b })
+        .collect();
+    Some((path, &line[space + 1..]))
+}
+
+fn parse_line(line: &[u8], format: ListFormat) -> Option<(Algorithm, PathBuf, HashSum)> {
+    let (algorithm, path, hex) = if format == ListFormat::Sfv {
+        let (path, hex) = parse_sfv(line)?;
+        (Algorithm::Crc32, path, hex)
+    } else {
+        // A leading backslash means the path is escaped
+        let (escaped, line) = match line.strip_prefix(b"\\") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (algorithm, path, hex) = match parse_tagged(line) {
+            Some(tagged) => tagged,
+            None => {
+                let (hex, path) = parse_untagged(line)?;
+                (format.algorithm()?, path, hex)
+            }
+        };
+        if format.algorithm().is_some_and(|a| a != algorithm) {
+            return None;
+        }
+        let path = if escaped {
+            unescape(path)?
+        } else {
+            path.to_vec()
+        };
+        (algorithm, path, hex)
+    };
+    let hash = from_hex(hex)?;
+    if hash.len() != algorithm.len() {
+        return None;
+    }
+    Some((algorithm, to_path(path)?, HashSum(hash)))
+}
+
+// Does the path conflict with a file, or a directory of files, already
+// collected?
+pub(super) fn conflicts(files: &BTreeMap<PathBuf, Metrics>, path: &Path) -> bool {
+    path.ancestors().skip(1).any(|a| files.contains_key(a))
+        || files
+            .range(path.to_owned()..)
+            .next()
+            .is_some_and(|(other, _)| other != path && other.starts_with(path))
+}
+
+// Collects the files listed in one or more checksum lists. Since the
+// lists only provide hashes, the files are marked as such; checking
+// them computes the same hashes and compares nothing else.
+#[derive(Debug, Default)]
+pub struct Importer {
+    files: BTreeMap<PathBuf, Metrics>,
+    skipped: u64, // Files listed without any digest
+}
+
+impl Importer {
+    pub fn new() -> Importer {
+        Importer::default()
+    }
+
+    fn insert(
+        &mut self,
+        line: usize,
+        path: PathBuf,
+        algorithm: Algorithm,
+        hash: HashSum,
+    ) -> Result<(), error::Error> {
+        if conflicts(&self.files, &path) {
+            return Err(error::Error::InvalidChecksumList(line));
+        }
+        let metrics = self.files.entry(path).or_insert_with(|| Metrics {
+            hashes_only: true,
+            ..Metrics::default()
+        });
+        let field = algorithm.field(metrics);
+        if field.as_ref().is_some_and(|other| *other != hash) {
+            return Err(error::Error::InvalidChecksumList(line));
+        }
+        *field = Some(hash);
+        Ok(())
+    }
+
+    // Adds the files of a list, with their paths relative to the prefix.
+    // Blank lines and comments are skipped; any other line that can't
+    // be parsed fails the import with its line number. Returns the
+    // number of files listed.
+    pub fn add(
+        &mut self,
+        r: impl Read,
+        format: ListFormat,
+        prefix: &Path,
+    ) -> Result<u64, error::Error> {
+        if format == ListFormat::Mtree {
+            let mut files = 0;
+            for file in mtree::parse(r)? {
+                // Files without a digest can't be checked
+                if file.hashes.is_empty() {
+                    self.skipped += 1;
+                    continue;
+                }
+                let path = prefix.join(&file.path);
+                for (algorithm, hash) in file.hashes {
+                    self.insert(file.line, path.clone(), algorithm, hash)?;
+                }
+                let metrics = self.files.get_mut(&path).expect("unreachable");
+                if let Some(size) = file.size {
+                    if metrics.size_known && metrics.size != size {
+                        return Err(error::Error::InvalidChecksumList(file.line));
+                    }
+                    metrics.size = size;
+                    metrics.size_known = true;
+                }
+                if let Some(archive) = file.archive {
+                    metrics.archive = Some(Box::new(archive));
+                }
+                files += 1;
+            }
+            return Ok(files);
+        }
+        let comment = if format == ListFormat::Sfv {
+            b';'
+        } else {
+            b'#'
+        };
+        let mut files = 0;
+        for (i, line) in BufReader::new(r).split(b'\n').enumerate() {
+            let line = line?;
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            if line.iter().all(u8::is_ascii_whitespace) || line.starts_with(&[comment]) {
+                continue;
+            }
+            let invalid = error::Error::InvalidChecksumList(i + 1);
+            let (algorithm, path, hash) = parse_line(line, format).ok_or(invalid)?;
+            self.insert(i + 1, prefix.join(path), algorithm, hash)?;
+            files += 1;
+        }
+        Ok(files)
+    }
+
+    // The number of files of mtree specifications that were skipped for
+    // lacking a digest
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn finish(self) -> Database {
+        let mut database = Database::default();
+        for (path, metrics) in self.files {
+            database.insert(path, Entry::File(metrics));
+        }
+        if let Entry::Directory(root) = &mut database.0 {
+            root.update_digest();
+        }
+        database
+    }
+}
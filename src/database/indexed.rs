@@ -0,0 +1,603 @@
+// Indexed encoding, which permits looking up individual files without
+// reading the entire database. See FORMAT.md for the layout.
+
+use std::fmt;
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use serde::de::{DeserializeSeed, SeqAccess, Visitor};
+use serde::Deserializer;
+use serde_json;
+
+use super::recover::{Lost, Salvage};
+use super::selfcheck::{
+    check_checksum, check_checksum_fields, check_metrics, json_error, schema_problems, Problem, Raw,
+};
+use super::{Database, DatabaseChecksum, DirectoryDigest, Entry, EntryVisitor, Features, Metrics};
+use crate::error;
+use crate::mac::MacKey;
+
+pub(super) const INDEX_MAGIC: &[u8] = b"ICKINDEX";
+
+// The trailer holds the offset and length of the index and the length
+// of the checksum (each u64, big endian), followed by the magic again
+const TRAILER_LEN: usize = 3 * 8 + 8;
+
+// Number of files stored in each independently compressed block
+const BLOCK_FILES: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Index {
+    blocks: Vec<BlockIndex>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<DirectoryDigest>, // Digest of the top-level directory
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockIndex {
+    first: PathBuf, // Path of the first file in the block
+    offset: u64,
+    length: u64,
+    checksum: DatabaseChecksum, // Checksum of the decompressed block
+}
+
+// Collects every file of a database in sorted order
+#[derive(Default)]
+struct FileCollector(Vec<(PathBuf, Metrics)>);
+
+impl EntryVisitor for FileCollector {
+    fn visit_file(&mut self, path: &Path, metrics: &Metrics) {
+        self.0.push((path.to_owned(), metrics.clone()));
+    }
+}
+
+pub(super) fn is_indexed(r: &mut impl BufRead) -> Result<bool, error::Error> {
+    Ok(r.fill_buf()?.starts_with(INDEX_MAGIC))
+}
+
+fn compress(bytes: &[u8]) -> Result<Vec<u8>, error::Error> {
+    let mut e = GzEncoder::new(Vec::new(), Compression::best());
+    e.write_all(bytes)?;
+    Ok(e.finish()?)
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, error::Error> {
+    let mut result = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut result)?;
+    Ok(result)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+// Read exactly length bytes, without trusting length for allocation
+fn read_exact_at(
+    r: &mut (impl Read + Seek),
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>, error::Error> {
+    r.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::new();
+    r.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(error::Error::ParseError);
+    }
+    Ok(bytes)
+}
+
+fn slice(bytes: &[u8], offset: u64, length: u64) -> Option<&[u8]> {
+    let end = offset.checked_add(length)?;
+    if end > bytes.len() as u64 {
+        return None;
+    }
+    Some(&bytes[offset as usize..end as usize])
+}
+
+fn layout(message: &str) -> Problem {
+    Problem::Layout {
+        message: message.to_owned(),
+    }
+}
+
+// Decodes the checksum and index of an indexed database, reporting
+// what is wrong with them
+fn check_index(
+    bytes: &[u8],
+    key: Option<&MacKey>,
+    problems: &mut Vec<Problem>,
+) -> (Option<DatabaseChecksum>, Option<Index>) {
+    let len = bytes.len();
+    if len < INDEX_MAGIC.len() + TRAILER_LEN || !bytes.ends_with(INDEX_MAGIC) {
+        problems.push(layout("the trailer is missing"));
+        return (None, None);
+    }
+    let trailer = &bytes[len - TRAILER_LEN..];
+    let index_offset = read_u64(&trailer[0..]);
+    let index_length = read_u64(&trailer[8..]);
+    let checksum_length = read_u64(&trailer[16..]);
+    let index_gz = match slice(bytes, index_offset, index_length) {
+        Some(index_gz) => index_gz,
+        None => {
+            problems.push(layout("the index lies outside the file"));
+            return (None, None);
+        }
+    };
+    let checksum_json = match slice(bytes, index_offset + index_length, checksum_length) {
+        Some(checksum_json) => checksum_json,
+        None => {
+            problems.push(layout("the checksum lies outside the file"));
+            return (None, None);
+        }
+    };
+
+    let expected = match serde_json::from_slice::<Raw>(checksum_json) {
+        Ok(raw) => {
+            schema_problems(PathBuf::new(), check_checksum_fields(&raw), problems);
+            serde_json::from_slice::<DatabaseChecksum>(checksum_json)
+                .map_err(|err| json_error(&err, 0))
+        }
+        Err(err) => Err(json_error(&err, 0)),
+    };
+    let index_json = super::selfcheck::decompress(index_gz, problems);
+    let expected = match expected {
+        Ok(expected) => {
+            check_checksum(&expected, &index_json, key, problems);
+            Some(expected)
+        }
+        Err((position, message)) => {
+            problems.push(Problem::Checksum { position, message });
+            None
+        }
+    };
+    match serde_json::from_slice(&index_json) {
+        Ok(index) => (expected, Some(index)),
+        Err(err) => {
+            let (position, message) = json_error(&err, 0);
+            problems.push(Problem::Parse { position, message });
+            (expected, None)
+        }
+    }
+}
+
+// Reports every problem with an indexed database (see
+// Database::self_check), checking each block even if others are
+// damaged. Returns whether the database is marked as partial.
+pub(super) fn self_check(bytes: &[u8], key: Option<&MacKey>, problems: &mut Vec<Problem>) -> bool {
+    let (expected, index) = check_index(bytes, key, problems);
+    let partial = expected.is_some_and(|expected| expected.partial);
+    let index = match index {
+        Some(index) => index,
+        None => return partial,
+    };
+
+    let mut previous = None;
+    for (i, block) in index.blocks.iter().enumerate() {
+        let mut block_problems = Vec::new();
+        check_block(bytes, block, &mut previous, &mut block_problems);
+        problems.extend(block_problems.into_iter().map(|problem| Problem::Block {
+            block: i,
+            problem: Box::new(problem),
+        }));
+    }
+
+    // Only the top-level digest is stored, and it can only be checked
+    // once every block is intact
+    if problems.is_empty() {
+        match IndexedDatabase::open_keyed(Cursor::new(bytes), key).and_then(|db| db.into_database())
+        {
+            Ok(_) => {}
+            Err(error::Error::ChecksumMismatch) => problems.push(Problem::Digest {
+                path: PathBuf::new(),
+            }),
+            Err(err) => problems.push(Problem::Parse {
+                position: None,
+                message: format!("{:?}", err),
+            }),
+        }
+    }
+    partial
+}
+
+// Salvages the files of an indexed database (see Database::recover),
+// skipping damaged blocks. Without an index, blocks are found by
+// scanning for gzip headers.
+pub(super) fn recover(
+    bytes: &[u8],
+    key: Option<&MacKey>,
+    salvage: &mut Salvage,
+    problems: &mut Vec<Problem>,
+    lost: &mut Vec<Lost>,
+) -> Option<DatabaseChecksum> {
+    let before = problems.len();
+    let (expected, index) = check_index(bytes, key, problems);
+    // The checksums of the blocks can't be trusted unless the index is
+    // intact
+    let index_verified = problems.len() == before;
+    let index = match index {
+        Some(index) => index,
+        None => {
+            lost.push(Lost::Unknown);
+            for start in INDEX_MAGIC.len()..bytes.len() {
+                if bytes[start..].starts_with(&[0x1f, 0x8b, 0x08]) {
+                    let block_json = super::selfcheck::decompress(&bytes[start..], &mut Vec::new());
+                    let _ = salvage_block(&block_json, salvage);
+                }
+            }
+            salvage.distrust(0);
+            return expected;
+        }
+    };
+
+    for (i, block) in index.blocks.iter().enumerate() {
+        let start = salvage.file_count();
+        let mut block_problems = Vec::new();
+        let complete = match slice(bytes, block.offset, block.length) {
+            Some(block_gz) => {
+                let block_json = super::selfcheck::decompress(block_gz, &mut block_problems);
+                check_checksum(&block.checksum, &block_json, None, &mut block_problems);
+                match salvage_block(&block_json, salvage) {
+                    Ok(()) => block_problems.is_empty(),
+                    Err(err) => {
+                        let (position, message) = json_error(&err, 0);
+                        block_problems.push(Problem::Parse { position, message });
+                        false
+                    }
+                }
+            }
+            None => {
+                block_problems.push(layout("the block lies outside the file"));
+                false
+            }
+        };
+        if !complete || !index_verified {
+            salvage.distrust(start);
+        }
+        if !complete {
+            let next = index.blocks.get(i + 1).map(|next| next.first.clone());
+            lost.push(Lost::Between(salvage.last_file().cloned(), next));
+        }
+        problems.extend(block_problems.into_iter().map(|problem| Problem::Block {
+            block: i,
+            problem: Box::new(problem),
+        }));
+    }
+    expected
+}
+
+// Hands each file of a block to the salvage as soon as it is decoded,
+// so that the files before any damage are kept
+fn salvage_block(block_json: &[u8], salvage: &mut Salvage) -> Result<(), serde_json::Error> {
+    struct BlockSeed<'a>(&'a mut Salvage);
+
+    impl<'de> DeserializeSeed<'de> for BlockSeed<'_> {
+        type Value = ();
+
+        fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_seq(self)
+        }
+    }
+
+    impl<'de> Visitor<'de> for BlockSeed<'_> {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an array of files")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            while let Some((path, metrics)) = seq.next_element::<(PathBuf, Metrics)>()? {
+                self.0.visit_file(&path, &metrics);
+            }
+            Ok(())
+        }
+    }
+
+    let mut d = serde_json::Deserializer::from_slice(block_json);
+    BlockSeed(salvage).deserialize(&mut d)
+}
+
+fn check_block(
+    bytes: &[u8],
+    block: &BlockIndex,
+    previous: &mut Option<PathBuf>,
+    problems: &mut Vec<Problem>,
+) {
+    let block_gz = match slice(bytes, block.offset, block.length) {
+        Some(block_gz) => block_gz,
+        None => return problems.push(layout("the block lies outside the file")),
+    };
+    let block_json = super::selfcheck::decompress(block_gz, problems);
+    check_checksum(&block.checksum, &block_json, None, problems);
+
+    let files = match serde_json::from_slice::<Raw>(&block_json) {
+        Ok(Raw::Seq(files)) => files,
+        Ok(_) => {
+            let message = "expected an array of files".to_owned();
+            return schema_problems(PathBuf::new(), vec![message], problems);
+        }
+        Err(err) => {
+            let (position, message) = json_error(&err, 0);
+            return problems.push(Problem::Parse { position, message });
+        }
+    };
+    for (i, file) in files.iter().enumerate() {
+        let (path, metrics) = match file {
+            Raw::Seq(pair) if pair.len() == 2 => match &pair[0] {
+                Raw::Str(path) => (PathBuf::from(path), &pair[1]),
+                _ => {
+                    let message = format!("file {}: expected a path", i);
+                    schema_problems(PathBuf::new(), vec![message], problems);
+                    continue;
+                }
+            },
+            _ => {
+                let message = format!("file {}: expected a pair of path and metrics", i);
+                schema_problems(PathBuf::new(), vec![message], problems);
+                continue;
+            }
+        };
+        if i == 0 && path != block.first {
+            problems.push(layout("the first file does not match the index"));
+        }
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            schema_problems(path.clone(), vec!["invalid path".to_owned()], problems);
+        }
+        if previous.as_ref().is_some_and(|previous| *previous >= path) {
+            let message = "duplicate or out of order".to_owned();
+            schema_problems(path.clone(), vec![message], problems);
+        }
+        schema_problems(path.clone(), check_metrics(metrics), problems);
+        *previous = Some(path);
+    }
+}
+
+impl Database {
+    pub fn dump_indexed<W>(&self, w: W, features: Features) -> Result<W, error::Error>
+    where
+        W: Write,
+    {
+        Ok(self.encode_indexed(w, features, None, false)?.0)
+    }
+
+    pub(super) fn encode_indexed<W>(
+        &self,
+        mut w: W,
+        features: Features,
+        key: Option<&MacKey>,
+        partial: bool,
+    ) -> Result<(W, DatabaseChecksum), error::Error>
+    where
+        W: Write,
+    {
+        let mut files = FileCollector::default();
+        self.0.walk(&mut PathBuf::new(), &mut files);
+
+        // Write each block, recording where it went
+        w.write_all(INDEX_MAGIC)?;
+        let mut offset = INDEX_MAGIC.len() as u64;
+        let mut blocks = Vec::new();
+        for chunk in files.0.chunks(BLOCK_FILES) {
+            let block_json = serde_json::to_vec(chunk)?;
+            let block = compress(&block_json)?;
+            w.write_all(&block)?;
+            blocks.push(BlockIndex {
+                first: chunk[0].0.clone(),
+                offset,
+                length: block.len() as u64,
+                checksum: Database::checksum(&block_json, features),
+            });
+            offset += block.len() as u64;
+        }
+
+        // Write the index, its checksum and the trailer
+        let index = Index {
+            blocks,
+            digest: self.digest().cloned(),
+        };
+        let index_json = serde_json::to_vec(&index)?;
+        let index_gz = compress(&index_json)?;
+        let checksum = Database::keyed_checksum(&index_json, features, key, partial)?;
+        let checksum_json = serde_json::to_vec(&checksum)?;
+        w.write_all(&index_gz)?;
+        w.write_all(&checksum_json)?;
+        w.write_all(&offset.to_be_bytes())?;
+        w.write_all(&(index_gz.len() as u64).to_be_bytes())?;
+        w.write_all(&(checksum_json.len() as u64).to_be_bytes())?;
+        w.write_all(INDEX_MAGIC)?;
+        Ok((w, checksum))
+    }
+
+    // Loads an entire indexed database. Since blocks precede the index,
+    // this has to buffer the (compressed) database in memory.
+    pub fn load_indexed(mut r: impl Read) -> Result<Database, error::Error> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        IndexedDatabase::open(Cursor::new(bytes))?.into_database()
+    }
+
+    // Finds the metrics of a single file. Indexed databases only read
+    // the block containing the file; other encodings are scanned in a
+    // single pass.
+    pub fn lookup_file<R>(
+        r: R,
+        key: Option<&MacKey>,
+        path: &Path,
+    ) -> Result<Option<Metrics>, error::Error>
+    where
+        R: Read + Seek,
+    {
+        let mut r = std::io::BufReader::new(r);
+        if is_indexed(&mut r)? {
+            return IndexedDatabase::open_keyed(r, key)?.lookup(path);
+        }
+
+        struct FindFile<'a> {
+            path: &'a Path,
+            found: Option<Metrics>,
+        }
+
+        impl EntryVisitor for FindFile<'_> {
+            fn visit_file(&mut self, path: &Path, metrics: &Metrics) {
+                if path == self.path {
+                    self.found = Some(metrics.clone());
+                }
+            }
+        }
+
+        let mut find = FindFile { path, found: None };
+        Database::visit(r, key, &mut find)?;
+        Ok(find.found)
+    }
+}
+
+pub struct IndexedDatabase<R> {
+    reader: R,
+    index: Index,
+    checksum: DatabaseChecksum,
+    features: Features,
+}
+
+impl<R: Read + Seek> IndexedDatabase<R> {
+    // Reads and verifies the index, but none of the blocks
+    pub fn open(reader: R) -> Result<IndexedDatabase<R>, error::Error> {
+        IndexedDatabase::open_keyed(reader, None)
+    }
+
+    // Also refuses the database unless its MAC matches the key
+    pub fn open_keyed(
+        mut reader: R,
+        key: Option<&MacKey>,
+    ) -> Result<IndexedDatabase<R>, error::Error> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        if len < (INDEX_MAGIC.len() + TRAILER_LEN) as u64 {
+            return Err(error::Error::ParseError);
+        }
+        let magic = read_exact_at(&mut reader, 0, INDEX_MAGIC.len() as u64)?;
+        let trailer = read_exact_at(&mut reader, len - TRAILER_LEN as u64, TRAILER_LEN as u64)?;
+        if magic != INDEX_MAGIC || &trailer[24..] != INDEX_MAGIC {
+            return Err(error::Error::ParseError);
+        }
+        let index_offset = read_u64(&trailer[0..]);
+        let index_length = read_u64(&trailer[8..]);
+        let checksum_length = read_u64(&trailer[16..]);
+
+        // Decode expected checksums
+        let checksum_offset = index_offset
+            .checked_add(index_length)
+            .ok_or(error::Error::ParseError)?;
+        let checksum_json = read_exact_at(&mut reader, checksum_offset, checksum_length)?;
+        let expected: DatabaseChecksum = serde_json::from_slice(&checksum_json)?;
+        let features = Features::infer_from_database_checksum(&expected);
+
+        // Compute actual checksums of index
+        let index_gz = read_exact_at(&mut reader, index_offset, index_length)?;
+        let index_json = decompress(&index_gz)?;
+        let actual = Database::checksum(&index_json, features);
+        if expected.diff(&actual) {
+            return Err(error::Error::ChecksumMismatch);
+        }
+        let mut mac = expected.mac_engine(key)?;
+        if let Some(mac) = &mut mac {
+            mac.input(&index_json);
+        }
+        expected.verify_mac(mac)?;
+
+        Ok(IndexedDatabase {
+            reader,
+            index: serde_json::from_slice(&index_json)?,
+            checksum: expected,
+            features,
+        })
+    }
+
+    pub fn digest(&self) -> Option<&DirectoryDigest> {
+        self.index.digest.as_ref()
+    }
+
+    pub fn checksum(&self) -> &DatabaseChecksum {
+        &self.checksum
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    fn read_block(&mut self, i: usize) -> Result<Vec<(PathBuf, Metrics)>, error::Error> {
+        let block = &self.index.blocks[i];
+        let block_gz = read_exact_at(&mut self.reader, block.offset, block.length)?;
+        let block_json = decompress(&block_gz)?;
+        let actual = Database::checksum(&block_json, self.features);
+        if block.checksum.diff(&actual) {
+            return Err(error::Error::ChecksumMismatch);
+        }
+        Ok(serde_json::from_slice(&block_json)?)
+    }
+
+    pub fn lookup(&mut self, path: &Path) -> Result<Option<Metrics>, error::Error> {
+        // Blocks are sorted, so the file can only be in the last block
+        // starting at or before it
+        let i = self
+            .index
+            .blocks
+            .partition_point(|block| block.first.as_path() <= path);
+        if i == 0 {
+            return Ok(None);
+        }
+        let mut files = self.read_block(i - 1)?;
+        Ok(files
+            .binary_search_by(|(p, _)| p.as_path().cmp(path))
+            .ok()
+            .map(|j| files.swap_remove(j).1))
+    }
+
+    pub fn into_database(mut self) -> Result<Database, error::Error> {
+        let mut database = Database::default();
+        let mut previous: Option<PathBuf> = None;
+        for i in 0..self.index.blocks.len() {
+            for (path, metrics) in self.read_block(i)? {
+                // The checksums say nothing about whether the writer
+                // produced a valid tree. Files must be strictly sorted
+                // and cannot sit beneath a file (or a file root), which
+                // in sorted order would be the file just before.
+                if !path.components().all(|c| matches!(c, Component::Normal(_)))
+                    || previous
+                        .as_ref()
+                        .is_some_and(|previous| *previous >= path || path.starts_with(previous))
+                {
+                    return Err(error::Error::ParseError);
+                }
+                previous = Some(path.clone());
+                if path.as_os_str().is_empty() {
+                    database = Database(Entry::File(metrics));
+                } else {
+                    database.insert(path, Entry::File(metrics));
+                }
+            }
+        }
+
+        // Directory digests are not stored, but can be recomputed from
+        // the files and compared against the top-level one
+        if let Some(expected) = &self.index.digest {
+            if let Entry::Directory(root) = &mut database.0 {
+                root.update_digest();
+            }
+            if database.digest() != Some(expected) {
+                return Err(error::Error::ChecksumMismatch);
+            }
+        }
+        Ok(database)
+    }
+}
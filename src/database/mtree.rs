@@ -0,0 +1,305 @@
+// mtree(5) specifications, as written by mtree -c and bsdtar
+// --format=mtree. Databases only record files, so the directories of a
+// specification just locate the files within it. Of the keywords of a
+// file, the size and digests are checked, while the mode, owner and
+// time are kept as its archive metadata; others (such as flags and
+// nlink) are ignored.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::export::path_bytes;
+use super::import::{from_hex, to_path, Algorithm};
+use super::{ArchiveMetadata, HashSum, Metrics};
+use crate::error;
+
+// Follows libarchive: whitespace, non-ASCII bytes and the characters
+// with a special meaning to mtree (including glob patterns) are written
+// as octal escapes
+fn escape(path: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(path.len());
+    for &b in path {
+        if b <= b' ' || b >= 0x7f || b"\\#=*?[".contains(&b) {
+            escaped.extend(format!("\\{:03o}", b).bytes());
+        } else {
+            escaped.push(b);
+        }
+    }
+    escaped
+}
+
+// Reverses the escaping of both mtree and libarchive, which use octal
+// escapes, and the short forms of vis(3)
+fn unescape(name: &[u8]) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(name.len());
+    let mut i = 0;
+    while i < name.len() {
+        if name[i] != b'\\' {
+            result.push(name[i]);
+            i += 1;
+            continue;
+        }
+        let b = match name.get(i + 1)? {
+            b'0'..=b'3' => {
+                let octal = std::str::from_utf8(name.get(i + 1..i + 4)?).ok()?;
+                i += 2;
+                u8::from_str_radix(octal, 8).ok()?
+            }
+            b'\\' => b'\\',
+            b's' => b' ',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b'#' => b'#',
+            _ => return None,
+        };
+        result.push(b);
+        i += 2;
+    }
+    Some(result)
+}
+
+fn write_path(w: &mut impl Write, path: &Path) -> std::io::Result<()> {
+    w.write_all(b".")?;
+    if !path.as_os_str().is_empty() {
+        w.write_all(b"/")?;
+        w.write_all(&escape(&path_bytes(path)))?;
+    }
+    Ok(())
+}
+
+pub(super) fn write_directory(w: &mut impl Write, path: &Path) -> std::io::Result<()> {
+    write_path(w, path)?;
+    writeln!(w, " type=dir")
+}
+
+pub(super) fn write_file(
+    w: &mut impl Write,
+    path: &Path,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    write_path(w, path)?;
+    write!(w, " type=file")?;
+    if let Some(size) = metrics.known_size() {
+        write!(w, " size={}", size)?;
+    }
+    if let Some(archive) = &metrics.archive {
+        if let Some(mode) = archive.mode {
+            write!(w, " mode={:04o}", mode)?;
+        }
+        for (keyword, id) in &[("uid", archive.uid), ("gid", archive.gid)] {
+            if let Some(id) = id {
+                write!(w, " {}={}", keyword, id)?;
+            }
+        }
+        for (keyword, name) in &[("uname", &archive.user), ("gname", &archive.group)] {
+            if let Some(name) = name {
+                write!(w, " {}=", keyword)?;
+                w.write_all(&escape(name.as_bytes()))?;
+            }
+        }
+        if let Some(mtime) = archive.mtime {
+            write!(w, " time={}.000000000", mtime)?;
+        }
+    }
+    for (keyword, hash) in &[
+        ("md5digest", &metrics.md5),
+        ("sha1digest", &metrics.sha1),
+        ("sha256digest", &metrics.sha256),
+    ] {
+        if let Some(hash) = hash {
+            write!(w, " {}={}", keyword, hash.hex())?;
+        }
+    }
+    writeln!(w)
+}
+
+// Can the file be written with at least one digest?
+pub(super) fn has_digest(metrics: &Metrics) -> bool {
+    metrics.md5.is_some() || metrics.sha1.is_some() || metrics.sha256.is_some()
+}
+
+fn algorithm(keyword: &[u8]) -> Option<Algorithm> {
+    match keyword {
+        b"md5" | b"md5digest" => Some(Algorithm::Md5),
+        b"sha1" | b"sha1digest" => Some(Algorithm::Sha1),
+        b"sha256" | b"sha256digest" => Some(Algorithm::Sha256),
+        _ => None,
+    }
+}
+
+// A file of a specification, with the line it starts on
+pub(super) struct SpecFile {
+    pub line: usize,
+    pub path: PathBuf,
+    pub hashes: Vec<(Algorithm, HashSum)>, // Empty if the file has no digest
+    pub size: Option<u64>,
+    pub archive: Option<ArchiveMetadata>,
+}
+
+fn integer(value: &[u8], radix: u32) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(value).ok()?, radix).ok()
+}
+
+fn name(value: &[u8]) -> Option<String> {
+    String::from_utf8(unescape(value)?).ok()
+}
+
+// The metadata of a file, if any is given. Times are recorded to the
+// second, as for archives.
+fn metadata(entry: &BTreeMap<Vec<u8>, Vec<u8>>) -> Option<Option<ArchiveMetadata>> {
+    let keyword = |key: &[u8]| entry.get(key).map(Vec::as_slice);
+    let mut metadata = ArchiveMetadata::default();
+    if let Some(mode) = keyword(b"mode") {
+        metadata.mode = Some(u32::try_from(integer(mode, 8)?).ok()?);
+    }
+    if let Some(uid) = keyword(b"uid") {
+        metadata.uid = Some(integer(uid, 10)?);
+    }
+    if let Some(gid) = keyword(b"gid") {
+        metadata.gid = Some(integer(gid, 10)?);
+    }
+    if let Some(uname) = keyword(b"uname") {
+        metadata.user = Some(name(uname)?);
+    }
+    if let Some(gname) = keyword(b"gname") {
+        metadata.group = Some(name(gname)?);
+    }
+    if let Some(time) = keyword(b"time") {
+        let seconds = time.split(|&b| b == b'.').next()?;
+        metadata.mtime = Some(integer(seconds, 10)?);
+    }
+    if metadata == ArchiveMetadata::default() {
+        Some(None)
+    } else {
+        Some(Some(metadata))
+    }
+}
+
+#[derive(Default)]
+struct Parser {
+    defaults: BTreeMap<Vec<u8>, Vec<u8>>, // Keywords given by /set
+    directories: Vec<PathBuf>,            // Directories entered, in the hierarchical form
+    files: Vec<SpecFile>,
+}
+
+impl Parser {
+    fn entry(&mut self, line: &[u8], number: usize) -> Option<()> {
+        let mut words = line
+            .split(|&b| b == b' ' || b == b'\t')
+            .filter(|word| !word.is_empty());
+        let name = match words.next() {
+            Some(name) if !name.starts_with(b"#") => name,
+            _ => return Some(()), // Blank line or comment
+        };
+        let keywords = words.filter_map(|word| {
+            let equals = word.iter().position(|&b| b == b'=')?;
+            Some((&word[..equals], &word[equals + 1..]))
+        });
+        match name {
+            b"/set" => {
+                for (key, value) in keywords {
+                    self.defaults.insert(key.to_vec(), value.to_vec());
+                }
+            }
+            b"/unset" => {
+                for word in line.split(|&b| b == b' ' || b == b'\t').skip(1) {
+                    if word == b"all" {
+                        self.defaults.clear();
+                    } else {
+                        self.defaults.remove(word);
+                    }
+                }
+            }
+            b".." => {
+                self.directories.pop()?;
+            }
+            _ => {
+                let mut entry = self.defaults.clone();
+                for (key, value) in keywords {
+                    entry.insert(key.to_vec(), value.to_vec());
+                }
+                let name = unescape(name)?;
+                // Names with a slash are relative to the root, and don't
+                // change the current directory
+                let full = name.contains(&b'/');
+                let path = if name == b"." {
+                    PathBuf::new()
+                } else if full {
+                    to_path(name)?
+                } else {
+                    let cwd = self.directories.last().cloned().unwrap_or_default();
+                    cwd.join(to_path(name)?)
+                };
+                match entry.get(&b"type"[..]).map(Vec::as_slice) {
+                    Some(b"dir") => {
+                        if !full {
+                            self.directories.push(path);
+                        }
+                    }
+                    Some(b"file") | None => {
+                        let mut hashes = Vec::new();
+                        for (key, value) in &entry {
+                            if let Some(algorithm) = algorithm(key) {
+                                let hash = from_hex(value)?;
+                                if hash.len() != algorithm.len() {
+                                    return None;
+                                }
+                                hashes.push((algorithm, HashSum(hash)));
+                            }
+                        }
+                        if path.as_os_str().is_empty() {
+                            return None;
+                        }
+                        let size = match entry.get(&b"size"[..]) {
+                            Some(size) => Some(integer(size, 10)?),
+                            None => None,
+                        };
+                        self.files.push(SpecFile {
+                            line: number,
+                            path,
+                            hashes,
+                            size,
+                            archive: metadata(&entry)?,
+                        });
+                    }
+                    // Links, devices and the like aren't recorded
+                    Some(_) => {}
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+// Reads the files of a specification. Fails with the line number of
+// anything that can't be parsed.
+pub(super) fn parse(r: impl Read) -> Result<Vec<SpecFile>, error::Error> {
+    let mut parser = Parser::default();
+    let mut pending = Vec::new();
+    let mut start = 0;
+    for (i, line) in BufReader::new(r).split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if pending.is_empty() {
+            start = i + 1;
+        }
+        // A trailing backslash continues the line
+        if let Some(line) = line.strip_suffix(b"\\") {
+            pending.extend(line);
+            pending.push(b' ');
+            continue;
+        }
+        pending.extend(line);
+        parser
+            .entry(&pending, start)
+            .ok_or(error::Error::InvalidChecksumList(start))?;
+        pending.clear();
+    }
+    if !pending.is_empty() {
+        return Err(error::Error::InvalidChecksumList(start));
+    }
+    Ok(parser.files)
+}
@@ -0,0 +1,349 @@
+// Recovery of damaged databases. Everything that can still be
+// decompressed and parsed is kept, down to individual files, and what
+// was lost is reported. Damage that still parses (such as a flipped bit
+// in a hash) can't be told apart from the original, so files that no
+// intact checksum covers are reported as unverified.
+
+use std::fmt;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use serde::de::DeserializeSeed;
+use serde_json;
+
+use super::cbor;
+use super::selfcheck::{cbor_error, check_checksum, decompress, json_error, Position, Problem};
+use super::{
+    indexed, Database, DatabaseChecksum, Directory, Encoding, Entry, EntrySeed, EntryVisitor,
+    Features, Metrics, CBOR_MAGIC, SEP,
+};
+use crate::error;
+use crate::mac::MacKey;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lost {
+    // Entries of a directory that sort after last (or all of them)
+    After {
+        directory: PathBuf,
+        last: Option<PathBuf>,
+    },
+    // Files of an indexed database that sort after the first path and
+    // before the second (or without bound)
+    Between(Option<PathBuf>, Option<PathBuf>),
+    // The index is damaged, so lost files can't be located
+    Unknown,
+}
+
+impl fmt::Display for Lost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lost::After {
+                directory,
+                last: Some(last),
+            } => write!(
+                f,
+                "{}: entries after {}",
+                show_directory(directory),
+                last.display()
+            ),
+            Lost::After {
+                directory,
+                last: None,
+            } => write!(f, "{}: all entries", show_directory(directory)),
+            Lost::Between(after, before) => {
+                write!(f, "files")?;
+                if let Some(after) = after {
+                    write!(f, " after {}", after.display())?;
+                }
+                if let Some(before) = before {
+                    write!(f, " before {}", before.display())?;
+                }
+                Ok(())
+            }
+            Lost::Unknown => write!(f, "unknown files (the index is damaged)"),
+        }
+    }
+}
+
+fn show_directory(path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        "top-level directory".to_owned()
+    } else {
+        path.display().to_string()
+    }
+}
+
+#[derive(Debug)]
+pub struct RecoveryReport {
+    pub encoding: Option<Encoding>,
+    pub features: Features, // Algorithms used by the recovered files
+    pub files: u64,         // Number of files recovered
+    pub partial: bool,      // Might files be missing from the recovered database?
+    pub problems: Vec<Problem>,
+    pub lost: Vec<Lost>,
+    pub unverified: Vec<PathBuf>, // Recovered files that no intact checksum covers
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.encoding {
+            Some(encoding) => writeln!(f, "recovered {} files from {}", self.files, encoding)?,
+            None => writeln!(f, "recovered {} files", self.files)?,
+        }
+        for problem in &self.problems {
+            writeln!(f, "damage: {}", problem)?;
+        }
+        for lost in &self.lost {
+            writeln!(f, "lost: {}", lost)?;
+        }
+        for path in &self.unverified {
+            writeln!(f, "unverified: {}", path.display())?;
+        }
+        if self.partial {
+            writeln!(f, "the recovered database is partial")
+        } else {
+            writeln!(f, "the database is intact")
+        }
+    }
+}
+
+// Builds a database from the entries that could be decoded. Entries
+// are only kept if they fit into the tree (damage might otherwise
+// produce duplicates).
+#[derive(Default)]
+pub(super) struct Salvage {
+    database: Database,
+    open: Vec<(PathBuf, Option<PathBuf>)>, // Directories being decoded, and their last complete entry
+    features: Option<Features>,
+    files: Vec<PathBuf>, // Files recovered so far, in order
+    unverified: Vec<PathBuf>,
+}
+
+impl Salvage {
+    fn fits(&self, path: &Path) -> bool {
+        matches!(self.database.0, Entry::Directory(_))
+            && !path.as_os_str().is_empty()
+            && path.components().all(|c| matches!(c, Component::Normal(_)))
+            && self.database.lookup(path).is_none()
+            && !path
+                .ancestors()
+                .any(|a| matches!(self.database.lookup(a), Some(Entry::File(_))))
+    }
+
+    fn completed(&mut self, path: &Path) {
+        if let Some((_, last)) = self.open.last_mut() {
+            *last = path.file_name().map(PathBuf::from);
+        }
+    }
+
+    pub(super) fn last_file(&self) -> Option<&PathBuf> {
+        self.files.last()
+    }
+
+    pub(super) fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    // Marks the files recovered after the first start as unverified
+    pub(super) fn distrust(&mut self, start: usize) {
+        self.unverified.extend_from_slice(&self.files[start..]);
+    }
+}
+
+impl EntryVisitor for Salvage {
+    fn enter_directory(&mut self, path: &Path) {
+        if self.fits(path) {
+            self.database
+                .insert(path.to_owned(), Entry::Directory(Directory::default()));
+        }
+        self.open.push((path.to_owned(), None));
+    }
+
+    fn leave_directory(&mut self, path: &Path) {
+        self.open.pop();
+        self.completed(path);
+    }
+
+    fn visit_file(&mut self, path: &Path, metrics: &Metrics) {
+        if path.as_os_str().is_empty() {
+            self.database = Database(Entry::File(metrics.clone()));
+        } else if self.fits(path) {
+            self.database
+                .insert(path.to_owned(), Entry::File(metrics.clone()));
+        } else {
+            return;
+        }
+        self.features
+            .get_or_insert_with(|| Features::infer_from_metrics(metrics));
+        self.files.push(path.to_owned());
+        self.completed(path);
+    }
+}
+
+impl Database {
+    // Salvages whatever can be decoded from a damaged database. The
+    // report lists the damage found, the entries that were lost, and
+    // the files kept that can't be checked against a checksum; unless
+    // it is marked intact, the database should be written with
+    // dump_partial. The key, if any, is only used to check the MAC.
+    pub fn recover(
+        mut r: impl Read,
+        key: Option<&MacKey>,
+    ) -> Result<(Database, RecoveryReport), error::Error> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+
+        let mut salvage = Salvage::default();
+        let mut problems = Vec::new();
+        let mut lost = Vec::new();
+        let mut started = true;
+        let (encoding, checksum) = if bytes.starts_with(indexed::INDEX_MAGIC) {
+            let checksum = indexed::recover(&bytes, key, &mut salvage, &mut problems, &mut lost);
+            (Some(Encoding::Indexed), checksum)
+        } else {
+            let data = decompress(&bytes, &mut problems);
+            let (encoding, result) = match data.first() {
+                None => (None, None),
+                Some(&b) if b == CBOR_MAGIC[0] => {
+                    let result = recover_cbor(&data, key, &mut salvage, &mut problems);
+                    (Some(Encoding::Cbor), result)
+                }
+                Some(_) => {
+                    let result = recover_json(&data, key, &mut salvage, &mut problems);
+                    (Some(Encoding::Json), result)
+                }
+            };
+            started = result.is_some();
+            (encoding, result.flatten())
+        };
+
+        // Whatever was still being decoded when decoding stopped is lost
+        for (directory, last) in salvage.open.drain(..).rev() {
+            lost.push(Lost::After { directory, last });
+        }
+        if !started {
+            lost.push(Lost::After {
+                directory: PathBuf::new(),
+                last: None,
+            });
+        }
+
+        let mut database = salvage.database;
+        if let Entry::Directory(root) = &mut database.0 {
+            root.update_digest();
+        }
+        let report = RecoveryReport {
+            encoding,
+            features: salvage
+                .features
+                .or_else(|| {
+                    checksum
+                        .as_ref()
+                        .map(Features::infer_from_database_checksum)
+                })
+                .unwrap_or_default(),
+            files: salvage.files.len() as u64,
+            partial: checksum.is_none_or(|checksum| checksum.partial)
+                || !problems.is_empty()
+                || !lost.is_empty(),
+            problems,
+            lost,
+            unverified: salvage.unverified,
+        };
+        Ok((database, report))
+    }
+}
+
+// Each of these returns None if the start of the entries is unknown,
+// and otherwise the checksum stored with the database, if it could be
+// read. A database whose checksum is lost is still partial, as its
+// entries can't be checked against it. Unless the checksum matches,
+// every file recovered is unverified.
+
+fn recover_json(
+    data: &[u8],
+    key: Option<&MacKey>,
+    salvage: &mut Salvage,
+    problems: &mut Vec<Problem>,
+) -> Option<Option<DatabaseChecksum>> {
+    let (header, body) = match data.iter().position(|&b| b == SEP) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => {
+            problems.push(Problem::Checksum {
+                position: None,
+                message: "no newline after the checksum".to_owned(),
+            });
+            return None;
+        }
+    };
+    // The entries start after the newline even if the checksum before
+    // it is damaged
+    let before = problems.len();
+    let expected = match serde_json::from_slice::<DatabaseChecksum>(header) {
+        Ok(expected) => {
+            check_checksum(&expected, body, key, problems);
+            Some(expected)
+        }
+        Err(err) => {
+            let (position, message) = json_error(&err, 0);
+            problems.push(Problem::Checksum { position, message });
+            None
+        }
+    };
+    let verified = problems.len() == before;
+
+    let mut path = PathBuf::new();
+    let seed = EntrySeed {
+        path: &mut path,
+        visitor: salvage,
+    };
+    let mut d = serde_json::Deserializer::from_slice(body);
+    if let Err(err) = seed.deserialize(&mut d) {
+        let (position, message) = json_error(&err, 1);
+        problems.push(Problem::Parse { position, message });
+    }
+    if !verified {
+        salvage.distrust(0);
+    }
+    Some(expected)
+}
+
+fn recover_cbor(
+    data: &[u8],
+    key: Option<&MacKey>,
+    salvage: &mut Salvage,
+    problems: &mut Vec<Problem>,
+) -> Option<Option<DatabaseChecksum>> {
+    let rest = match data.strip_prefix(CBOR_MAGIC) {
+        Some(rest) => rest,
+        None => {
+            problems.push(Problem::Checksum {
+                position: Some(Position::Offset(0)),
+                message: "incomplete CBOR magic".to_owned(),
+            });
+            return None;
+        }
+    };
+    let (expected, header_len) = match cbor::from_slice_prefix::<DatabaseChecksum>(rest) {
+        Ok(expected) => expected,
+        Err(err) => {
+            let (position, message) = cbor_error(&err, CBOR_MAGIC.len() as u64);
+            problems.push(Problem::Checksum { position, message });
+            return None;
+        }
+    };
+    let body_start = CBOR_MAGIC.len() + header_len;
+    let body = &data[body_start..];
+    let before = problems.len();
+    check_checksum(&expected, body, key, problems);
+    let verified = problems.len() == before;
+
+    if let Err(err) = cbor::visit(body, &mut PathBuf::new(), salvage) {
+        let (position, message) = cbor_error(&err, body_start as u64);
+        problems.push(Problem::Parse { position, message });
+    }
+    if !verified {
+        salvage.distrust(0);
+    }
+    Some(Some(expected))
+}
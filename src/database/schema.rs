@@ -0,0 +1,326 @@
+// Validation of decoded entries against the JSON schemas in schema/,
+// which are compiled into the binary so that they remain the single
+// description of the format. Only the keywords those schemas use are
+// understood: $ref, anyOf, type, enum, properties, required,
+// additionalProperties, items, minLength, maxLength, pattern, minimum
+// and maximum.
+
+use std::collections::{BTreeSet, HashMap};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use serde_json::{self, Value};
+
+use super::selfcheck::Raw;
+
+const DATABASE: &str = include_str!("../../schema/database.json");
+const CHECKSUM: &str = include_str!("../../schema/checksum.json");
+
+// Pointer to the metrics of a file within schema/database.json
+pub(super) const METRICS: &str = "/definitions/file/properties/File";
+
+pub(super) struct Schema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+pub(super) fn database() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(|| Schema::new(DATABASE))
+}
+
+pub(super) fn checksum() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(|| Schema::new(CHECKSUM))
+}
+
+impl Schema {
+    fn new(source: &str) -> Schema {
+        let root: Value = serde_json::from_str(source).expect("schema is not valid JSON");
+        let mut patterns = HashMap::new();
+        collect_patterns(&root, &mut patterns);
+        Schema { root, patterns }
+    }
+
+    // Checks raw against the part of the schema at pointer (the whole
+    // schema if empty), returning each violation along with the path
+    // of the entry it was found in
+    pub(super) fn check(&self, pointer: &str, raw: &Raw, path: PathBuf) -> Vec<(PathBuf, String)> {
+        let schema = self.root.pointer(pointer).expect("no such schema");
+        let mut check = Check {
+            schema: self,
+            path,
+            fields: Vec::new(),
+            found: Vec::new(),
+        };
+        check.value(schema, raw);
+        check.found
+    }
+
+    fn resolve(&self, reference: &str) -> &Value {
+        reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .expect("unresolved schema reference")
+    }
+}
+
+fn collect_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) {
+    match schema {
+        Value::Object(map) => {
+            if let Some(Value::String(pattern)) = map.get("pattern") {
+                let regex = Regex::new(pattern).expect("invalid schema pattern");
+                patterns.insert(pattern.clone(), regex);
+            }
+            map.values()
+                .for_each(|value| collect_patterns(value, patterns));
+        }
+        Value::Array(items) => items
+            .iter()
+            .for_each(|item| collect_patterns(item, patterns)),
+        _ => {}
+    }
+}
+
+fn describe(kind: &str) -> String {
+    match kind {
+        "object" | "array" | "integer" => format!("an {}", kind),
+        kind => format!("a {}", kind),
+    }
+}
+
+fn matches_type(kind: &str, raw: &Raw) -> bool {
+    match kind {
+        "object" => matches!(raw, Raw::Map(_)),
+        "array" => matches!(raw, Raw::Seq(_)),
+        // CBOR stores hashes as byte strings, which stand in for their
+        // base64 encoding in JSON
+        "string" => matches!(raw, Raw::Str(_) | Raw::Bytes(_)),
+        "integer" => matches!(raw, Raw::Unsigned(_) | Raw::Signed),
+        "number" => matches!(raw, Raw::Unsigned(_) | Raw::Signed | Raw::Float),
+        "boolean" => matches!(raw, Raw::Bool),
+        "null" => matches!(raw, Raw::Null),
+        _ => true,
+    }
+}
+
+// Entry names can't be constrained by the schema, but must still make
+// sense as paths
+fn invalid_name(name: &str) -> Option<&'static str> {
+    if name.is_empty() {
+        Some("empty")
+    } else if name == ".." {
+        Some("refers to the parent directory")
+    } else if name.contains('/') {
+        Some("contains a slash")
+    } else if name.contains('\0') {
+        Some("contains a NUL byte")
+    } else {
+        None
+    }
+}
+
+struct Check<'a> {
+    schema: &'a Schema,
+    path: PathBuf,       // Entry being checked
+    fields: Vec<String>, // Fields leading to the value within the entry
+    found: Vec<(PathBuf, String)>,
+}
+
+impl Check<'_> {
+    fn report(&mut self, message: String) {
+        let message = if self.fields.is_empty() {
+            message
+        } else {
+            format!("{}: {}", self.fields.join(": "), message)
+        };
+        self.found.push((self.path.clone(), message));
+    }
+
+    fn value(&mut self, schema: &Value, raw: &Raw) {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let schema = self.schema.resolve(reference);
+            return self.value(schema, raw);
+        }
+        if let Some(alternatives) = schema.get("anyOf").and_then(Value::as_array) {
+            return self.any_of(alternatives, raw);
+        }
+        if let Some(kind) = schema.get("type").and_then(Value::as_str) {
+            if !matches_type(kind, raw) {
+                return self.report(format!("expected {}, got {}", describe(kind), raw.kind()));
+            }
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            if let Raw::Str(s) = raw {
+                if !values.iter().any(|v| v.as_str() == Some(s)) {
+                    return self.report(format!("unknown value {:?}", s));
+                }
+            }
+        }
+        match raw {
+            Raw::Str(s) => self.string(schema, s),
+            Raw::Bytes(bytes) => self.string(schema, &::base64::encode(bytes)),
+            Raw::Unsigned(v) => self.unsigned(schema, *v),
+            Raw::Signed => {
+                // Only negative integers are decoded as signed
+                if let Some(min) = schema.get("minimum").and_then(Value::as_u64) {
+                    self.report(format!("negative, but the minimum is {}", min));
+                }
+            }
+            Raw::Seq(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.fields.push(i.to_string());
+                        self.value(item_schema, item);
+                        self.fields.pop();
+                    }
+                }
+            }
+            Raw::Map(entries) => self.object(schema, entries),
+            Raw::Null | Raw::Bool | Raw::Float => {}
+        }
+    }
+
+    fn string(&mut self, schema: &Value, s: &str) {
+        let len = s.chars().count() as u64;
+        let min = schema.get("minLength").and_then(Value::as_u64);
+        let max = schema.get("maxLength").and_then(Value::as_u64);
+        match (min, max) {
+            (Some(min), Some(max)) if min == max && len != min => {
+                return self.report(format!("expected {} characters, got {}", min, len));
+            }
+            (Some(min), _) if len < min => {
+                return self.report(format!("expected at least {} characters, got {}", min, len));
+            }
+            (_, Some(max)) if len > max => {
+                return self.report(format!("expected at most {} characters, got {}", max, len));
+            }
+            _ => {}
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            if !self.schema.patterns[pattern].is_match(s) {
+                self.report(format!("does not match the pattern {}", pattern));
+            }
+        }
+    }
+
+    fn unsigned(&mut self, schema: &Value, v: u64) {
+        if let Some(min) = schema.get("minimum").and_then(Value::as_u64) {
+            if v < min {
+                self.report(format!("{} is less than the minimum of {}", v, min));
+            }
+        }
+        if let Some(max) = schema.get("maximum").and_then(Value::as_u64) {
+            if v > max {
+                self.report(format!("{} exceeds the maximum of {}", v, max));
+            }
+        }
+    }
+
+    fn object(&mut self, schema: &Value, entries: &[(String, Raw)]) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let additional = schema.get("additionalProperties");
+        // An object with a single property is how an enum variant is
+        // tagged, which isn't worth naming in messages
+        let tagged = properties.is_some_and(|p| p.len() == 1);
+
+        let mut seen = BTreeSet::new();
+        for (name, raw) in entries {
+            let property = properties.and_then(|p| p.get(name));
+            // Keys allowed by a schema for additionalProperties are
+            // the names of entries in a directory
+            let entry = property.is_none() && additional.is_some_and(Value::is_object);
+            if !seen.insert(name.as_str()) {
+                let kind = if entry { "entry" } else { "field" };
+                self.report(format!("duplicate {} {:?}", kind, name));
+                continue;
+            }
+            match (property, additional) {
+                (Some(property), _) => {
+                    if !tagged {
+                        self.fields.push(name.clone());
+                    }
+                    self.value(property, raw);
+                    if !tagged {
+                        self.fields.pop();
+                    }
+                }
+                (None, Some(Value::Bool(false))) => {
+                    self.report(format!("unknown field {:?}", name));
+                }
+                (None, Some(additional)) if entry => match invalid_name(name) {
+                    Some(reason) => {
+                        self.report(format!("invalid entry name {:?}: {}", name, reason));
+                    }
+                    None => {
+                        let fields = mem::take(&mut self.fields);
+                        self.path.push(name);
+                        self.value(additional, raw);
+                        self.path.pop();
+                        self.fields = fields;
+                    }
+                },
+                _ => {}
+            }
+        }
+        let required = schema.get("required").and_then(Value::as_array);
+        for name in required.into_iter().flatten().filter_map(Value::as_str) {
+            if !seen.contains(name) {
+                self.report(format!("missing field {:?}", name));
+            }
+        }
+    }
+
+    // Accepts whatever matches one of the alternatives. Otherwise, the
+    // problems are only worth reporting in detail for an object that
+    // has every field required by exactly one of them.
+    fn any_of(&mut self, alternatives: &[Value], raw: &Raw) {
+        let mut candidates = Vec::new();
+        for alternative in alternatives {
+            let mut check = Check {
+                schema: self.schema,
+                path: self.path.clone(),
+                fields: self.fields.clone(),
+                found: Vec::new(),
+            };
+            check.value(alternative, raw);
+            if check.found.is_empty() {
+                return;
+            }
+            let schema = match alternative.get("$ref").and_then(Value::as_str) {
+                Some(reference) => self.schema.resolve(reference),
+                None => alternative,
+            };
+            let has_required = match raw {
+                Raw::Map(entries) => schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                    .all(|name| entries.iter().any(|(key, _)| key == name)),
+                _ => false,
+            };
+            if has_required {
+                candidates.push(check.found);
+            }
+        }
+        if candidates.len() == 1 {
+            self.found.append(&mut candidates[0]);
+            return;
+        }
+        let names: Vec<_> = alternatives
+            .iter()
+            .filter_map(|a| a.get("$ref").and_then(Value::as_str))
+            .filter_map(|r| r.rsplit('/').next())
+            .collect();
+        self.report(format!(
+            "expected {}, got {}",
+            names.join(" or "),
+            raw.kind()
+        ));
+    }
+}
@@ -0,0 +1,610 @@
+// Detailed consistency check of an encoded database. Rather than
+// stopping at the first error, this reports everything it can find:
+// damage to the gzip stream, mismatched checksums, the position of
+// parse errors, and contents that don't conform to the schemas in
+// schema/.
+
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::bufread::GzDecoder;
+
+use serde::de::{Deserialize, MapAccess, SeqAccess, Visitor};
+use serde::Deserializer;
+use serde_json;
+
+use super::cbor::{self, CborError};
+use super::{
+    indexed, schema, Database, DatabaseChecksum, Directory, Encoding, Entry, Features, HashSum,
+    CBOR_MAGIC, SEP,
+};
+use crate::error;
+use crate::mac::MacKey;
+
+// Where in the decompressed stream (or block) something went wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Position {
+    Line { line: usize, column: usize }, // JSON
+    Offset(u64),                         // CBOR, in bytes
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Gzip {
+        offset: u64,
+        message: String,
+    }, // Decompression failed after offset bytes of output
+    TrailingData {
+        bytes: u64,
+    }, // Garbage after the end of the gzip stream
+    Layout {
+        message: String,
+    }, // The indexed framing is broken
+    Checksum {
+        position: Option<Position>,
+        message: String,
+    }, // The checksum can't be read
+    HashMismatch {
+        algorithm: &'static str,
+        expected: HashSum,
+        actual: HashSum,
+    },
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    MissingMac,
+    MacMismatch,
+    Parse {
+        position: Option<Position>,
+        message: String,
+    },
+    Schema {
+        path: PathBuf,
+        message: String,
+    }, // An entry violates the schema
+    Digest {
+        path: PathBuf,
+    }, // A directory digest doesn't match its entries
+    Block {
+        block: usize,
+        problem: Box<Problem>,
+    }, // A problem within a block of an indexed database
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Position::Line { line, column } => write!(f, "line {}, column {}", line, column),
+            Position::Offset(offset) => write!(f, "byte {}", offset),
+        }
+    }
+}
+
+fn at(position: &Option<Position>) -> String {
+    position
+        .as_ref()
+        .map_or_else(String::new, |position| format!(" at {}", position))
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Gzip { offset, message } => write!(
+                f,
+                "gzip stream is damaged after {} bytes of output: {}",
+                offset, message
+            ),
+            Problem::TrailingData { bytes } => {
+                write!(f, "{} bytes of trailing data after the gzip stream", bytes)
+            }
+            Problem::Layout { message } => write!(f, "indexed layout is broken: {}", message),
+            Problem::Checksum { position, message } => {
+                write!(f, "checksum is unreadable{}: {}", at(position), message)
+            }
+            Problem::HashMismatch {
+                algorithm,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} mismatch: expected {}, actual {}",
+                algorithm,
+                expected.describe(),
+                actual.describe()
+            ),
+            Problem::SizeMismatch { expected, actual } => write!(
+                f,
+                "size mismatch: expected {} bytes, actual {} bytes",
+                expected, actual
+            ),
+            Problem::MissingMac => write!(f, "database has no MAC, but a key was given"),
+            Problem::MacMismatch => write!(f, "MAC does not match the key"),
+            Problem::Parse { position, message } => {
+                write!(f, "parse error{}: {}", at(position), message)
+            }
+            Problem::Schema { path, message } => write!(f, "{}: {}", show_path(path), message),
+            Problem::Digest { path } => write!(
+                f,
+                "{}: directory digest does not match its entries",
+                show_path(path)
+            ),
+            Problem::Block { block, problem } => write!(f, "block {}: {}", block, problem),
+        }
+    }
+}
+
+fn show_path(path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        "top-level directory".to_owned()
+    } else {
+        path.display().to_string()
+    }
+}
+
+#[derive(Debug)]
+pub struct SelfCheckReport {
+    pub encoding: Option<Encoding>, // None if not even the encoding could be told
+    pub partial: bool,              // Is the database marked as recovered?
+    pub problems: Vec<Problem>,
+}
+
+impl SelfCheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for SelfCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.encoding {
+            Some(encoding) => writeln!(f, "encoding: {}", encoding)?,
+            None => writeln!(f, "encoding: unknown")?,
+        }
+        if self.partial {
+            writeln!(f, "partial: recovered from a damaged database")?;
+        }
+        if self.is_ok() {
+            return writeln!(f, "no problems found");
+        }
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Database {
+    // Checks the database as thoroughly as possible, reporting every
+    // problem found. Only I/O errors are returned as errors. The whole
+    // database is held in memory while checking.
+    pub fn self_check(
+        mut r: impl Read,
+        key: Option<&MacKey>,
+    ) -> Result<SelfCheckReport, error::Error> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+
+        let mut problems = Vec::new();
+        if bytes.starts_with(indexed::INDEX_MAGIC) {
+            let partial = indexed::self_check(&bytes, key, &mut problems);
+            return Ok(SelfCheckReport {
+                encoding: Some(Encoding::Indexed),
+                partial,
+                problems,
+            });
+        }
+
+        let data = decompress(&bytes, &mut problems);
+        let encoding = match data.first() {
+            None => {
+                problems.push(Problem::Checksum {
+                    position: None,
+                    message: "database is empty".to_owned(),
+                });
+                None
+            }
+            Some(&b) if b == CBOR_MAGIC[0] => Some(Encoding::Cbor),
+            Some(_) => Some(Encoding::Json),
+        };
+        let partial = match encoding {
+            Some(Encoding::Json) => check_json(&data, key, &mut problems),
+            Some(Encoding::Cbor) => check_cbor(&data, key, &mut problems),
+            _ => false,
+        };
+        Ok(SelfCheckReport {
+            encoding,
+            partial,
+            problems,
+        })
+    }
+}
+
+// Decompresses as much as possible, noting where the stream broke
+pub(super) fn decompress(bytes: &[u8], problems: &mut Vec<Problem>) -> Vec<u8> {
+    let mut d = GzDecoder::new(bytes);
+    let mut data = Vec::new();
+    let mut buf = [0; 8192];
+    loop {
+        match d.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                problems.push(Problem::Gzip {
+                    offset: data.len() as u64,
+                    message: err.to_string(),
+                });
+                return data;
+            }
+        }
+    }
+    let rest = d.into_inner();
+    if !rest.is_empty() {
+        problems.push(Problem::TrailingData {
+            bytes: rest.len() as u64,
+        });
+    }
+    data
+}
+
+// Strips the position that serde_json appends to its messages
+pub(super) fn json_error(
+    err: &serde_json::Error,
+    line_offset: usize,
+) -> (Option<Position>, String) {
+    let message = err.to_string();
+    let suffix = format!(" at line {} column {}", err.line(), err.column());
+    let message = message.strip_suffix(&suffix).unwrap_or(&message).to_owned();
+    if err.line() == 0 {
+        return (None, message);
+    }
+    let position = Position::Line {
+        line: err.line() + line_offset,
+        column: err.column(),
+    };
+    (Some(position), message)
+}
+
+pub(super) fn cbor_error(err: &CborError, offset: u64) -> (Option<Position>, String) {
+    let at = |at: usize| Position::Offset(offset + at as u64);
+    match err {
+        ciborium::de::Error::Io(err) => (None, err.to_string()),
+        ciborium::de::Error::Syntax(offset) => (Some(at(*offset)), "invalid CBOR".to_owned()),
+        ciborium::de::Error::Semantic(offset, message) => (offset.map(at), message.clone()),
+        ciborium::de::Error::RecursionLimitExceeded => (None, "nested too deeply".to_owned()),
+    }
+}
+
+// Returns whether the database is marked as partial
+fn check_json(data: &[u8], key: Option<&MacKey>, problems: &mut Vec<Problem>) -> bool {
+    let (header, body) = match data.iter().position(|&b| b == SEP) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => {
+            problems.push(Problem::Checksum {
+                position: None,
+                message: "no newline after the checksum".to_owned(),
+            });
+            return false;
+        }
+    };
+
+    let expected = match serde_json::from_slice::<Raw>(header) {
+        Ok(raw) => {
+            schema_problems(PathBuf::new(), check_checksum_fields(&raw), problems);
+            serde_json::from_slice::<DatabaseChecksum>(header).map_err(|err| json_error(&err, 0))
+        }
+        Err(err) => Err(json_error(&err, 0)),
+    };
+    let partial = match expected {
+        Ok(expected) => {
+            check_checksum(&expected, body, key, problems);
+            expected.partial
+        }
+        Err((position, message)) => {
+            problems.push(Problem::Checksum { position, message });
+            false
+        }
+    };
+
+    // The body starts on the second line
+    match serde_json::from_slice::<Raw>(body) {
+        Ok(raw) => {
+            let before = problems.len();
+            check_entries(&raw, problems);
+            if problems.len() == before {
+                match serde_json::from_slice::<Database>(body) {
+                    Ok(database) => check_digests(&database, problems),
+                    Err(err) => {
+                        let (position, message) = json_error(&err, 1);
+                        problems.push(Problem::Parse { position, message })
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            let (position, message) = json_error(&err, 1);
+            problems.push(Problem::Parse { position, message })
+        }
+    }
+    partial
+}
+
+fn check_cbor(data: &[u8], key: Option<&MacKey>, problems: &mut Vec<Problem>) -> bool {
+    if !data.starts_with(CBOR_MAGIC) {
+        problems.push(Problem::Checksum {
+            position: Some(Position::Offset(0)),
+            message: "incomplete CBOR magic".to_owned(),
+        });
+        return false;
+    }
+    let start = CBOR_MAGIC.len();
+
+    // The checksum is a single CBOR item; whatever follows it is the
+    // database
+    let (header, header_len) = match cbor::from_slice_prefix::<Raw>(&data[start..]) {
+        Ok(header) => header,
+        Err(err) => {
+            let (position, message) = cbor_error(&err, start as u64);
+            problems.push(Problem::Checksum { position, message });
+            return false;
+        }
+    };
+    let (header_bytes, body) = data[start..].split_at(header_len);
+    let body_start = (start + header_len) as u64;
+
+    schema_problems(PathBuf::new(), check_checksum_fields(&header), problems);
+    let partial = match cbor::from_slice::<DatabaseChecksum>(header_bytes) {
+        Ok(expected) => {
+            check_checksum(&expected, body, key, problems);
+            expected.partial
+        }
+        Err(err) => {
+            let (position, message) = cbor_error(&err, start as u64);
+            problems.push(Problem::Checksum { position, message });
+            false
+        }
+    };
+
+    match cbor::from_slice::<Raw>(body) {
+        Ok(raw) => {
+            let before = problems.len();
+            check_entries(&raw, problems);
+            if problems.len() == before {
+                match cbor::from_slice::<Database>(body) {
+                    Ok(database) => check_digests(&database, problems),
+                    Err(err) => {
+                        let (position, message) = cbor_error(&err, body_start);
+                        problems.push(Problem::Parse { position, message })
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            let (position, message) = cbor_error(&err, body_start);
+            problems.push(Problem::Parse { position, message })
+        }
+    }
+    partial
+}
+
+// Compares the stored checksum against the data it covers, algorithm
+// by algorithm
+pub(super) fn check_checksum(
+    expected: &DatabaseChecksum,
+    data: &[u8],
+    key: Option<&MacKey>,
+    problems: &mut Vec<Problem>,
+) {
+    let actual = Database::checksum(data, Features::infer_from_database_checksum(expected));
+    let hashes = [
+        ("sha2-512/256", &expected.sha2, &actual.sha2),
+        ("blake2b", &expected.blake2b, &actual.blake2b),
+    ];
+    for (algorithm, expected, actual) in hashes.iter() {
+        if let (Some(expected), Some(actual)) = (expected, actual) {
+            if expected != actual {
+                problems.push(Problem::HashMismatch {
+                    algorithm,
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                });
+            }
+        }
+    }
+    if expected.size != actual.size {
+        problems.push(Problem::SizeMismatch {
+            expected: expected.size,
+            actual: actual.size,
+        });
+    }
+
+    match expected.mac_engine(key) {
+        Ok(Some(mut engine)) => {
+            engine.input(data);
+            if expected.verify_mac(Some(engine)).is_err() {
+                problems.push(Problem::MacMismatch);
+            }
+        }
+        Ok(None) => {}
+        Err(error::Error::MissingMac) => problems.push(Problem::MissingMac),
+        // Unknown algorithms are reported by the schema check
+        Err(_) => {}
+    }
+}
+
+// Reports the directories whose stored digest differs from the one
+// computed from their entries. Only the deepest such directory on each
+// path is reported, since a change also alters every parent digest.
+pub(super) fn check_digests(database: &Database, problems: &mut Vec<Problem>) {
+    fn check(stored: &Directory, computed: &Directory, path: &mut PathBuf) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for (name, entry) in stored.entries.iter() {
+            if let (Entry::Directory(stored), Some(Entry::Directory(computed))) =
+                (entry, computed.entries.get(name))
+            {
+                path.push(name);
+                problems.extend(check(stored, computed, path));
+                path.pop();
+            }
+        }
+        if problems.is_empty() && stored.digest.is_some() && stored.digest != computed.digest {
+            problems.push(Problem::Digest {
+                path: path.to_owned(),
+            });
+        }
+        problems
+    }
+
+    if let Entry::Directory(stored) = &database.0 {
+        let mut computed = stored.clone();
+        computed.update_digest();
+        problems.extend(check(stored, &computed, &mut PathBuf::new()));
+    }
+}
+
+// A generic decoded value, which unlike serde_json::Value keeps
+// duplicate keys so that they can be reported. Only what the schema
+// constrains is kept.
+pub(super) enum Raw {
+    Null,
+    Bool,
+    Unsigned(u64),
+    Signed,
+    Float,
+    Str(String),
+    Bytes(Vec<u8>),
+    Seq(Vec<Raw>),
+    Map(Vec<(String, Raw)>),
+}
+
+impl Raw {
+    pub(super) fn kind(&self) -> &'static str {
+        match self {
+            Raw::Null => "null",
+            Raw::Bool => "a boolean",
+            Raw::Unsigned(_) | Raw::Signed => "an integer",
+            Raw::Float => "a number",
+            Raw::Str(_) => "a string",
+            Raw::Bytes(_) => "a byte string",
+            Raw::Seq(_) => "an array",
+            Raw::Map(_) => "an object",
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Raw {
+    fn deserialize<D>(deserializer: D) -> Result<Raw, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RawVisitor;
+
+        impl<'de> Visitor<'de> for RawVisitor {
+            type Value = Raw;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("any value")
+            }
+
+            fn visit_unit<E>(self) -> Result<Raw, E> {
+                Ok(Raw::Null)
+            }
+
+            fn visit_none<E>(self) -> Result<Raw, E> {
+                Ok(Raw::Null)
+            }
+
+            fn visit_bool<E>(self, _: bool) -> Result<Raw, E> {
+                Ok(Raw::Bool)
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Raw, E> {
+                Ok(Raw::Unsigned(v))
+            }
+
+            fn visit_i64<E>(self, _: i64) -> Result<Raw, E> {
+                Ok(Raw::Signed)
+            }
+
+            fn visit_f64<E>(self, _: f64) -> Result<Raw, E> {
+                Ok(Raw::Float)
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Raw, E> {
+                Ok(Raw::Str(v.to_owned()))
+            }
+
+            fn visit_string<E>(self, v: String) -> Result<Raw, E> {
+                Ok(Raw::Str(v))
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Raw, E> {
+                Ok(Raw::Bytes(v.to_owned()))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Raw, E> {
+                Ok(Raw::Bytes(v))
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Raw, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(Raw::Seq(items))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Raw, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Raw::Map(entries))
+            }
+        }
+
+        deserializer.deserialize_any(RawVisitor)
+    }
+}
+
+pub(super) fn schema_problems(path: PathBuf, messages: Vec<String>, problems: &mut Vec<Problem>) {
+    problems.extend(messages.into_iter().map(|message| Problem::Schema {
+        path: path.clone(),
+        message,
+    }));
+}
+
+fn schema_found(found: Vec<(PathBuf, String)>, problems: &mut Vec<Problem>) {
+    problems.extend(
+        found
+            .into_iter()
+            .map(|(path, message)| Problem::Schema { path, message }),
+    );
+}
+
+// Checks every entry against schema/database.json
+fn check_entries(raw: &Raw, problems: &mut Vec<Problem>) {
+    schema_found(schema::database().check("", raw, PathBuf::new()), problems);
+}
+
+pub(super) fn check_metrics(raw: &Raw) -> Vec<String> {
+    let found = schema::database().check(schema::METRICS, raw, PathBuf::new());
+    found.into_iter().map(|(_, message)| message).collect()
+}
+
+// Checks a stored checksum against schema/checksum.json
+pub(super) fn check_checksum_fields(raw: &Raw) -> Vec<String> {
+    let found = schema::checksum().check("", raw, PathBuf::new());
+    found
+        .into_iter()
+        .map(|(_, message)| format!("checksum: {}", message))
+        .collect()
+}
@@ -8,8 +8,25 @@ pub enum Error {
     StripPrefix(std::path::StripPrefixError),
     Ignore(ignore::Error),
     Json(serde_json::Error),
+    CborDecode(ciborium::de::Error<std::io::Error>),
+    CborEncode(ciborium::ser::Error<std::io::Error>),
+    ReedSolomon(reed_solomon_erasure::Error),
+    Zip(zip::result::ZipError),
     ChecksumMismatch,
     ParseError,
+    InvalidKey,
+    MissingSignature,
+    SignatureMismatch,
+    MissingMac,
+    MacMismatch,
+    DamagedParity,
+    Unrepairable,
+    MissingHash,
+    InvalidChecksumList(usize),                 // Line number
+    InvalidArchive(std::path::PathBuf),         // Entry that can't be placed in a database
+    UnsupportedCompression(std::path::PathBuf), // Entry compressed with an unknown method
+    CrcMismatch(std::path::PathBuf),            // Entry whose contents don't match its stored CRC32
+    NotInDatabase(std::path::PathBuf),
 }
 
 impl From<std::io::Error> for Error {
@@ -35,3 +52,27 @@ impl From<serde_json::Error> for Error {
         Error::Json(err)
     }
 }
+
+impl From<ciborium::de::Error<std::io::Error>> for Error {
+    fn from(err: ciborium::de::Error<std::io::Error>) -> Error {
+        Error::CborDecode(err)
+    }
+}
+
+impl From<ciborium::ser::Error<std::io::Error>> for Error {
+    fn from(err: ciborium::ser::Error<std::io::Error>) -> Error {
+        Error::CborEncode(err)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(err: zip::result::ZipError) -> Error {
+        Error::Zip(err)
+    }
+}
+
+impl From<reed_solomon_erasure::Error> for Error {
+    fn from(err: reed_solomon_erasure::Error) -> Error {
+        Error::ReedSolomon(err)
+    }
+}
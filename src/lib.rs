@@ -4,3 +4,8 @@ extern crate serde_derive;
 mod base64;
 pub mod database;
 pub mod error;
+pub mod mac;
+mod magic;
+pub mod parity;
+pub mod signature;
+mod sparse;
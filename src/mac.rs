@@ -0,0 +1,112 @@
+// Keyed MACs over the encoded database, so that only holders of the
+// secret key can produce a valid database checksum
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use blake2::Blake2bMac;
+use digest::consts::U32;
+use hmac::{Hmac, Mac};
+use sha2::Sha512_256;
+
+use crate::error;
+
+// Names of the MAC algorithms, as stored in the checksum
+pub const HMAC_SHA2: &str = "hmac-sha2-512/256";
+pub const BLAKE2B: &str = "blake2b-keyed";
+
+// Environment variable consulted when no key file is given
+pub const KEY_ENV: &str = "ICK_MAC_KEY";
+
+// Keys may be at most as long as BLAKE2b permits, and shouldn't be so
+// short as to be guessable
+const KEY_MIN_LEN: usize = 16;
+const KEY_MAX_LEN: usize = 64;
+
+pub struct MacKey(Vec<u8>);
+
+impl MacKey {
+    pub fn new(key: Vec<u8>) -> Result<MacKey, error::Error> {
+        if key.len() < KEY_MIN_LEN || key.len() > KEY_MAX_LEN {
+            return Err(error::Error::InvalidKey);
+        }
+        Ok(MacKey(key))
+    }
+
+    // Reads a key file, ignoring a final newline
+    pub fn load(path: impl AsRef<Path>) -> Result<MacKey, error::Error> {
+        let mut key = Vec::new();
+        File::open(path)?.read_to_end(&mut key)?;
+        if key.ends_with(b"\n") {
+            key.pop();
+            if key.ends_with(b"\r") {
+                key.pop();
+            }
+        }
+        MacKey::new(key)
+    }
+
+    pub fn from_env() -> Result<Option<MacKey>, error::Error> {
+        match env::var_os(KEY_ENV) {
+            Some(key) => Ok(Some(MacKey::new(
+                key.into_string()
+                    .map_err(|_| error::Error::InvalidKey)?
+                    .into_bytes(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+}
+
+type HmacSha2 = Hmac<Sha512_256>;
+type Blake2bMac32 = Blake2bMac<U32>;
+
+pub(crate) enum EngineMac {
+    HmacSha2(Box<HmacSha2>),
+    Blake2b(Box<Blake2bMac32>),
+}
+
+impl EngineMac {
+    pub(crate) fn new(algorithm: &str, key: &MacKey) -> Result<EngineMac, error::Error> {
+        match algorithm {
+            HMAC_SHA2 => Ok(EngineMac::HmacSha2(Box::new(
+                HmacSha2::new_from_slice(&key.0).map_err(|_| error::Error::InvalidKey)?,
+            ))),
+            BLAKE2B => Ok(EngineMac::Blake2b(Box::new(
+                Blake2bMac32::new_from_slice(&key.0).map_err(|_| error::Error::InvalidKey)?,
+            ))),
+            _ => Err(error::Error::ParseError),
+        }
+    }
+
+    pub(crate) fn algorithm(&self) -> &'static str {
+        match self {
+            EngineMac::HmacSha2(_) => HMAC_SHA2,
+            EngineMac::Blake2b(_) => BLAKE2B,
+        }
+    }
+
+    pub(crate) fn input(&mut self, input: &[u8]) {
+        match self {
+            EngineMac::HmacSha2(mac) => mac.update(input),
+            EngineMac::Blake2b(mac) => mac.update(input),
+        }
+    }
+
+    pub(crate) fn result(self) -> Vec<u8> {
+        match self {
+            EngineMac::HmacSha2(mac) => mac.finalize().into_bytes().to_vec(),
+            EngineMac::Blake2b(mac) => mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    // Compares in constant time
+    pub(crate) fn verify(self, tag: &[u8]) -> bool {
+        match self {
+            EngineMac::HmacSha2(mac) => mac.verify_slice(tag).is_ok(),
+            EngineMac::Blake2b(mac) => mac.verify_slice(tag).is_ok(),
+        }
+    }
+}
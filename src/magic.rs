@@ -0,0 +1,152 @@
+// File format sniffing based on well-known magic numbers
+
+use std::ffi::OsStr;
+
+// Number of leading bytes needed to recognize any format below
+pub const HEAD_LEN: usize = 512;
+
+// Format names used when no magic number matches
+pub const EMPTY: &str = "empty";
+pub const ZEROS: &str = "zeros";
+pub const DATA: &str = "data";
+pub const HTML: &str = "html";
+pub const XML: &str = "xml";
+
+// (format, offset, magic)
+const MAGIC: &[(&str, usize, &[u8])] = &[
+    ("pdf", 0, b"%PDF-"),
+    ("zip", 0, b"PK\x03\x04"),
+    ("zip", 0, b"PK\x05\x06"),
+    ("png", 0, b"\x89PNG\r\n\x1a\n"),
+    ("jpeg", 0, b"\xff\xd8\xff"),
+    ("gif", 0, b"GIF87a"),
+    ("gif", 0, b"GIF89a"),
+    ("tiff", 0, b"II*\x00"),
+    ("tiff", 0, b"MM\x00*"),
+    ("psd", 0, b"8BPS"),
+    ("gzip", 0, b"\x1f\x8b\x08"),
+    ("bzip2", 0, b"BZh"),
+    ("xz", 0, b"\xfd7zXZ\x00"),
+    ("zstd", 0, b"\x28\xb5\x2f\xfd"),
+    ("7z", 0, b"7z\xbc\xaf\x27\x1c"),
+    ("rar", 0, b"Rar!\x1a\x07"),
+    ("tar", 257, b"ustar"),
+    ("elf", 0, b"\x7fELF"),
+    ("wasm", 0, b"\x00asm"),
+    ("sqlite", 0, b"SQLite format 3\x00"),
+    ("ogg", 0, b"OggS"),
+    ("flac", 0, b"fLaC"),
+    ("mp3", 0, b"ID3"),
+    ("iso-media", 4, b"ftyp"),
+];
+
+// "MZ" alone is too weak a signature, so PE files are only recognized
+// by the "PE\0\0" signature at the offset stored at 0x3c
+const PE: &str = "pe";
+
+// RIFF containers carry their actual type at offset 8
+const RIFF: &[(&str, &[u8])] = &[("webp", b"WEBP"), ("wav", b"WAVE"), ("avi", b"AVI ")];
+
+// Binary formats implied by common file extensions
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("pdf", "pdf"),
+    ("zip", "zip"),
+    ("jar", "zip"),
+    ("apk", "zip"),
+    ("epub", "zip"),
+    ("docx", "zip"),
+    ("xlsx", "zip"),
+    ("pptx", "zip"),
+    ("odt", "zip"),
+    ("ods", "zip"),
+    ("odp", "zip"),
+    ("png", "png"),
+    ("jpg", "jpeg"),
+    ("jpeg", "jpeg"),
+    ("gif", "gif"),
+    ("tif", "tiff"),
+    ("tiff", "tiff"),
+    ("psd", "psd"),
+    ("webp", "webp"),
+    ("wav", "wav"),
+    ("avi", "avi"),
+    ("gz", "gzip"),
+    ("tgz", "gzip"),
+    ("bz2", "bzip2"),
+    ("xz", "xz"),
+    ("zst", "zstd"),
+    ("7z", "7z"),
+    ("rar", "rar"),
+    ("tar", "tar"),
+    ("exe", "pe"),
+    ("dll", "pe"),
+    ("wasm", "wasm"),
+    ("sqlite", "sqlite"),
+    ("ogg", "ogg"),
+    ("flac", "flac"),
+    ("mp4", "iso-media"),
+    ("m4a", "iso-media"),
+];
+
+pub fn sniff(head: &[u8]) -> &'static str {
+    if head.is_empty() {
+        return EMPTY;
+    }
+    if head.iter().all(|&b| b == 0) {
+        return ZEROS;
+    }
+    if head.len() >= 12 && &head[0..4] == b"RIFF" {
+        for (format, kind) in RIFF {
+            if &head[8..12] == *kind {
+                return format;
+            }
+        }
+    }
+    if is_pe(head) {
+        return PE;
+    }
+    for (format, offset, magic) in MAGIC {
+        if head.len() >= offset + magic.len() && &head[*offset..offset + magic.len()] == *magic {
+            return format;
+        }
+    }
+    sniff_markup(head).unwrap_or(DATA)
+}
+
+fn is_pe(head: &[u8]) -> bool {
+    if head.len() < 0x40 || &head[0..2] != b"MZ" {
+        return false;
+    }
+    let mut offset = [0; 4];
+    offset.copy_from_slice(&head[0x3c..0x40]);
+    let offset = u32::from_le_bytes(offset) as usize;
+    head.get(offset..offset.saturating_add(4)) == Some(b"PE\0\0")
+}
+
+fn sniff_markup(head: &[u8]) -> Option<&'static str> {
+    let start = head.iter().position(|b| !b.is_ascii_whitespace())?;
+    let text = String::from_utf8_lossy(&head[start..]).to_ascii_lowercase();
+    if text.starts_with("<!doctype html") || text.starts_with("<html") {
+        Some(HTML)
+    } else if text.starts_with("<?xml") {
+        Some(XML)
+    } else {
+        None
+    }
+}
+
+// Is this format identified by a magic number (as opposed to a
+// fallback or a textual heuristic)?
+pub fn is_magic(format: &str) -> bool {
+    format == PE
+        || MAGIC.iter().any(|(f, _, _)| *f == format)
+        || RIFF.iter().any(|(f, _)| *f == format)
+}
+
+pub fn expected_for_extension(extension: &OsStr) -> Option<&'static str> {
+    let extension = extension.to_str()?.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(e, _)| *e == extension)
+        .map(|(_, format)| *format)
+}
@@ -2,10 +2,18 @@
 extern crate clap;
 
 use std::ffi::OsString;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 
-use integrity_checker::database::{Database, DiffSummary, Features};
+use integrity_checker::database::{
+    ArchiveFormat, CopyFinder, Database, DiffSummary, DirectoryDigest, DuplicateFinder, Encoding,
+    EntryVisitor, Features, Importer, ListFormat, Metrics,
+};
 use integrity_checker::error;
+use integrity_checker::mac::MacKey;
+use integrity_checker::parity::{parity_path, update_parity, Parity};
+use integrity_checker::signature::{signature_path, PublicKey, SecretKey, Signature};
 
 enum Action {
     Build {
@@ -14,37 +22,219 @@ enum Action {
         features: Features,
         threads: usize,
         force: bool,
+        encoding: Encoding,
+        signing_key: Option<OsString>,
+        mac_key: Option<OsString>,
+        parity: bool,
+        ignore_crc: bool,
     },
     Check {
         db_path: OsString,
         dir_path: OsString,
         features: Features,
         threads: usize,
+        verbosity: u64,
+        trusted_key: Option<OsString>,
+        mac_key: Option<OsString>,
     },
     Diff {
         old_path: OsString,
         new_path: OsString,
+        verbosity: u64,
+        trusted_key: Option<OsString>,
+        mac_key: Option<OsString>,
+    },
+    Compare {
+        old_path: OsString,
+        new_path: OsString,
+        features: Features,
+        threads: usize,
+        stream: bool,
+        verbosity: u64,
     },
     SelfCheck {
         db_path: OsString,
+        mac_key: Option<OsString>,
+        repair: bool,
+    },
+    Digest {
+        db_path: OsString,
+        mac_key: Option<OsString>,
+    },
+    Keygen {
+        key_path: OsString,
+        force: bool,
+    },
+    VerifySignature {
+        db_path: OsString,
+        key_path: OsString,
+        mac_key: Option<OsString>,
+    },
+    VerifyFile {
+        db_path: OsString,
+        file_path: OsString,
+        root_path: OsString,
+        verbosity: u64,
+        mac_key: Option<OsString>,
+    },
+    Convert {
+        input_path: OsString,
+        output_path: OsString,
+        encoding: Option<Encoding>,
+        force: bool,
+        mac_key: Option<OsString>,
+        parity: bool,
+    },
+    Export {
+        db_path: OsString,
+        format: ListFormat,
+        trusted_key: Option<OsString>,
+        mac_key: Option<OsString>,
+    },
+    Duplicates {
+        db_paths: Vec<OsString>,
+        json: bool,
+        trusted_key: Option<OsString>,
+        mac_key: Option<OsString>,
+    },
+    FindGood {
+        db_path: OsString,
+        search_paths: Vec<OsString>,
+        root_path: OsString,
+        paths: Vec<OsString>,
+        threads: usize,
+        script: bool,
+        trusted_key: Option<OsString>,
+        mac_key: Option<OsString>,
+    },
+    Import {
+        db_path: OsString,
+        list_paths: Vec<OsString>,
+        list_format: Option<ListFormat>,
+        root_path: OsString,
+        force: bool,
+        mac_key: Option<OsString>,
+        parity: bool,
+    },
+    Recover {
+        input_path: OsString,
+        output_path: OsString,
+        encoding: Option<Encoding>,
+        force: bool,
+        mac_key: Option<OsString>,
+        parity: bool,
     },
 }
 
 #[derive(Debug)]
 enum ActionSummary {
     Built,
+    Shown,
+    Converted,
+    Damaged,
     Diff(DiffSummary),
 }
 
+// Remembers the digest of the top-level directory
+struct RootDigest(Option<DirectoryDigest>);
+
+impl EntryVisitor for RootDigest {
+    fn visit_digest(&mut self, path: &Path, digest: &DirectoryDigest) {
+        if path.as_os_str().is_empty() {
+            self.0 = Some(digest.clone());
+        }
+    }
+
+    fn visit_file(&mut self, _path: &Path, _metrics: &Metrics) {}
+}
+
 fn validate_usize(s: &str) -> Result<(), String> {
     s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())
 }
 
+// Parses a size in bytes, with an optional K, M or G (binary) suffix
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, scale) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let size = digits
+        .parse::<u64>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(scale)
+        .ok_or_else(|| "size is too large".to_owned())?;
+    if size == 0 {
+        return Err("size must be positive".to_owned());
+    }
+    Ok(size)
+}
+
+fn validate_size(s: &str) -> Result<(), String> {
+    parse_size(s).map(|_| ())
+}
+
 trait DefaultFlags {
     fn add_default_flags(self) -> Self;
+    fn add_verbose_flag(self) -> Self;
+    fn add_format_flag(self) -> Self;
+    fn add_signature_flag(self) -> Self;
+    fn add_mac_key_flag(self) -> Self;
+    fn add_parity_flag(self) -> Self;
 }
 
 impl<'a> DefaultFlags for clap::App<'a> {
+    fn add_verbose_flag(self) -> Self {
+        self.arg(
+            clap::Arg::with_name("verbose")
+                .help("Show old and new metrics of changed files (-vv: also list unchanged files)")
+                .short('v')
+                .long("verbose")
+                .multiple_occurrences(true),
+        )
+    }
+
+    fn add_signature_flag(self) -> Self {
+        self.arg(
+            clap::Arg::with_name("require-signature")
+                .value_parser(value_parser!(OsString))
+                .help("Refuse databases without a valid signature by this public key")
+                .long("require-signature")
+                .value_name("PUBLIC_KEY")
+                .takes_value(true),
+        )
+    }
+
+    fn add_mac_key_flag(self) -> Self {
+        self.arg(
+            clap::Arg::with_name("mac-key")
+                .value_parser(value_parser!(OsString))
+                .help("Authenticate the database checksum with the key in this file (default: $ICK_MAC_KEY)")
+                .long("mac-key")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+    }
+
+    fn add_parity_flag(self) -> Self {
+        self.arg(
+            clap::Arg::with_name("parity")
+                .help("Also write parity data (to <database>.parity) for repairing damage later")
+                .long("parity"),
+        )
+    }
+
+    fn add_format_flag(self) -> Self {
+        self.arg(
+            clap::Arg::with_name("format")
+                .help("Encoding of the integrity database to write")
+                .long("format")
+                .takes_value(true)
+                .possible_values(["json", "cbor", "indexed"]),
+        )
+    }
+
     fn add_default_flags(self) -> Self {
         self.arg(
             clap::Arg::with_name("threads")
@@ -78,6 +268,25 @@ impl<'a> DefaultFlags for clap::App<'a> {
                 .long("no-blake2")
                 .overrides_with("blake2"),
         )
+        .arg(
+            clap::Arg::with_name("sha256")
+                .help("Enable use of SHA-256 algorithm (as used by sha256sum)")
+                .long("sha256")
+                .overrides_with("no-sha256"),
+        )
+        .arg(
+            clap::Arg::with_name("no-sha256")
+                .help("Disable use of SHA-256 algorithm")
+                .long("no-sha256")
+                .overrides_with("sha256"),
+        )
+        .arg(
+            clap::Arg::with_name("block-size")
+                .help("Also hash each block of this size (e.g. 1M) to locate changes within files")
+                .long("block-size")
+                .takes_value(true)
+                .validator(validate_size),
+        )
     }
 }
 
@@ -100,7 +309,67 @@ fn parse_features(matches: &clap::ArgMatches) -> Features {
         defaults.blake2b
     };
 
-    Features { sha2, blake2b }
+    let sha256 = if matches.is_present("sha256") {
+        true
+    } else if matches.is_present("no-sha256") {
+        false
+    } else {
+        defaults.sha256
+    };
+
+    let blocks = matches
+        .value_of("block-size")
+        .map(|size| parse_size(size).unwrap());
+
+    Features {
+        sha2,
+        blake2b,
+        sha256,
+        blocks,
+    }
+}
+
+fn parse_encoding(matches: &clap::ArgMatches) -> Option<Encoding> {
+    match matches.value_of("format") {
+        Some("json") => Some(Encoding::Json),
+        Some("cbor") => Some(Encoding::Cbor),
+        Some("indexed") => Some(Encoding::Indexed),
+        _ => None,
+    }
+}
+
+fn parse_list_format(name: &str) -> ListFormat {
+    match name {
+        "sha256sum" => ListFormat::Sha256sum,
+        "b2sum" => ListFormat::B2sum,
+        "md5sum" => ListFormat::Md5sum,
+        "sha1sum" => ListFormat::Sha1sum,
+        "sfv" => ListFormat::Sfv,
+        "bsd" => ListFormat::Bsd,
+        "mtree" => ListFormat::Mtree,
+        _ => unreachable!(),
+    }
+}
+
+const LIST_FORMATS: [&str; 7] = [
+    "sha256sum",
+    "b2sum",
+    "md5sum",
+    "sha1sum",
+    "sfv",
+    "bsd",
+    "mtree",
+];
+
+// Archives are scanned as if they had been extracted, rather than as
+// single files
+fn archive_format(path: &OsString) -> Option<ArchiveFormat> {
+    let path = Path::new(path);
+    if path.is_file() {
+        ArchiveFormat::infer_from_path(path)
+    } else {
+        None
+    }
 }
 
 fn parse_threads(matches: &clap::ArgMatches) -> usize {
@@ -127,7 +396,7 @@ fn parse_args() -> Action {
                 .arg(
                     clap::Arg::with_name("path")
                         .value_parser(value_parser!(OsString))
-                        .help("Path of file or directory to scan")
+                        .help("Path of file, directory or archive (.tar, .tar.gz, .tar.xz, .tar.zst or .zip) to scan")
                         .required(true)
                         .index(2),
                 )
@@ -137,7 +406,23 @@ fn parse_args() -> Action {
                         .short('f')
                         .long("force"),
                 )
-                .add_default_flags(),
+                .arg(
+                    clap::Arg::with_name("sign")
+                        .value_parser(value_parser!(OsString))
+                        .help("Sign the database with this secret key (see keygen)")
+                        .long("sign")
+                        .value_name("SECRET_KEY")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("ignore-crc")
+                        .help("Build from a zip archive even if members don't match their stored CRC32")
+                        .long("ignore-crc"),
+                )
+                .add_default_flags()
+                .add_format_flag()
+                .add_mac_key_flag()
+                .add_parity_flag(),
         )
         .subcommand(
             clap::SubCommand::with_name("check")
@@ -152,11 +437,14 @@ fn parse_args() -> Action {
                 .arg(
                     clap::Arg::with_name("path")
                         .value_parser(value_parser!(OsString))
-                        .help("Path of file or directory to scan")
+                        .help("Path of file, directory or archive (.tar, .tar.gz, .tar.xz, .tar.zst or .zip) to scan")
                         .required(true)
                         .index(2),
                 )
-                .add_default_flags(),
+                .add_default_flags()
+                .add_verbose_flag()
+                .add_signature_flag()
+                .add_mac_key_flag(),
         )
         .subcommand(
             clap::SubCommand::with_name("diff")
@@ -174,7 +462,35 @@ fn parse_args() -> Action {
                         .help("Path of new integrity database")
                         .required(true)
                         .index(2),
-                ),
+                )
+                .add_verbose_flag()
+                .add_signature_flag()
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("compare")
+                .about("Compare two directories, without a database for either")
+                .arg(
+                    clap::Arg::with_name("old")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of old directory")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("new")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of new directory")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    clap::Arg::with_name("stream")
+                        .help("Match files as they are found, keeping only the differences in memory")
+                        .long("stream"),
+                )
+                .add_default_flags()
+                .add_verbose_flag(),
         )
         .subcommand(
             clap::SubCommand::with_name("selfcheck")
@@ -185,6 +501,287 @@ fn parse_args() -> Action {
                         .help("Path of integrity database to read")
                         .required(true)
                         .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("repair")
+                        .help("Repair damage to the database with its parity data (see build --parity)")
+                        .long("repair"),
+                )
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("digest")
+                .about("Print the digest of the top-level directory of an integrity database")
+                .arg(
+                    clap::Arg::with_name("database")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database to read")
+                        .required(true)
+                        .index(1),
+                )
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("keygen")
+                .about("Generate a key pair for signing integrity databases")
+                .arg(
+                    clap::Arg::with_name("key")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of secret key to create (the public key gets a .pub suffix)")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("force")
+                        .help("Overwrite existing files")
+                        .short('f')
+                        .long("force"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("verify-signature")
+                .about("Check the signature of an integrity database")
+                .arg(
+                    clap::Arg::with_name("database")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database to read")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("key")
+                        .value_parser(value_parser!(OsString))
+                        .help("Public key the database must be signed with")
+                        .long("key")
+                        .value_name("PUBLIC_KEY")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("verify-file")
+                .about("Check a single file against an integrity database")
+                .arg(
+                    clap::Arg::with_name("database")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database to read")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("path")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of file to check, relative to the scanned directory")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    clap::Arg::with_name("root")
+                        .value_parser(value_parser!(OsString))
+                        .help("Directory the database was built from (default: current directory)")
+                        .long("root")
+                        .takes_value(true),
+                )
+                .add_verbose_flag()
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("convert")
+                .about("Re-encode an integrity database as JSON, CBOR or indexed")
+                .arg(
+                    clap::Arg::with_name("input")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database to read")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("output")
+                        .value_parser(value_parser!(OsString))
+                        .help(
+                            "Path of integrity database to create (default format: the other one)",
+                        )
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    clap::Arg::with_name("force")
+                        .help("Overwrite existing file")
+                        .short('f')
+                        .long("force"),
+                )
+                .add_format_flag()
+                .add_mac_key_flag()
+                .add_parity_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about("Write the hashes in an integrity database as a checksum list (to standard output)")
+                .arg(
+                    clap::Arg::with_name("database")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database to read")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("format")
+                        .help("Format of the list: sha256sum and b2sum need databases built with --sha256 or --blake2, respectively, md5sum, sha1sum and sfv imported ones, and mtree needs MD5, SHA-1 or SHA-256 (e.g. --sha256)")
+                        .long("format")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(LIST_FORMATS),
+                )
+                .add_signature_flag()
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("duplicates")
+                .about("List files with the same contents, in one integrity database or across several")
+                .arg(
+                    clap::Arg::with_name("databases")
+                        .value_parser(value_parser!(OsString))
+                        .help("Paths of integrity databases to read")
+                        .required(true)
+                        .multiple_values(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("format")
+                        .help("Format of the report")
+                        .long("format")
+                        .takes_value(true)
+                        .default_value("text")
+                        .possible_values(["text", "json"]),
+                )
+                .add_signature_flag()
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("find-good")
+                .about("Search other integrity databases or directories for good copies of damaged files")
+                .arg(
+                    clap::Arg::with_name("database")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database of the damaged files")
+                        .long("db")
+                        .value_name("DATABASE")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("search")
+                        .value_parser(value_parser!(OsString))
+                        .help("Paths of integrity databases or directories to search")
+                        .long("search")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("root")
+                        .value_parser(value_parser!(OsString))
+                        .help("Directory the database was built from (default: current directory)")
+                        .long("root")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("paths")
+                        .value_parser(value_parser!(OsString))
+                        .help("Paths of damaged files or directories, relative to the root (default: every file that no longer matches the database)")
+                        .multiple_values(true)
+                        .last(true),
+                )
+                .arg(
+                    clap::Arg::with_name("script")
+                        .help("Write a shell script that copies the good files into place")
+                        .long("script"),
+                )
+                .arg(
+                    clap::Arg::with_name("threads")
+                        .help("Number of threads to use")
+                        .short('j')
+                        .long("threads")
+                        .takes_value(true)
+                        .validator(validate_usize),
+                )
+                .add_signature_flag()
+                .add_mac_key_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("import")
+                .about("Create an integrity database from checksum lists (such as SHA256SUMS, .sfv or mtree files)")
+                .arg(
+                    clap::Arg::with_name("database")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database to create")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("lists")
+                        .value_parser(value_parser!(OsString))
+                        .help("Paths of checksum lists to read")
+                        .required(true)
+                        .multiple_values(true)
+                        .index(2),
+                )
+                .arg(
+                    clap::Arg::with_name("format")
+                        .help("Format of the lists (default: guessed from their names)")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(LIST_FORMATS),
+                )
+                .arg(
+                    clap::Arg::with_name("root")
+                        .value_parser(value_parser!(OsString))
+                        .help("Directory the database describes, containing the lists other than mtree specifications (default: current directory)")
+                        .long("root")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("force")
+                        .help("Overwrite existing file")
+                        .short('f')
+                        .long("force"),
+                )
+                .add_mac_key_flag()
+                .add_parity_flag(),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("recover")
+                .about("Salvage what can be decoded from a damaged integrity database")
+                .arg(
+                    clap::Arg::with_name("input")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of damaged integrity database to read")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("output")
+                        .value_parser(value_parser!(OsString))
+                        .help("Path of integrity database to create (default format: the same)")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    clap::Arg::with_name("force")
+                        .help("Overwrite existing file")
+                        .short('f')
+                        .long("force"),
+                )
+                .add_format_flag()
+                .add_mac_key_flag()
+                .add_parity_flag()
+                .after_help(
+                    "Decoding stops at the first damage that can't be parsed, and what \
+                     follows is lost. Damage that still parses (such as a flipped bit in \
+                     a hash) can't be told apart from the original, so unless a checksum \
+                     covering them is intact, the files kept are listed as unverified.",
                 ),
         )
         .after_help(
@@ -192,6 +789,8 @@ fn parse_args() -> Action {
                     \n    0       Success \
                     \n    1       Changes \
                     \n    2       Suspicious changes \
+                    \n    3       Mass modification (possible ransomware) \
+                    \n    4       Line endings converted, contents otherwise unchanged \
                     \n   -1       Error",
         )
         .get_matches();
@@ -205,6 +804,11 @@ fn parse_args() -> Action {
             features: parse_features(submatches),
             threads: parse_threads(submatches),
             force: submatches.is_present("force"),
+            encoding: parse_encoding(submatches).unwrap_or(Encoding::Json),
+            signing_key: submatches.get_one::<OsString>("sign").cloned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+            parity: submatches.is_present("parity"),
+            ignore_crc: submatches.is_present("ignore-crc"),
         },
         Some(("check", submatches)) => Action::Check {
             db_path: submatches
@@ -214,21 +818,188 @@ fn parse_args() -> Action {
             dir_path: submatches.get_one::<OsString>("path").unwrap().to_owned(),
             features: parse_features(submatches),
             threads: parse_threads(submatches),
+            verbosity: submatches.occurrences_of("verbose"),
+            trusted_key: submatches.get_one::<OsString>("require-signature").cloned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
         },
         Some(("diff", submatches)) => Action::Diff {
             old_path: submatches.get_one::<OsString>("old").unwrap().to_owned(),
             new_path: submatches.get_one::<OsString>("new").unwrap().to_owned(),
+            verbosity: submatches.occurrences_of("verbose"),
+            trusted_key: submatches.get_one::<OsString>("require-signature").cloned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+        },
+        Some(("compare", submatches)) => Action::Compare {
+            old_path: submatches.get_one::<OsString>("old").unwrap().to_owned(),
+            new_path: submatches.get_one::<OsString>("new").unwrap().to_owned(),
+            features: parse_features(submatches),
+            threads: parse_threads(submatches),
+            stream: submatches.is_present("stream"),
+            verbosity: submatches.occurrences_of("verbose"),
         },
         Some(("selfcheck", submatches)) => Action::SelfCheck {
             db_path: submatches
                 .get_one::<OsString>("database")
                 .unwrap()
                 .to_owned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+            repair: submatches.is_present("repair"),
+        },
+        Some(("digest", submatches)) => Action::Digest {
+            db_path: submatches
+                .get_one::<OsString>("database")
+                .unwrap()
+                .to_owned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+        },
+        Some(("keygen", submatches)) => Action::Keygen {
+            key_path: submatches.get_one::<OsString>("key").unwrap().to_owned(),
+            force: submatches.is_present("force"),
+        },
+        Some(("verify-signature", submatches)) => Action::VerifySignature {
+            db_path: submatches
+                .get_one::<OsString>("database")
+                .unwrap()
+                .to_owned(),
+            key_path: submatches.get_one::<OsString>("key").unwrap().to_owned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+        },
+        Some(("verify-file", submatches)) => Action::VerifyFile {
+            db_path: submatches
+                .get_one::<OsString>("database")
+                .unwrap()
+                .to_owned(),
+            file_path: submatches.get_one::<OsString>("path").unwrap().to_owned(),
+            root_path: submatches
+                .get_one::<OsString>("root")
+                .cloned()
+                .unwrap_or_else(|| OsString::from(".")),
+            verbosity: submatches.occurrences_of("verbose"),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+        },
+        Some(("convert", submatches)) => Action::Convert {
+            input_path: submatches.get_one::<OsString>("input").unwrap().to_owned(),
+            output_path: submatches.get_one::<OsString>("output").unwrap().to_owned(),
+            encoding: parse_encoding(submatches),
+            force: submatches.is_present("force"),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+            parity: submatches.is_present("parity"),
+        },
+        Some(("export", submatches)) => Action::Export {
+            db_path: submatches
+                .get_one::<OsString>("database")
+                .unwrap()
+                .to_owned(),
+            format: parse_list_format(submatches.value_of("format").unwrap()),
+            trusted_key: submatches.get_one::<OsString>("require-signature").cloned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+        },
+        Some(("duplicates", submatches)) => Action::Duplicates {
+            db_paths: submatches
+                .get_many::<OsString>("databases")
+                .unwrap()
+                .cloned()
+                .collect(),
+            json: submatches.value_of("format") == Some("json"),
+            trusted_key: submatches.get_one::<OsString>("require-signature").cloned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+        },
+        Some(("find-good", submatches)) => Action::FindGood {
+            db_path: submatches
+                .get_one::<OsString>("database")
+                .unwrap()
+                .to_owned(),
+            search_paths: submatches
+                .get_many::<OsString>("search")
+                .unwrap()
+                .cloned()
+                .collect(),
+            root_path: submatches
+                .get_one::<OsString>("root")
+                .cloned()
+                .unwrap_or_else(|| OsString::from(".")),
+            paths: submatches
+                .get_many::<OsString>("paths")
+                .map(|paths| paths.cloned().collect())
+                .unwrap_or_default(),
+            threads: parse_threads(submatches),
+            script: submatches.is_present("script"),
+            trusted_key: submatches.get_one::<OsString>("require-signature").cloned(),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+        },
+        Some(("import", submatches)) => Action::Import {
+            db_path: submatches
+                .get_one::<OsString>("database")
+                .unwrap()
+                .to_owned(),
+            list_paths: submatches
+                .get_many::<OsString>("lists")
+                .unwrap()
+                .cloned()
+                .collect(),
+            list_format: submatches.value_of("format").map(parse_list_format),
+            root_path: submatches
+                .get_one::<OsString>("root")
+                .cloned()
+                .unwrap_or_else(|| OsString::from(".")),
+            force: submatches.is_present("force"),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+            parity: submatches.is_present("parity"),
+        },
+        Some(("recover", submatches)) => Action::Recover {
+            input_path: submatches.get_one::<OsString>("input").unwrap().to_owned(),
+            output_path: submatches.get_one::<OsString>("output").unwrap().to_owned(),
+            encoding: parse_encoding(submatches),
+            force: submatches.is_present("force"),
+            mac_key: submatches.get_one::<OsString>("mac-key").cloned(),
+            parity: submatches.is_present("parity"),
         },
         _ => unreachable!(),
     }
 }
 
+// Loads a database, refusing it unless it carries a valid signature by
+// the trusted key (if any) and a valid MAC under the key (if any)
+fn load_database(
+    db_path: &OsString,
+    trusted_key: &Option<PublicKey>,
+    mac_key: &Option<MacKey>,
+) -> Result<Database, error::Error> {
+    let f = File::open(db_path)?;
+    let (database, checksum) = Database::load_with_checksum(f, mac_key.as_ref())?;
+    if checksum.is_partial() {
+        eprintln!(
+            "warning: {} was recovered from a damaged database and may lack some files",
+            Path::new(db_path).display()
+        );
+    }
+    if let Some(key) = trusted_key {
+        Signature::load(signature_path(db_path))?.verify(&checksum, key)?;
+    }
+    Ok(database)
+}
+
+fn load_trusted_key(key_path: Option<OsString>) -> Result<Option<PublicKey>, error::Error> {
+    key_path.map(PublicKey::load).transpose()
+}
+
+// Falls back to the key in the environment when no key file is given
+fn load_mac_key(key_path: Option<OsString>) -> Result<Option<MacKey>, error::Error> {
+    match key_path {
+        Some(path) => Ok(Some(MacKey::load(path)?)),
+        None => MacKey::from_env(),
+    }
+}
+
+// Replaces the contents of a file, without leaving it half-written
+fn replace(path: &OsString, contents: &[u8]) -> Result<(), error::Error> {
+    let mut temp_path = path.clone();
+    temp_path.push(".repaired");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
 fn driver() -> Result<ActionSummary, error::Error> {
     let action = parse_args();
     match action {
@@ -238,17 +1009,36 @@ fn driver() -> Result<ActionSummary, error::Error> {
             features,
             threads,
             force,
+            encoding,
+            signing_key,
+            mac_key,
+            parity,
+            ignore_crc,
         } => {
+            // Load the keys up front, rather than failing after the build
+            let signing_key = signing_key.map(SecretKey::load).transpose()?;
+            let mac_key = load_mac_key(mac_key)?;
+
             // Truncate only when force is set
             let f = OpenOptions::new()
                 .write(true)
                 .create(true)
                 .truncate(true)
                 .create_new(!force)
-                .open(db_path)?;
+                .open(&db_path)?;
 
-            let database = Database::build(dir_path, features, threads, true)?;
-            database.dump_json(f, features)?;
+            let database = match archive_format(&dir_path) {
+                Some(format) => {
+                    Database::build_archive(&dir_path, format, features, threads, ignore_crc, true)?
+                }
+                None => Database::build(&dir_path, features, threads, true)?,
+            };
+            let (_, checksum) =
+                database.dump_with_checksum(f, features, encoding, mac_key.as_ref())?;
+            if let Some(key) = signing_key {
+                Signature::sign(&checksum, &key)?.save(signature_path(&db_path))?;
+            }
+            update_parity(&db_path, parity)?;
 
             Ok(ActionSummary::Built)
         }
@@ -257,24 +1047,339 @@ fn driver() -> Result<ActionSummary, error::Error> {
             dir_path,
             features,
             threads,
+            verbosity,
+            trusted_key,
+            mac_key,
         } => {
+            let trusted_key = load_trusted_key(trusted_key)?;
+            let mac_key = load_mac_key(mac_key)?;
+            let database = load_database(&db_path, &trusted_key, &mac_key)?;
+            Ok(ActionSummary::Diff(match archive_format(&dir_path) {
+                Some(format) => {
+                    database.check_archive(&dir_path, format, features, threads, verbosity)?
+                }
+                None => database.check(&dir_path, features, threads, verbosity)?,
+            }))
+        }
+        Action::Diff {
+            old_path,
+            new_path,
+            verbosity,
+            trusted_key,
+            mac_key,
+        } => {
+            let trusted_key = load_trusted_key(trusted_key)?;
+            let mac_key = load_mac_key(mac_key)?;
+            let old = load_database(&old_path, &trusted_key, &mac_key)?;
+            let new = load_database(&new_path, &trusted_key, &mac_key)?;
+            Ok(ActionSummary::Diff(old.show_diff(&new, verbosity)))
+        }
+        Action::Compare {
+            old_path,
+            new_path,
+            features,
+            threads,
+            stream,
+            verbosity,
+        } => Ok(ActionSummary::Diff(if stream {
+            Database::compare_stream(&old_path, &new_path, features, verbosity)?
+        } else {
+            Database::compare(&old_path, &new_path, features, threads, verbosity)?
+        })),
+        Action::SelfCheck {
+            db_path,
+            mac_key,
+            repair,
+        } => {
+            let mac_key = load_mac_key(mac_key)?;
+            let mut database = fs::read(&db_path)?;
+
+            // Parity data is optional, unless repairing
+            let parity = match File::open(parity_path(&db_path)) {
+                Ok(f) => Some(Parity::load(f)),
+                Err(err) if err.kind() == ErrorKind::NotFound && !repair => None,
+                Err(err) => return Err(err.into()),
+            };
+            let mut report = Database::self_check(&database[..], mac_key.as_ref())?;
+            let mut intact = true;
+            match parity {
+                // Parity that doesn't match a database passing its own
+                // checksum was made for some other database. Repairing
+                // with it would only undo the changes since.
+                Some(Ok(parity)) if parity.check(&database).is_mismatched() && report.is_ok() => {
+                    if repair {
+                        update_parity(&db_path, true)?;
+                        println!("rewrote stale parity data");
+                    } else {
+                        println!("parity: the parity data is stale (rewrite it with --repair)");
+                    }
+                }
+                Some(Ok(parity)) => {
+                    let status = parity.check(&database);
+                    print!("{}", status);
+                    if repair && !status.is_ok() {
+                        if status.is_mismatched() {
+                            database = parity.repair(&database)?;
+                            replace(&db_path, &database)?;
+                            println!("repaired {}", Path::new(&db_path).display());
+                            report = Database::self_check(&database[..], mac_key.as_ref())?;
+                        }
+                        if status.damaged_parity > 0 || status.damaged_metadata {
+                            update_parity(&db_path, true)?;
+                            println!("rewrote damaged parity data");
+                        }
+                    } else {
+                        intact = status.is_ok();
+                    }
+                }
+                Some(Err(error::Error::DamagedParity)) if !repair => {
+                    println!("parity: the parity data is damaged and can't be used");
+                    intact = false;
+                }
+                Some(Err(err)) => return Err(err),
+                None => {}
+            }
+
+            print!("{}", report);
+            if report.is_ok() && intact {
+                Ok(ActionSummary::Diff(DiffSummary::NoChanges))
+            } else {
+                Ok(ActionSummary::Damaged)
+            }
+        }
+        Action::Digest { db_path, mac_key } => {
+            let mac_key = load_mac_key(mac_key)?;
             let f = File::open(db_path)?;
-            let database = Database::load_json(f)?;
-            Ok(ActionSummary::Diff(
-                database.check(dir_path, features, threads)?,
-            ))
+            let mut root = RootDigest(None);
+            Database::visit(f, mac_key.as_ref(), &mut root)?;
+            match root.0 {
+                Some(digest) => println!("{}", digest),
+                None => println!("database has no digest (built by an older version)"),
+            }
+            Ok(ActionSummary::Shown)
+        }
+        Action::Keygen { key_path, force } => {
+            let key = SecretKey::generate()?;
+            let mut public_path = key_path.clone();
+            public_path.push(".pub");
+            key.save(&key_path, force)?;
+            key.public_key().save(&public_path, force)?;
+            println!("{}", key.public_key());
+            Ok(ActionSummary::Shown)
         }
-        Action::Diff { old_path, new_path } => {
-            let f_old = File::open(old_path)?;
-            let f_new = File::open(new_path)?;
-            let old = Database::load_json(f_old)?;
-            let new = Database::load_json(f_new)?;
-            Ok(ActionSummary::Diff(old.show_diff(&new)))
+        Action::VerifySignature {
+            db_path,
+            key_path,
+            mac_key,
+        } => {
+            let key = PublicKey::load(key_path)?;
+            let mac_key = load_mac_key(mac_key)?;
+            let f = File::open(&db_path)?;
+            let (_, checksum) = Database::load_with_checksum(f, mac_key.as_ref())?;
+            Signature::load(signature_path(&db_path))?.verify(&checksum, &key)?;
+            println!("good signature by {}", key);
+            Ok(ActionSummary::Shown)
         }
-        Action::SelfCheck { db_path } => {
+        Action::VerifyFile {
+            db_path,
+            file_path,
+            root_path,
+            verbosity,
+            mac_key,
+        } => {
+            let mac_key = load_mac_key(mac_key)?;
             let f = File::open(db_path)?;
-            Database::load_json(f)?;
-            Ok(ActionSummary::Diff(DiffSummary::NoChanges))
+            Ok(ActionSummary::Diff(Database::verify_file(
+                f,
+                mac_key.as_ref(),
+                root_path,
+                Path::new(&file_path),
+                std::io::stdout().lock(),
+                verbosity,
+            )?))
+        }
+        Action::Convert {
+            input_path,
+            output_path,
+            encoding,
+            force,
+            mac_key,
+            parity,
+        } => {
+            let mac_key = load_mac_key(mac_key)?;
+            let input = File::open(input_path)?;
+            let output = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .create_new(!force)
+                .open(&output_path)?;
+            let (_, encoding) = Database::convert(input, output, encoding, mac_key.as_ref())?;
+            println!("converted database to {}", encoding);
+            update_parity(&output_path, parity)?;
+            Ok(ActionSummary::Converted)
+        }
+        Action::Export {
+            db_path,
+            format,
+            trusted_key,
+            mac_key,
+        } => {
+            let trusted_key = load_trusted_key(trusted_key)?;
+            let mac_key = load_mac_key(mac_key)?;
+            let database = load_database(&db_path, &trusted_key, &mac_key)?;
+            database.export(std::io::stdout().lock(), format)?;
+            Ok(ActionSummary::Shown)
+        }
+        Action::Duplicates {
+            db_paths,
+            json,
+            trusted_key,
+            mac_key,
+        } => {
+            let trusted_key = load_trusted_key(trusted_key)?;
+            let mac_key = load_mac_key(mac_key)?;
+            let mut finder = DuplicateFinder::new();
+            for db_path in &db_paths {
+                let database = load_database(db_path, &trusted_key, &mac_key)?;
+                finder.add(db_path, &database);
+            }
+            let duplicates = finder.finish();
+            if json {
+                duplicates.write_json(std::io::stdout().lock())?;
+            } else {
+                duplicates.write_text(std::io::stdout().lock())?;
+            }
+            Ok(ActionSummary::Shown)
+        }
+        Action::FindGood {
+            db_path,
+            search_paths,
+            root_path,
+            paths,
+            threads,
+            script,
+            trusted_key,
+            mac_key,
+        } => {
+            let trusted_key = load_trusted_key(trusted_key)?;
+            let mac_key = load_mac_key(mac_key)?;
+            let database = load_database(&db_path, &trusted_key, &mac_key)?;
+            let mut finder = if paths.is_empty() {
+                CopyFinder::damaged(&database, &root_path, threads)?
+            } else {
+                let paths: Vec<_> = paths.iter().map(PathBuf::from).collect();
+                CopyFinder::new(&database, &root_path, &paths)?
+            };
+            for search_path in &search_paths {
+                if Path::new(search_path).is_dir() {
+                    finder.search_directory(search_path)?;
+                } else {
+                    let other = load_database(search_path, &trusted_key, &mac_key)?;
+                    finder.search_database(search_path, &other);
+                }
+            }
+            let plan = finder.finish();
+            if script {
+                plan.write_script(std::io::stdout().lock())?;
+            } else {
+                plan.write_text(std::io::stdout().lock())?;
+            }
+            Ok(ActionSummary::Shown)
+        }
+        Action::Import {
+            db_path,
+            list_paths,
+            list_format,
+            root_path,
+            force,
+            mac_key,
+            parity,
+        } => {
+            let mac_key = load_mac_key(mac_key)?;
+            let root = fs::canonicalize(&root_path)?;
+            let mut importer = Importer::new();
+            let mut files = 0;
+            for list_path in &list_paths {
+                let list_path = Path::new(list_path);
+                let format = match list_format.or_else(|| ListFormat::infer_from_path(list_path)) {
+                    Some(format) => format,
+                    None => {
+                        eprintln!(
+                            "error: can't tell the format of {} (use --format)",
+                            list_path.display()
+                        );
+                        return Ok(ActionSummary::Damaged);
+                    }
+                };
+                // Paths in a list are relative to the directory it's in,
+                // while mtree specifications usually live outside the
+                // tree, and describe it from the root
+                let list_dir = fs::canonicalize(list_path)?
+                    .parent()
+                    .expect("unreachable")
+                    .to_owned();
+                let prefix = if format == ListFormat::Mtree {
+                    Path::new("")
+                } else {
+                    list_dir.strip_prefix(&root)?
+                };
+                files += importer
+                    .add(File::open(list_path)?, format, prefix)
+                    .inspect_err(|_| {
+                        eprintln!("error: failed to import {}", list_path.display())
+                    })?;
+            }
+            if importer.skipped() > 0 {
+                eprintln!(
+                    "warning: skipped {} files without a digest (use mtree -k with sha256digest)",
+                    importer.skipped()
+                );
+            }
+            let database = importer.finish();
+
+            let f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .create_new(!force)
+                .open(&db_path)?;
+            database.dump_with_checksum(
+                f,
+                Features::default(),
+                Encoding::Json,
+                mac_key.as_ref(),
+            )?;
+            update_parity(&db_path, parity)?;
+            println!("imported {} files from {} lists", files, list_paths.len());
+            Ok(ActionSummary::Built)
+        }
+        Action::Recover {
+            input_path,
+            output_path,
+            encoding,
+            force,
+            mac_key,
+            parity,
+        } => {
+            let mac_key = load_mac_key(mac_key)?;
+            let input = File::open(input_path)?;
+            let output = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .create_new(!force)
+                .open(&output_path)?;
+            let (database, report) = Database::recover(input, mac_key.as_ref())?;
+            print!("{}", report);
+            let encoding = encoding.or(report.encoding).unwrap_or(Encoding::Json);
+            if report.partial {
+                database.dump_partial(output, report.features, encoding, mac_key.as_ref())?;
+            } else {
+                database.dump_with_checksum(output, report.features, encoding, mac_key.as_ref())?;
+            }
+            update_parity(&output_path, parity)?;
+            Ok(ActionSummary::Converted)
         }
     }
 }
@@ -283,9 +1388,14 @@ fn main() {
     ::std::process::exit(match driver() {
         Ok(action_summary) => match action_summary {
             ActionSummary::Built => 0,
+            ActionSummary::Shown => 0,
+            ActionSummary::Converted => 0,
+            ActionSummary::Damaged => -1,
             ActionSummary::Diff(DiffSummary::NoChanges) => 0,
             ActionSummary::Diff(DiffSummary::Changes) => 1,
             ActionSummary::Diff(DiffSummary::Suspicious) => 2,
+            ActionSummary::Diff(DiffSummary::MassModification) => 3,
+            ActionSummary::Diff(DiffSummary::ConvertedLineEndings) => 4,
         },
         Err(err) => {
             eprintln!("error: {:?}", err);
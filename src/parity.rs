@@ -0,0 +1,456 @@
+// Reed-Solomon parity data for a database, stored in a sidecar file, so
+// that small amounts of damage to the database can be repaired. See
+// FORMAT.md for the layout.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha512_256};
+
+use serde_json;
+
+use crate::base64;
+use crate::error;
+
+const MAGIC: &[u8] = b"ICKPARITY";
+
+const ALGORITHM: &str = "reed-solomon-gf256";
+
+// By default, every 64 shards of 4 KiB get 8 parity shards, so that
+// any 8 damaged shards out of each 256 KiB of database can be repaired.
+// Smaller databases get smaller shards (of at least 64 bytes), to keep
+// the parity at about an eighth of the database.
+pub const SHARD_SIZE: usize = 4096;
+const MIN_SHARD_SIZE: usize = 64;
+pub const DATA_SHARDS: usize = 64;
+pub const PARITY_SHARDS: usize = 8;
+
+const HASH_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layout {
+    algorithm: String,
+    #[serde(rename = "shard-size")]
+    shard_size: usize,
+    #[serde(rename = "data-shards")]
+    data_shards: usize,
+    #[serde(rename = "parity-shards")]
+    parity_shards: usize,
+    size: u64, // Size of the database in bytes
+    #[serde(rename = "sha2-512/256")]
+    #[serde(with = "base64")]
+    sha2: Vec<u8>, // Hash of the entire database
+}
+
+impl Layout {
+    fn shards(&self) -> usize {
+        self.size.div_ceil(self.shard_size as u64) as usize
+    }
+
+    fn groups(&self) -> usize {
+        self.shards().div_ceil(self.data_shards)
+    }
+
+    fn parity_len(&self) -> usize {
+        self.groups() * self.parity_shards * self.shard_size
+    }
+
+    // As parity_len, but for a layout read from a file, which might
+    // describe more parity than can be addressed
+    fn checked_parity_len(&self) -> Option<usize> {
+        self.groups()
+            .checked_mul(self.parity_shards)?
+            .checked_mul(self.shard_size)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parity {
+    layout: Layout,
+    hashes: Vec<u8>, // Hashes of the data shards, then the parity shards
+    parity: Vec<u8>,
+    damaged_metadata: bool, // Was one copy of the metadata unreadable?
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParityReport {
+    pub size: u64,         // Expected size of the database
+    pub actual_size: u64,  // Size of the database as found
+    pub shards: u64,       // Number of data shards
+    pub damaged: Vec<u64>, // Indices of damaged data shards
+    pub damaged_parity: u64,
+    pub damaged_metadata: bool,
+    pub unrepairable: Vec<u64>, // Groups with more damage than parity
+}
+
+// Parity data is stored next to the database, with ".parity" appended
+pub fn parity_path(database_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = database_path.as_ref().as_os_str().to_owned();
+    path.push(".parity");
+    PathBuf::from(path)
+}
+
+// Writes parity data for the database at the path. Without parity, any
+// left from a database previously written there is removed, as it
+// would no longer match.
+pub fn update_parity(database_path: impl AsRef<Path>, parity: bool) -> Result<(), error::Error> {
+    let path = parity_path(&database_path);
+    if parity {
+        Parity::create(&fs::read(database_path)?)?.save(File::create(path)?)
+    } else {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> [u8; HASH_LEN] {
+    Sha512_256::digest(bytes).into()
+}
+
+// Returns shard i of the data, padded with zeros to the shard size
+fn shard(data: &[u8], i: usize, shard_size: usize) -> Vec<u8> {
+    let start = (i * shard_size).min(data.len());
+    let end = ((i + 1) * shard_size).min(data.len());
+    let mut shard = data[start..end].to_vec();
+    shard.resize(shard_size, 0);
+    shard
+}
+
+impl Parity {
+    pub fn create(database: &[u8]) -> Result<Parity, error::Error> {
+        let shard_size = database.len().div_ceil(DATA_SHARDS);
+        let shard_size = shard_size.clamp(MIN_SHARD_SIZE, SHARD_SIZE);
+        Parity::with_shards(database, shard_size, DATA_SHARDS, PARITY_SHARDS)
+    }
+
+    // The number of data and parity shards may add up to at most 256
+    pub fn with_shards(
+        database: &[u8],
+        shard_size: usize,
+        data_shards: usize,
+        parity_shards: usize,
+    ) -> Result<Parity, error::Error> {
+        if shard_size == 0 {
+            return Err(error::Error::ReedSolomon(
+                reed_solomon_erasure::Error::EmptyShard,
+            ));
+        }
+        let codec = ReedSolomon::new(data_shards, parity_shards)?;
+        let layout = Layout {
+            algorithm: ALGORITHM.to_owned(),
+            shard_size,
+            data_shards,
+            parity_shards,
+            size: database.len() as u64,
+            sha2: hash(database).to_vec(),
+        };
+
+        let mut hashes = Vec::new();
+        for i in 0..layout.shards() {
+            hashes.extend(hash(&shard(database, i, shard_size)));
+        }
+        let mut parity = Vec::with_capacity(layout.parity_len());
+        for group in 0..layout.groups() {
+            let mut shards: Vec<_> = (0..data_shards)
+                .map(|i| shard(database, group * data_shards + i, shard_size))
+                .collect();
+            shards.resize(data_shards + parity_shards, vec![0; shard_size]);
+            codec.encode(&mut shards)?;
+            for shard in &shards[data_shards..] {
+                hashes.extend(hash(shard));
+                parity.extend(shard);
+            }
+        }
+
+        Ok(Parity {
+            layout,
+            hashes,
+            parity,
+            damaged_metadata: false,
+        })
+    }
+
+    fn parity_shard(&self, group: usize, i: usize) -> &[u8] {
+        let start = (group * self.layout.parity_shards + i) * self.layout.shard_size;
+        &self.parity[start..start + self.layout.shard_size]
+    }
+
+    fn data_hash(&self, i: usize) -> &[u8] {
+        &self.hashes[i * HASH_LEN..(i + 1) * HASH_LEN]
+    }
+
+    fn parity_hash(&self, group: usize, i: usize) -> &[u8] {
+        let j = self.layout.shards() + group * self.layout.parity_shards + i;
+        &self.hashes[j * HASH_LEN..(j + 1) * HASH_LEN]
+    }
+
+    // Data shards that are damaged or (partly) missing
+    fn damaged_shards(&self, database: &[u8]) -> Vec<usize> {
+        if database.len() as u64 == self.layout.size && hash(database)[..] == self.layout.sha2[..] {
+            return Vec::new();
+        }
+        // Anything past the expected size is ignored (and cut off by repair)
+        let database = &database[..database.len().min(self.layout.size as usize)];
+        let shard_size = self.layout.shard_size;
+        (0..self.layout.shards())
+            .filter(|&i| {
+                let end = ((i + 1) * shard_size).min(self.layout.size as usize);
+                database.len() < end
+                    || hash(&shard(database, i, shard_size))[..] != *self.data_hash(i)
+            })
+            .collect()
+    }
+
+    fn damaged_parity_shards(&self, group: usize) -> Vec<usize> {
+        (0..self.layout.parity_shards)
+            .filter(|&i| hash(self.parity_shard(group, i))[..] != *self.parity_hash(group, i))
+            .collect()
+    }
+
+    pub fn check(&self, database: &[u8]) -> ParityReport {
+        let damaged = self.damaged_shards(database);
+        let mut damaged_parity = 0;
+        let mut unrepairable = Vec::new();
+        for group in 0..self.layout.groups() {
+            let parity = self.damaged_parity_shards(group).len();
+            let data = damaged
+                .iter()
+                .filter(|&&i| i / self.layout.data_shards == group)
+                .count();
+            if data > 0 && data + parity > self.layout.parity_shards {
+                unrepairable.push(group as u64);
+            }
+            damaged_parity += parity as u64;
+        }
+        ParityReport {
+            size: self.layout.size,
+            actual_size: database.len() as u64,
+            shards: self.layout.shards() as u64,
+            damaged: damaged.into_iter().map(|i| i as u64).collect(),
+            damaged_parity,
+            damaged_metadata: self.damaged_metadata,
+            unrepairable,
+        }
+    }
+
+    // Reconstructs the database as it was when the parity was created
+    pub fn repair(&self, database: &[u8]) -> Result<Vec<u8>, error::Error> {
+        let layout = &self.layout;
+        let codec = ReedSolomon::new(layout.data_shards, layout.parity_shards)?;
+        let damaged = self.damaged_shards(database);
+        let database = &database[..database.len().min(layout.size as usize)];
+
+        let mut repaired =
+            Vec::with_capacity(layout.groups() * layout.data_shards * layout.shard_size);
+        for group in 0..layout.groups() {
+            let first = group * layout.data_shards;
+            let mut shards: Vec<Option<Vec<u8>>> = (first..first + layout.data_shards)
+                .map(|i| {
+                    if damaged.contains(&i) {
+                        None
+                    } else {
+                        // Shards past the end of the database are zeros
+                        Some(shard(database, i, layout.shard_size))
+                    }
+                })
+                .collect();
+            let bad_parity = self.damaged_parity_shards(group);
+            shards.extend((0..layout.parity_shards).map(|i| {
+                if bad_parity.contains(&i) {
+                    None
+                } else {
+                    Some(self.parity_shard(group, i).to_vec())
+                }
+            }));
+            if shards.iter().any(Option::is_none) {
+                if shards.iter().filter(|s| s.is_none()).count() > layout.parity_shards {
+                    return Err(error::Error::Unrepairable);
+                }
+                codec.reconstruct_data(&mut shards)?;
+            }
+            for shard in shards.into_iter().take(layout.data_shards) {
+                repaired.extend(shard.unwrap());
+            }
+        }
+        repaired.truncate(layout.size as usize);
+
+        if hash(&repaired)[..] != layout.sha2[..] {
+            return Err(error::Error::Unrepairable);
+        }
+        Ok(repaired)
+    }
+
+    // Like the database itself, the metadata (the layout and the hashes
+    // of the shards) may be damaged. It is stored twice, once at either
+    // end of the file, and each copy carries its own hash.
+    fn metadata(&self) -> Result<Vec<u8>, error::Error> {
+        let layout = serde_json::to_vec(&self.layout)?;
+        let mut metadata = Vec::new();
+        metadata.extend((layout.len() as u64).to_be_bytes());
+        metadata.extend(layout);
+        metadata.extend(&self.hashes);
+        let hash = hash(&metadata);
+        metadata.extend(hash);
+        Ok(metadata)
+    }
+
+    pub fn save(&self, mut w: impl Write) -> Result<(), error::Error> {
+        let metadata = self.metadata()?;
+        let len = (metadata.len() as u64).to_be_bytes();
+        w.write_all(MAGIC)?;
+        w.write_all(&len)?;
+        w.write_all(&metadata)?;
+        w.write_all(&self.parity)?;
+        w.write_all(&metadata)?;
+        w.write_all(&len)?;
+        w.write_all(MAGIC)?;
+        Ok(())
+    }
+
+    pub fn load(mut r: impl Read) -> Result<Parity, error::Error> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+
+        let min_len = 2 * (MAGIC.len() + 8);
+        if bytes.len() < min_len {
+            return Err(error::Error::DamagedParity);
+        }
+        let front = read_len(&bytes[MAGIC.len()..])
+            .filter(|_| bytes.starts_with(MAGIC))
+            .and_then(|len| {
+                let start = MAGIC.len() + 8;
+                let end = start.checked_add(len)?;
+                Some((len, parse_metadata(bytes.get(start..end)?)?))
+            });
+        let back = read_len(&bytes[bytes.len() - MAGIC.len() - 8..])
+            .filter(|_| bytes.ends_with(MAGIC))
+            .and_then(|len| {
+                let end = bytes.len() - MAGIC.len() - 8;
+                let start = end.checked_sub(len)?;
+                Some((len, parse_metadata(bytes.get(start..end)?)?))
+            });
+
+        let damaged_metadata = front.is_none() || back.is_none() || front != back;
+        let (len, (layout, hashes)) = front.or(back).ok_or(error::Error::DamagedParity)?;
+        // The lengths come from the file, so their sums might overflow
+        let start = (MAGIC.len() + 8)
+            .checked_add(len)
+            .ok_or(error::Error::DamagedParity)?;
+        let end = layout
+            .checked_parity_len()
+            .and_then(|parity_len| start.checked_add(parity_len))
+            .ok_or(error::Error::DamagedParity)?;
+        let total = end
+            .checked_add(len)
+            .and_then(|total| total.checked_add(8 + MAGIC.len()));
+        if total != Some(bytes.len()) {
+            return Err(error::Error::DamagedParity);
+        }
+        Ok(Parity {
+            parity: bytes[start..end].to_vec(),
+            layout,
+            hashes,
+            damaged_metadata,
+        })
+    }
+}
+
+fn read_len(bytes: &[u8]) -> Option<usize> {
+    let mut len = [0; 8];
+    len.copy_from_slice(bytes.get(..8)?);
+    usize::try_from(u64::from_be_bytes(len)).ok()
+}
+
+fn parse_metadata(metadata: &[u8]) -> Option<(Layout, Vec<u8>)> {
+    let (body, expected) = metadata.split_at(metadata.len().checked_sub(HASH_LEN)?);
+    if hash(body)[..] != *expected {
+        return None;
+    }
+    let len = read_len(body)?;
+    let layout: Layout = serde_json::from_slice(body.get(8..8usize.checked_add(len)?)?).ok()?;
+    let hashes = body[8 + len..].to_vec();
+    let valid = layout.algorithm == ALGORITHM
+        && layout.shard_size > 0
+        && layout.data_shards > 0
+        && layout.parity_shards > 0
+        && layout.data_shards + layout.parity_shards <= 256
+        && layout.sha2.len() == HASH_LEN
+        && layout
+            .groups()
+            .checked_mul(layout.parity_shards)
+            .and_then(|n| n.checked_add(layout.shards()))
+            .and_then(|n| n.checked_mul(HASH_LEN))
+            == Some(hashes.len());
+    if valid {
+        Some((layout, hashes))
+    } else {
+        None
+    }
+}
+
+impl ParityReport {
+    // Does the database itself differ from what the parity expects?
+    pub fn is_mismatched(&self) -> bool {
+        !self.damaged.is_empty() || self.size != self.actual_size
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.damaged.is_empty()
+            && self.size == self.actual_size
+            && self.damaged_parity == 0
+            && !self.damaged_metadata
+    }
+
+    // Can the database be restored from the parity?
+    pub fn is_repairable(&self) -> bool {
+        self.unrepairable.is_empty()
+    }
+}
+
+impl fmt::Display for ParityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.size != self.actual_size {
+            writeln!(
+                f,
+                "parity: size mismatch: expected {} bytes, actual {} bytes",
+                self.size, self.actual_size
+            )?;
+        }
+        if self.damaged.is_empty() {
+            writeln!(
+                f,
+                "parity: all {} shards of the database intact",
+                self.shards
+            )?;
+        } else if self.is_repairable() {
+            writeln!(
+                f,
+                "parity: {} of {} shards of the database damaged, all repairable",
+                self.damaged.len(),
+                self.shards
+            )?;
+        } else {
+            writeln!(
+                f,
+                "parity: {} of {} shards of the database damaged, {} groups of shards beyond repair",
+                self.damaged.len(),
+                self.shards,
+                self.unrepairable.len()
+            )?;
+        }
+        if self.damaged_parity > 0 {
+            writeln!(f, "parity: {} parity shards damaged", self.damaged_parity)?;
+        }
+        if self.damaged_metadata {
+            writeln!(f, "parity: one copy of the parity metadata is damaged")?;
+        }
+        Ok(())
+    }
+}
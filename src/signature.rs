@@ -0,0 +1,214 @@
+// Detached Ed25519 signatures over the checksum of a database. Since
+// the checksum covers the entire database, signing it signs the whole
+// database.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+
+use serde_json;
+
+use crate::base64;
+use crate::database::DatabaseChecksum;
+use crate::error;
+
+const ALGORITHM: &str = "ed25519";
+
+// Prefix of every signed message, so that signatures can't be reused
+// for anything else
+const CONTEXT: &[u8] = b"integrity-checker database signature\n";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SecretKeyFile {
+    algorithm: String,
+    #[serde(rename = "secret-key")]
+    #[serde(with = "base64")]
+    secret_key: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PublicKeyFile {
+    algorithm: String,
+    #[serde(rename = "public-key")]
+    #[serde(with = "base64")]
+    public_key: Vec<u8>,
+}
+
+pub struct SecretKey(SigningKey);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey(VerifyingKey);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    algorithm: String,
+    #[serde(rename = "public-key")]
+    #[serde(with = "base64")]
+    public_key: Vec<u8>,
+    #[serde(with = "base64")]
+    signature: Vec<u8>,
+}
+
+// Signatures are stored next to the database, with ".sig" appended
+pub fn signature_path(database_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = database_path.as_ref().as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+// Only a checksum with a hash is worth signing
+fn message(checksum: &DatabaseChecksum) -> Result<Vec<u8>, error::Error> {
+    if !checksum.has_hash() {
+        return Err(error::Error::MissingHash);
+    }
+    let mut message = CONTEXT.to_vec();
+    message.extend(serde_json::to_vec(checksum)?);
+    Ok(message)
+}
+
+fn read_json<T>(path: impl AsRef<Path>) -> Result<T, error::Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+fn write_json<T>(value: &T, mut f: File) -> Result<(), error::Error>
+where
+    T: serde::Serialize,
+{
+    serde_json::to_writer(&mut f, value)?;
+    f.write_all(b"\n")?;
+    Ok(())
+}
+
+fn create(path: impl AsRef<Path>, force: bool, private: bool) -> Result<File, error::Error> {
+    let mut options = OpenOptions::new();
+    options
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!force);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    Ok(options.open(path)?)
+}
+
+impl SecretKey {
+    // Generates a key from the operating system's random number generator
+    pub fn generate() -> Result<SecretKey, error::Error> {
+        let mut bytes = [0; 32];
+        getrandom::getrandom(&mut bytes).map_err(std::io::Error::from)?;
+        Ok(SecretKey(SigningKey::from_bytes(&bytes)))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<SecretKey, error::Error> {
+        let file: SecretKeyFile = read_json(path)?;
+        if file.algorithm != ALGORITHM || file.secret_key.len() != 32 {
+            return Err(error::Error::InvalidKey);
+        }
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(&file.secret_key);
+        Ok(SecretKey(SigningKey::from_bytes(&bytes)))
+    }
+
+    // The file is only readable by its owner
+    pub fn save(&self, path: impl AsRef<Path>, force: bool) -> Result<(), error::Error> {
+        let file = SecretKeyFile {
+            algorithm: ALGORITHM.to_owned(),
+            secret_key: self.0.to_bytes().to_vec(),
+        };
+        write_json(&file, create(path, force, true)?)
+    }
+}
+
+impl PublicKey {
+    pub fn load(path: impl AsRef<Path>) -> Result<PublicKey, error::Error> {
+        let file: PublicKeyFile = read_json(path)?;
+        if file.algorithm != ALGORITHM {
+            return Err(error::Error::InvalidKey);
+        }
+        PublicKey::from_bytes(&file.public_key)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<PublicKey, error::Error> {
+        let mut key = [0; 32];
+        if bytes.len() != key.len() {
+            return Err(error::Error::InvalidKey);
+        }
+        key.copy_from_slice(bytes);
+        VerifyingKey::from_bytes(&key)
+            .map(PublicKey)
+            .map_err(|_| error::Error::InvalidKey)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, force: bool) -> Result<(), error::Error> {
+        let file = PublicKeyFile {
+            algorithm: ALGORITHM.to_owned(),
+            public_key: self.0.to_bytes().to_vec(),
+        };
+        write_json(&file, create(path, force, false)?)
+    }
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", ALGORITHM, ::base64::encode(self.0.to_bytes()))
+    }
+}
+
+impl Signature {
+    pub fn sign(checksum: &DatabaseChecksum, key: &SecretKey) -> Result<Signature, error::Error> {
+        Ok(Signature {
+            algorithm: ALGORITHM.to_owned(),
+            public_key: key.0.verifying_key().to_bytes().to_vec(),
+            signature: key.0.sign(&message(checksum)?).to_bytes().to_vec(),
+        })
+    }
+
+    // Checks that the signature was made by the trusted key over this
+    // checksum
+    pub fn verify(&self, checksum: &DatabaseChecksum, key: &PublicKey) -> Result<(), error::Error> {
+        if self.algorithm != ALGORITHM || PublicKey::from_bytes(&self.public_key)? != *key {
+            return Err(error::Error::SignatureMismatch);
+        }
+        let signature = ed25519_dalek::Signature::from_slice(&self.signature)
+            .map_err(|_| error::Error::SignatureMismatch)?;
+        key.0
+            .verify_strict(&message(checksum)?, &signature)
+            .map_err(|_| error::Error::SignatureMismatch)
+    }
+
+    // Public key that claims to have made the signature
+    pub fn signer(&self) -> Result<PublicKey, error::Error> {
+        PublicKey::from_bytes(&self.public_key)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Signature, error::Error> {
+        read_json(path).map_err(|err| match err {
+            error::Error::Io(err) if err.kind() == ErrorKind::NotFound => {
+                error::Error::MissingSignature
+            }
+            err => err,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), error::Error> {
+        write_json(self, create(path, true, false)?)
+    }
+}
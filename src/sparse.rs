@@ -0,0 +1,55 @@
+// Detection of sparse holes via lseek(2) with SEEK_DATA/SEEK_HOLE
+
+use std::fs::File;
+
+// Returns the number of bytes of the file that lie in holes, or None
+// if the platform or file system can't tell us.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "illumos",
+    target_os = "solaris"
+))]
+pub fn hole_bytes(f: &File) -> Option<u64> {
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    let fd = f.as_raw_fd();
+    let size = f.metadata().ok()?.len() as libc::off_t;
+
+    let mut holes = 0;
+    let mut pos = 0;
+    while pos < size {
+        // Safety: lseek only repositions the file offset of a valid
+        // descriptor; callers rewind the file before reading it.
+        let data = unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) };
+        if data < 0 {
+            return match Error::last_os_error().raw_os_error() {
+                // No more data: the rest of the file is a hole
+                Some(libc::ENXIO) => Some(holes + (size - pos) as u64),
+                _ => None,
+            };
+        }
+        holes += (data - pos) as u64;
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return None;
+        }
+        pos = hole;
+    }
+    Some(holes)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "illumos",
+    target_os = "solaris"
+)))]
+pub fn hole_bytes(_f: &File) -> Option<u64> {
+    None
+}
@@ -0,0 +1,278 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use integrity_checker::database::{ArchiveFormat, Database, DiffSummary, Features};
+use integrity_checker::error::Error;
+
+use flate2::read::GzDecoder;
+use tempfile::{tempdir, TempDir};
+
+const FORMATS: [(&str, ArchiveFormat); 4] = [
+    ("a.tar", ArchiveFormat::Tar),
+    ("a.tar.gz", ArchiveFormat::TarGz),
+    ("a.tar.xz", ArchiveFormat::TarXz),
+    ("a.tar.zst", ArchiveFormat::TarZst),
+];
+
+fn compress(path: &Path, format: ArchiveFormat, tar: Vec<u8>) {
+    let f = File::create(path).unwrap();
+    match format {
+        ArchiveFormat::Tar => Box::new(f) as Box<dyn Write>,
+        ArchiveFormat::TarGz => Box::new(flate2::write::GzEncoder::new(
+            f,
+            flate2::Compression::default(),
+        )),
+        ArchiveFormat::TarXz => Box::new(xz2::write::XzEncoder::new(f, 6)),
+        ArchiveFormat::TarZst => Box::new(zstd::Encoder::new(f, 0).unwrap().auto_finish()),
+        ArchiveFormat::Zip => unreachable!(),
+    }
+    .write_all(&tar)
+    .unwrap();
+}
+
+fn archive_dir(dir: &str) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_dir_all(".", dir).unwrap();
+    builder.into_inner().unwrap()
+}
+
+fn header(entry_type: tar::EntryType, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(0o640);
+    header.set_uid(1000);
+    header.set_gid(100);
+    header.set_username("alice").unwrap();
+    header.set_groupname("users").unwrap();
+    header.set_mtime(1234567890);
+    header
+}
+
+fn write_archive(tar: Vec<u8>) -> (TempDir, PathBuf) {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("a.tar");
+    compress(&path, ArchiveFormat::Tar, tar);
+    (dir, path)
+}
+
+fn build(path: &Path) -> Result<Database, Error> {
+    let threads = 1;
+    Database::build_archive(
+        path,
+        ArchiveFormat::Tar,
+        Features::default(),
+        threads,
+        false,
+        false,
+    )
+}
+
+#[test]
+fn matches_directory() {
+    let before =
+        Database::build("tests/changes_edit/before", Features::default(), 1, false).unwrap();
+    let after = Database::build("tests/changes_edit/after", Features::default(), 1, false).unwrap();
+    let dir = tempdir().unwrap();
+    let mut archives: Vec<_> = FORMATS
+        .iter()
+        .map(|(name, format)| {
+            let path = dir.path().join(name);
+            compress(&path, *format, archive_dir("tests/changes_edit/before"));
+            (path, *format)
+        })
+        .collect();
+    for name in &["deflated.zip", "stored.zip"] {
+        archives.push((Path::new("tests/zip").join(name), ArchiveFormat::Zip));
+    }
+
+    let threads = 2;
+    for (path, format) in &archives {
+        assert_eq!(ArchiveFormat::infer_from_path(path), Some(*format));
+
+        let check =
+            |db: &Database| db.check_archive(path, *format, Features::default(), threads, 0);
+        assert_eq!(check(&before).unwrap(), DiffSummary::NoChanges);
+        assert_eq!(check(&after).unwrap(), DiffSummary::Changes);
+
+        // Databases built from the archive check the same way
+        let db = Database::build_archive(path, *format, Features::default(), threads, false, false)
+            .unwrap();
+        assert_eq!(db.digest(), before.digest());
+        let check = |dir| db.check(dir, Features::default(), threads, 0).unwrap();
+        assert_eq!(check("tests/changes_edit/before"), DiffSummary::NoChanges);
+        assert_eq!(check("tests/changes_edit/after"), DiffSummary::Changes);
+    }
+}
+
+#[test]
+fn zip_metadata() {
+    let path = "tests/zip/deflated.zip";
+    let threads = 4;
+    let db = Database::build_archive(
+        path,
+        ArchiveFormat::Zip,
+        Features::default(),
+        threads,
+        false,
+        false,
+    )
+    .unwrap();
+    // Directories and symbolic links are skipped
+    assert!(db.lookup(Path::new("dir")).is_none());
+    assert!(db.lookup(Path::new("link")).is_none());
+    let mut json = String::new();
+    let dumped = db.dump_json(Vec::new(), Features::default()).unwrap();
+    GzDecoder::new(&dumped[..])
+        .read_to_string(&mut json)
+        .unwrap();
+    assert_eq!(json.matches(r#""archive":{"mode":416}"#).count(), 3);
+
+    // Members are read in parallel, but the result is the same
+    let threads = 1;
+    let sequential = Database::build_archive(
+        path,
+        ArchiveFormat::Zip,
+        Features::default(),
+        threads,
+        false,
+        false,
+    );
+    assert_eq!(sequential.unwrap(), db);
+}
+
+#[test]
+fn zip_crc_mismatch() {
+    let path = "tests/zip/bad_crc.zip";
+    let threads = 1;
+    let before = Database::build(
+        "tests/changes_edit/before",
+        Features::default(),
+        threads,
+        false,
+    )
+    .unwrap();
+    // The contents are intact, but don't match the stored CRC32
+    let check = before.check_archive(path, ArchiveFormat::Zip, Features::default(), threads, 0);
+    assert_eq!(check.unwrap(), DiffSummary::Suspicious);
+
+    // Building fails, unless the mismatch is ignored
+    let build = |ignore_crc| {
+        Database::build_archive(
+            path,
+            ArchiveFormat::Zip,
+            Features::default(),
+            threads,
+            ignore_crc,
+            false,
+        )
+    };
+    match build(false) {
+        Err(Error::CrcMismatch(_)) => (),
+        result => panic!("expected a CRC32 mismatch, got {:?}", result),
+    }
+    assert_eq!(build(true).unwrap().digest(), before.digest());
+}
+
+#[test]
+fn metadata() {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder
+        .append_data(&mut header(tar::EntryType::Directory, 0), "dir", &[][..])
+        .unwrap();
+    builder
+        .append_data(
+            &mut header(tar::EntryType::Regular, 5),
+            "dir/file",
+            &b"hello"[..],
+        )
+        .unwrap();
+    builder
+        .append_link(&mut header(tar::EntryType::Link, 0), "hard", "dir/file")
+        .unwrap();
+    builder
+        .append_link(&mut header(tar::EntryType::Symlink, 0), "soft", "dir/file")
+        .unwrap();
+    let (dir, path) = write_archive(builder.into_inner().unwrap());
+
+    let db = build(&path).unwrap();
+    assert!(db.lookup(Path::new("soft")).is_none());
+    let mut json = String::new();
+    let dumped = db.dump_json(Vec::new(), Features::default()).unwrap();
+    GzDecoder::new(&dumped[..])
+        .read_to_string(&mut json)
+        .unwrap();
+    let metadata = r#""archive":{"mode":416,"uid":1000,"gid":100,"user":"alice","group":"users","mtime":1234567890"#;
+    assert!(json.contains(&format!("{}}}", metadata)));
+    assert!(json.contains(&format!(r#"{},"link":"dir/file"}}"#, metadata)));
+
+    // The hard link has the contents of its target
+    let tree = dir.path().join("tree");
+    std::fs::create_dir_all(tree.join("dir")).unwrap();
+    std::fs::write(tree.join("dir/file"), "hello").unwrap();
+    std::fs::write(tree.join("hard"), "hello").unwrap();
+    let threads = 1;
+    let check = db.check(&tree, Features::default(), threads, 0);
+    assert_eq!(check.unwrap(), DiffSummary::NoChanges);
+}
+
+#[test]
+fn later_entries_replace_earlier() {
+    let mut builder = tar::Builder::new(Vec::new());
+    for data in &[&b"old"[..], &b"new"[..]] {
+        builder
+            .append_data(&mut header(tar::EntryType::Regular, 3), "file", *data)
+            .unwrap();
+    }
+    let (dir, path) = write_archive(builder.into_inner().unwrap());
+    std::fs::create_dir(dir.path().join("tree")).unwrap();
+    std::fs::write(dir.path().join("tree/file"), "new").unwrap();
+    let db = build(&path).unwrap();
+    let threads = 1;
+    let check = db.check(dir.path().join("tree"), Features::default(), threads, 0);
+    assert_eq!(check.unwrap(), DiffSummary::NoChanges);
+}
+
+#[test]
+fn invalid_entries() {
+    // A path leaving the root
+    let mut evil = header(tar::EntryType::Regular, 1);
+    evil.as_old_mut().name[..7].copy_from_slice(b"../evil");
+    evil.set_cksum();
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append(&evil, &b"x"[..]).unwrap();
+    let (_dir, path) = write_archive(builder.into_inner().unwrap());
+    assert!(matches!(build(&path), Err(Error::InvalidArchive(_))));
+
+    // A hard link to a file that isn't in the archive
+    let mut builder = tar::Builder::new(Vec::new());
+    builder
+        .append_link(&mut header(tar::EntryType::Link, 0), "hard", "missing")
+        .unwrap();
+    let (_dir, path) = write_archive(builder.into_inner().unwrap());
+    assert!(matches!(build(&path), Err(Error::InvalidArchive(_))));
+
+    // A file that is also a directory
+    let mut builder = tar::Builder::new(Vec::new());
+    for name in &["a", "a/b"] {
+        builder
+            .append_data(&mut header(tar::EntryType::Regular, 1), name, &b"x"[..])
+            .unwrap();
+    }
+    let (_dir, path) = write_archive(builder.into_inner().unwrap());
+    assert!(matches!(build(&path), Err(Error::InvalidArchive(_))));
+}
+
+#[test]
+fn infer_format() {
+    for (name, format) in &[
+        ("backup.TGZ", Some(ArchiveFormat::TarGz)),
+        ("backup.txz", Some(ArchiveFormat::TarXz)),
+        ("backup.tzst", Some(ArchiveFormat::TarZst)),
+        ("backup.ZIP", Some(ArchiveFormat::Zip)),
+        ("backup.gz", None),
+    ] {
+        assert_eq!(ArchiveFormat::infer_from_path(Path::new(name)), *format);
+    }
+}
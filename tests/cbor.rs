@@ -0,0 +1,85 @@
+use integrity_checker::database::{Database, Encoding, Features};
+use integrity_checker::error::Error;
+
+mod common;
+use common::{build, gunzip, gzip, ALL_BLOCKS, BLAKE2B, NONE, SHA2};
+
+const ALL_FEATURES: &[Features] = &[NONE, SHA2, BLAKE2B, ALL_BLOCKS];
+
+#[test]
+fn round_trip() {
+    for features in ALL_FEATURES {
+        let db = build("tests/changes_edit_blocks/before", *features);
+        let mut bytes = Vec::new();
+        db.dump_cbor(&mut bytes, *features).unwrap();
+        assert_eq!(db, Database::load_cbor(&bytes[..]).unwrap());
+        assert_eq!(db, Database::load(&bytes[..]).unwrap());
+    }
+}
+
+#[test]
+fn auto_detect_json() {
+    let db = build("tests/changes_new/before", SHA2);
+    let mut bytes = Vec::new();
+    db.dump(&mut bytes, SHA2, Encoding::Json).unwrap();
+    assert_eq!(db, Database::load(&bytes[..]).unwrap());
+}
+
+#[test]
+fn corrupted_database() {
+    for features in &[SHA2, BLAKE2B] {
+        let db = build("tests/changes_edit/before", *features);
+        let mut bytes = Vec::new();
+        db.dump_cbor(&mut bytes, *features).unwrap();
+
+        // Flip a bit in the last byte of the encoded database, which
+        // leaves the CBOR well-formed
+        let mut contents = gunzip(&bytes);
+        *contents.last_mut().unwrap() ^= 1;
+        let corrupted = gzip(&contents);
+        match Database::load(&corrupted[..]) {
+            Err(Error::ChecksumMismatch) => (),
+            result => panic!("expected checksum mismatch, got {:?}", result),
+        }
+    }
+}
+
+#[test]
+fn convert_round_trip() {
+    let db = build("tests/changes_delete_dir/before", BLAKE2B);
+    let mut json = Vec::new();
+    db.dump_json(&mut json, BLAKE2B).unwrap();
+
+    let (cbor, encoding) = Database::convert(&json[..], Vec::new(), None, None).unwrap();
+    assert_eq!(encoding, Encoding::Cbor);
+    assert_eq!(db, Database::load_cbor(&cbor[..]).unwrap());
+
+    let (json2, encoding) = Database::convert(&cbor[..], Vec::new(), None, None).unwrap();
+    assert_eq!(encoding, Encoding::Json);
+    assert_eq!(json, json2);
+}
+
+#[test]
+fn cbor_is_smaller() {
+    let db = build("tests/changes_edit_blocks/before", ALL_BLOCKS);
+    let mut json = Vec::new();
+    db.dump_json(&mut json, ALL_BLOCKS).unwrap();
+    let mut cbor = Vec::new();
+    db.dump_cbor(&mut cbor, ALL_BLOCKS).unwrap();
+    assert!(gunzip(&cbor).len() < gunzip(&json).len());
+}
+
+#[test]
+fn truncated_magic() {
+    for len in 1..3 {
+        let bytes = gzip(&[0xd9, 0xd9, 0xf7][..len]);
+        match Database::load(&bytes[..]) {
+            Err(Error::ParseError) => {}
+            result => panic!("expected a parse error, got {:?}", result),
+        }
+        // Damage is reported rather than panicking on
+        assert!(!Database::self_check(&bytes[..], None).unwrap().is_ok());
+        let (_, report) = Database::recover(&bytes[..], None).unwrap();
+        assert_eq!(report.files, 0);
+    }
+}
@@ -2,6 +2,11 @@ use std::path::{Path, PathBuf};
 
 use integrity_checker::database::{Database, DiffSummary, Features};
 
+mod common;
+use common::{
+    ALL_FEATURES, BLOCK_FEATURES, NONVIABLE_FEATURES, VIABLE_BLOCK_FEATURES, VIABLE_FEATURES,
+};
+
 fn check(
     root_dir: impl AsRef<Path>,
     before_features: Features,
@@ -16,51 +21,10 @@ fn check(
     let threads = 1;
     let before_db = Database::build(&before_path, before_features, threads, false).unwrap();
     before_db
-        .check(&after_path, after_features, threads)
+        .check(&after_path, after_features, threads, 0)
         .unwrap()
 }
 
-const NONE: Features = Features {
-    sha2: false,
-    blake2b: false,
-};
-const SHA2: Features = Features {
-    sha2: true,
-    blake2b: false,
-};
-const BLAKE2B: Features = Features {
-    sha2: false,
-    blake2b: true,
-};
-const ALL: Features = Features {
-    sha2: true,
-    blake2b: true,
-};
-
-const ALL_FEATURES: &[Features] = &[NONE, SHA2, BLAKE2B, ALL];
-
-// These pairs of features share at least one hash in common (and
-// therefore can detect changes even when other metrics don't change).
-const VIABLE_FEATURES: &[(Features, Features)] = &[
-    (SHA2, ALL),
-    (ALL, SHA2),
-    (BLAKE2B, ALL),
-    (ALL, BLAKE2B),
-    (ALL, ALL),
-];
-
-// These pairs of features don't share any common hash (and therefore
-// can't detect changes except when another metric changes).
-const NONVIABLE_FEATURES: &[(Features, Features)] = &[
-    (NONE, NONE),
-    (NONE, SHA2),
-    (SHA2, NONE),
-    (NONE, BLAKE2B),
-    (BLAKE2B, NONE),
-    (SHA2, BLAKE2B),
-    (BLAKE2B, SHA2),
-];
-
 #[test]
 fn no_changes() {
     for before_features in ALL_FEATURES {
@@ -194,3 +158,128 @@ fn suspicious_nonascii() {
         }
     }
 }
+
+#[test]
+fn converted_line_endings() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check(
+                "tests/converted_line_endings",
+                *before_features,
+                *after_features,
+            );
+            assert_eq!(result, DiffSummary::ConvertedLineEndings);
+        }
+    }
+}
+
+#[test]
+fn converted_line_endings_split() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check(
+                "tests/converted_line_endings_split",
+                *before_features,
+                *after_features,
+            );
+            assert_eq!(result, DiffSummary::ConvertedLineEndings);
+        }
+    }
+}
+
+#[test]
+fn suspicious_entropy() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check(
+                "tests/suspicious_entropy",
+                *before_features,
+                *after_features,
+            );
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
+
+#[test]
+fn mass_entropy() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check("tests/mass_entropy", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::MassModification);
+        }
+    }
+}
+
+#[test]
+fn mass_extension() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check("tests/mass_extension", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::MassModification);
+        }
+    }
+}
+
+#[test]
+fn mass_notes() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check("tests/mass_notes", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::MassModification);
+        }
+    }
+}
+
+// A few new notes among many unchanged directories are ordinary additions
+#[test]
+fn changes_readme() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check("tests/changes_readme", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Changes);
+        }
+    }
+}
+
+#[test]
+fn suspicious_format() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check("tests/suspicious_format", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
+
+#[test]
+fn no_changes_blocks() {
+    for before_features in BLOCK_FEATURES {
+        for after_features in BLOCK_FEATURES {
+            let result = check("tests/nochanges", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::NoChanges);
+        }
+    }
+}
+
+#[test]
+fn changes_edit_blocks() {
+    for (before_features, after_features) in VIABLE_BLOCK_FEATURES {
+        let result = check(
+            "tests/changes_edit_blocks",
+            *before_features,
+            *after_features,
+        );
+        assert_eq!(result, DiffSummary::Changes);
+    }
+}
+
+#[test]
+fn suspicious_zeros() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = check("tests/suspicious_zeros", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
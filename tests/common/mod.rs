@@ -0,0 +1,140 @@
+// Helpers shared by the integration tests. Each test crate includes
+// this module, and most use only some of it.
+#![allow(dead_code)]
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use integrity_checker::database::{Database, Features};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use serde_json::Value;
+
+use valico::json_schema;
+
+pub fn build(path: impl AsRef<Path>, features: Features) -> Database {
+    let threads = 1;
+    Database::build(&path, features, threads, false).unwrap()
+}
+
+// Writes a file, creating its parent directories as needed
+pub fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+pub const NONE: Features = Features {
+    sha2: false,
+    blake2b: false,
+    sha256: false,
+    blocks: None,
+};
+pub const SHA2: Features = Features {
+    sha2: true,
+    blake2b: false,
+    sha256: false,
+    blocks: None,
+};
+pub const BLAKE2B: Features = Features {
+    sha2: false,
+    blake2b: true,
+    sha256: false,
+    blocks: None,
+};
+pub const ALL: Features = Features {
+    sha2: true,
+    blake2b: true,
+    sha256: false,
+    blocks: None,
+};
+pub const SHA256: Features = Features {
+    sha2: false,
+    blake2b: false,
+    sha256: true,
+    blocks: None,
+};
+
+pub const ALL_FEATURES: &[Features] = &[NONE, SHA2, BLAKE2B, ALL];
+
+// Per-block hashes use a tiny block size so that the test files span
+// several blocks.
+pub const SHA2_BLOCKS: Features = Features {
+    sha2: true,
+    blake2b: false,
+    sha256: false,
+    blocks: Some(16),
+};
+pub const BLAKE2B_BLOCKS: Features = Features {
+    sha2: false,
+    blake2b: true,
+    sha256: false,
+    blocks: Some(16),
+};
+pub const ALL_BLOCKS: Features = Features {
+    sha2: true,
+    blake2b: true,
+    sha256: false,
+    blocks: Some(16),
+};
+
+pub const BLOCK_FEATURES: &[Features] = &[SHA2_BLOCKS, BLAKE2B_BLOCKS, ALL_BLOCKS];
+
+// These pairs share at least one hash in common, with per-block
+// hashes on at least one side.
+pub const VIABLE_BLOCK_FEATURES: &[(Features, Features)] = &[
+    (SHA2_BLOCKS, SHA2_BLOCKS),
+    (SHA2_BLOCKS, ALL_BLOCKS),
+    (ALL_BLOCKS, BLAKE2B_BLOCKS),
+    (BLAKE2B_BLOCKS, BLAKE2B_BLOCKS),
+    (ALL_BLOCKS, ALL_BLOCKS),
+    (ALL, ALL_BLOCKS),
+    (SHA2_BLOCKS, SHA2),
+];
+
+// These pairs of features share at least one hash in common (and
+// therefore can detect changes even when other metrics don't change).
+pub const VIABLE_FEATURES: &[(Features, Features)] = &[
+    (SHA2, ALL),
+    (ALL, SHA2),
+    (BLAKE2B, ALL),
+    (ALL, BLAKE2B),
+    (ALL, ALL),
+];
+
+// These pairs of features don't share any common hash (and therefore
+// can't detect changes except when another metric changes).
+pub const NONVIABLE_FEATURES: &[(Features, Features)] = &[
+    (NONE, NONE),
+    (NONE, SHA2),
+    (SHA2, NONE),
+    (NONE, BLAKE2B),
+    (BLAKE2B, NONE),
+    (SHA2, BLAKE2B),
+    (BLAKE2B, SHA2),
+];
+
+pub fn gunzip(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut result).unwrap();
+    result
+}
+
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut e = GzEncoder::new(Vec::new(), Compression::default());
+    e.write_all(bytes).unwrap();
+    e.finish().unwrap()
+}
+
+// Checks an instance against one of the schemas in schema/
+pub fn validate_schema(instance: &Value, schema_path: impl AsRef<Path>) -> bool {
+    let f = File::open(schema_path).unwrap();
+    let schema: Value = serde_json::from_reader(f).unwrap();
+
+    let mut scope = json_schema::Scope::new();
+    let schema = scope.compile_and_return(schema, true).unwrap();
+    schema.validate(instance).is_valid()
+}
@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use integrity_checker::database::{Database, DiffSummary, Features};
+
+use tempfile::tempdir;
+
+mod common;
+use common::{build, write, ALL_BLOCKS, NONE, SHA2};
+
+const ALL_FEATURES: &[Features] = &[NONE, SHA2, ALL_BLOCKS];
+
+const FIXTURES: &[(&str, DiffSummary)] = &[
+    ("nochanges", DiffSummary::NoChanges),
+    ("changes_edit", DiffSummary::Changes),
+    ("changes_delete_dir", DiffSummary::Changes),
+    ("changes_new", DiffSummary::Changes),
+    ("suspicious_nul", DiffSummary::Suspicious),
+    ("suspicious_entropy", DiffSummary::Suspicious),
+    ("mass_extension", DiffSummary::MassModification),
+    ("mass_notes", DiffSummary::MassModification),
+    ("changes_readme", DiffSummary::Changes),
+];
+
+fn sides(root_dir: &str) -> (PathBuf, PathBuf) {
+    let root = PathBuf::from("tests").join(root_dir);
+    (root.join("before"), root.join("after"))
+}
+
+#[test]
+fn compare() {
+    for (root_dir, expected) in FIXTURES {
+        let (before, after) = sides(root_dir);
+        for threads in &[1, 4] {
+            let summary = Database::compare(&before, &after, SHA2, *threads, 0).unwrap();
+            assert_eq!(summary, *expected, "{}", root_dir);
+        }
+    }
+}
+
+#[test]
+fn compare_stream() {
+    for (root_dir, expected) in FIXTURES {
+        let (before, after) = sides(root_dir);
+        for verbosity in 0..3 {
+            let summary = Database::compare_stream(&before, &after, SHA2, verbosity).unwrap();
+            assert_eq!(summary, *expected, "{}", root_dir);
+        }
+    }
+}
+
+// Streaming must match the same files as building both databases, even
+// where a file becomes a directory or names sort around the separator
+#[test]
+fn compare_stream_matches_diff() {
+    let dir = tempdir().unwrap();
+    let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+    for side in &[&old, &new] {
+        write(&side.join("same/deep/f"), "hi");
+        write(&side.join("same/g"), "yo");
+        write(&side.join("a.txt"), "z");
+        write(&side.join("mixed/keep"), "m");
+    }
+    write(&old.join("mixed/changed"), "old");
+    write(&new.join("mixed/changed"), "newer");
+    write(&old.join("y"), "file");
+    write(&new.join("y/in"), "file");
+    write(&old.join("mixed.d"), "d");
+    write(&new.join("mixed.d/in"), "d");
+    write(&old.join("gone/sub/q"), "g");
+    write(&new.join("added/sub/n"), "n");
+
+    for features in ALL_FEATURES {
+        let old_db = build(&old, *features);
+        let new_db = build(&new, *features);
+        let expected = old_db.show_diff(&new_db, 0);
+        assert_eq!(expected, DiffSummary::Changes);
+        for verbosity in 0..3 {
+            let summary = Database::compare_stream(&old, &new, *features, verbosity).unwrap();
+            assert_eq!(summary, expected);
+        }
+        assert_eq!(
+            Database::compare_stream(&old, &old, *features, 0).unwrap(),
+            DiffSummary::NoChanges
+        );
+    }
+}
+
+#[test]
+fn compare_missing_directory() {
+    let (before, _) = sides("nochanges");
+    let missing = Path::new("tests/nochanges/missing");
+    assert!(Database::compare(&before, missing, SHA2, 1, 0).is_err());
+    assert!(Database::compare_stream(&before, missing, SHA2, 0).is_err());
+}
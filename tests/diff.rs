@@ -1,6 +1,15 @@
+use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use integrity_checker::database::{Database, DiffSummary, Features};
+use integrity_checker::database::{Database, DiffSummary, EntryDiff, Features};
+
+use tempfile::tempdir;
+
+mod common;
+use common::{
+    ALL_FEATURES, BLOCK_FEATURES, NONVIABLE_FEATURES, SHA2, VIABLE_BLOCK_FEATURES, VIABLE_FEATURES,
+};
 
 fn diff(
     root_dir: impl AsRef<Path>,
@@ -16,50 +25,9 @@ fn diff(
     let threads = 1;
     let before_db = Database::build(&before_path, before_features, threads, false).unwrap();
     let after_db = Database::build(&after_path, after_features, threads, false).unwrap();
-    before_db.show_diff(&after_db)
+    before_db.show_diff(&after_db, 0)
 }
 
-const NONE: Features = Features {
-    sha2: false,
-    blake2b: false,
-};
-const SHA2: Features = Features {
-    sha2: true,
-    blake2b: false,
-};
-const BLAKE2B: Features = Features {
-    sha2: false,
-    blake2b: true,
-};
-const ALL: Features = Features {
-    sha2: true,
-    blake2b: true,
-};
-
-const ALL_FEATURES: &[Features] = &[NONE, SHA2, BLAKE2B, ALL];
-
-// These pairs of features share at least one hash in common (and
-// therefore can detect changes even when other metrics don't change).
-const VIABLE_FEATURES: &[(Features, Features)] = &[
-    (SHA2, ALL),
-    (ALL, SHA2),
-    (BLAKE2B, ALL),
-    (ALL, BLAKE2B),
-    (ALL, ALL),
-];
-
-// These pairs of features don't share any common hash (and therefore
-// can't detect changes except when another metric changes).
-const NONVIABLE_FEATURES: &[(Features, Features)] = &[
-    (NONE, NONE),
-    (NONE, SHA2),
-    (SHA2, NONE),
-    (NONE, BLAKE2B),
-    (BLAKE2B, NONE),
-    (SHA2, BLAKE2B),
-    (BLAKE2B, SHA2),
-];
-
 #[test]
 fn no_changes() {
     for before_features in ALL_FEATURES {
@@ -193,3 +161,401 @@ fn suspicious_nonascii() {
         }
     }
 }
+
+#[test]
+fn converted_line_endings() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(
+                "tests/converted_line_endings",
+                *before_features,
+                *after_features,
+            );
+            assert_eq!(result, DiffSummary::ConvertedLineEndings);
+        }
+    }
+}
+
+#[test]
+fn converted_line_endings_split() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(
+                "tests/converted_line_endings_split",
+                *before_features,
+                *after_features,
+            );
+            assert_eq!(result, DiffSummary::ConvertedLineEndings);
+        }
+    }
+}
+
+// A conversion only stands out when it is the sole difference
+#[test]
+fn converted_line_endings_with_changes() {
+    let dir = tempdir().unwrap();
+    let (before, after) = (dir.path().join("before"), dir.path().join("after"));
+    fs::create_dir_all(&before).unwrap();
+    fs::create_dir_all(&after).unwrap();
+    fs::write(before.join("converted.txt"), "one\ntwo\n").unwrap();
+    fs::write(after.join("converted.txt"), "one\r\ntwo\r\n").unwrap();
+    fs::write(before.join("edited.txt"), "one\n").unwrap();
+    fs::write(after.join("edited.txt"), "one and more\n").unwrap();
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(dir.path(), *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Changes);
+        }
+    }
+
+    // An edit that keeps the size of the converted file is still an edit
+    fs::remove_file(before.join("edited.txt")).unwrap();
+    fs::remove_file(after.join("edited.txt")).unwrap();
+    fs::write(before.join("converted.txt"), "ab\nx\n").unwrap();
+    fs::write(after.join("converted.txt"), "ac\r\ny\r\n").unwrap();
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(dir.path(), *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Changes);
+        }
+    }
+}
+
+// LF to CR keeps the size, so the conversion is only reported when a
+// shared hash shows that the contents changed at all
+#[test]
+fn changes_line_endings_same_size() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(
+                "tests/changes_line_endings_same_size",
+                *before_features,
+                *after_features,
+            );
+            let shared = (before_features.sha2 && after_features.sha2)
+                || (before_features.blake2b && after_features.blake2b);
+            if shared {
+                assert_eq!(result, DiffSummary::ConvertedLineEndings);
+            } else {
+                assert_eq!(result, DiffSummary::NoChanges);
+            }
+        }
+    }
+}
+
+#[test]
+fn suspicious_entropy() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(
+                "tests/suspicious_entropy",
+                *before_features,
+                *after_features,
+            );
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
+
+#[test]
+fn mass_entropy() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff("tests/mass_entropy", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::MassModification);
+        }
+    }
+}
+
+#[test]
+fn mass_extension() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff("tests/mass_extension", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::MassModification);
+        }
+    }
+}
+
+#[test]
+fn mass_notes() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff("tests/mass_notes", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::MassModification);
+        }
+    }
+}
+
+// A few new notes among many unchanged directories are ordinary additions
+#[test]
+fn changes_readme() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff("tests/changes_readme", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Changes);
+        }
+    }
+}
+
+#[test]
+fn suspicious_format() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff("tests/suspicious_format", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
+
+// Files that only resemble a format in their first couple of bytes
+#[test]
+fn weak_magic() {
+    let dir = tempdir().unwrap();
+    let (before, after) = (dir.path().join("before"), dir.path().join("after"));
+    fs::create_dir_all(&before).unwrap();
+    fs::create_dir_all(&after).unwrap();
+
+    fs::write(before.join("notes.txt"), "MZ is short for Mark Zbikowski\n").unwrap();
+    fs::write(after.join("notes.txt"), "Mark Zbikowski is MZ\n").unwrap();
+    fs::write(before.join("odd.bin"), b"\x1f\x8bxxxx").unwrap();
+    fs::write(after.join("odd.bin"), b"plain \x8b text\n").unwrap();
+
+    // A classic QuickTime movie, which has no ftyp box
+    let movie = b"\x00\x00\x00\x08moov\x00\x00\x00\x08mdat";
+    fs::write(before.join("old.mov"), movie).unwrap();
+    fs::write(after.join("old.mov"), movie).unwrap();
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(dir.path(), *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Changes);
+        }
+    }
+
+    // A real PE file overwritten with text is still a format change
+    let mut pe = vec![0; 0x84];
+    pe[0..2].copy_from_slice(b"MZ");
+    pe[0x3c] = 0x80;
+    pe[0x80..0x84].copy_from_slice(b"PE\0\0");
+    fs::write(before.join("tool.exe"), &pe).unwrap();
+    fs::write(after.join("tool.exe"), "not a program\n").unwrap();
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(dir.path(), *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
+
+#[test]
+fn no_changes_blocks() {
+    for before_features in BLOCK_FEATURES {
+        for after_features in BLOCK_FEATURES {
+            let result = diff("tests/nochanges", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::NoChanges);
+        }
+    }
+}
+
+#[test]
+fn changes_edit_blocks() {
+    for (before_features, after_features) in VIABLE_BLOCK_FEATURES {
+        let result = diff(
+            "tests/changes_edit_blocks",
+            *before_features,
+            *after_features,
+        );
+        assert_eq!(result, DiffSummary::Changes);
+    }
+}
+
+// One byte was changed in each of the fourth and ninth of the twelve
+// 16-byte blocks
+#[test]
+fn changes_edit_blocks_ranges() {
+    for (before_features, after_features) in VIABLE_BLOCK_FEATURES {
+        let before_db = Database::build(
+            "tests/changes_edit_blocks/before",
+            *before_features,
+            1,
+            false,
+        )
+        .unwrap();
+        let after_db =
+            Database::build("tests/changes_edit_blocks/after", *after_features, 1, false).unwrap();
+        let diff = match before_db.diff(&after_db) {
+            EntryDiff::Directory(mut entries, _) => entries.remove(Path::new("blocks.txt")),
+            _ => None,
+        };
+        let diff = match diff {
+            Some(EntryDiff::File(diff)) => diff,
+            _ => panic!("expected blocks.txt to have changed"),
+        };
+        let blocks = diff.changed_blocks();
+        if before_features.blocks.is_none() || after_features.blocks.is_none() {
+            assert!(blocks.is_none());
+            continue;
+        }
+        let blocks = blocks.unwrap();
+        assert_eq!(blocks.ranges(), &[(48, 64), (128, 144)]);
+        assert_eq!(
+            blocks.to_string(),
+            "2 of 12 blocks differ at bytes 48..64, 128..144"
+        );
+    }
+}
+
+#[test]
+fn suspicious_zeros() {
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff("tests/suspicious_zeros", *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
+
+// Holes can't be checked in as fixtures, so these files are made here
+fn write_sparse(path: &Path, head: &[u8], size: u64) {
+    let mut f = fs::File::create(path).unwrap();
+    f.write_all(head).unwrap();
+    f.set_len(size).unwrap();
+}
+
+#[test]
+fn suspicious_zeros_holes() {
+    const SIZE: u64 = 64 * 1024;
+    let dir = tempdir().unwrap();
+    let (before, after) = (dir.path().join("before"), dir.path().join("after"));
+    fs::create_dir_all(&before).unwrap();
+    fs::create_dir_all(&after).unwrap();
+
+    // Identical contents stored densely, then sparsely, as a copy might
+    let mut dense = vec![b'x'; 4096];
+    dense.resize(SIZE as usize, 0);
+    fs::write(before.join("copied.img"), &dense).unwrap();
+    write_sparse(&after.join("copied.img"), &dense[..4096], SIZE);
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(dir.path(), *before_features, *after_features);
+            assert_eq!(result, DiffSummary::NoChanges);
+        }
+    }
+
+    // Contents overwritten by a hole
+    fs::write(before.join("punched.img"), vec![b'x'; SIZE as usize]).unwrap();
+    write_sparse(&after.join("punched.img"), &dense[..4096], SIZE);
+    for before_features in ALL_FEATURES {
+        for after_features in ALL_FEATURES {
+            let result = diff(dir.path(), *before_features, *after_features);
+            assert_eq!(result, DiffSummary::Suspicious);
+        }
+    }
+}
+
+fn diff_output(root_dir: &str, features: Features, verbosity: u64) -> String {
+    let root = Path::new(root_dir);
+    let before_db = Database::build(root.join("before"), features, 1, false).unwrap();
+    let after_db = Database::build(root.join("after"), features, 1, false).unwrap();
+    let mut output = Vec::new();
+    before_db
+        .write_diff(&after_db, &mut output, verbosity)
+        .unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn output_verbosity() {
+    let summary = ".: 1 changed, 0 added, 0 removed, 2 unchanged\n\
+                   | zxcv.txt changed: size changed from 19 to 49 bytes\n";
+    let metrics = [
+        "|   size: 19 -> 49\n",
+        "|   sha2-512/256: \
+         f5efd9a471adcc1b3c2104eafca5aaf594cbffbc889be6b7868cae8efc0ecd46 \
+         (9e/ZpHGtzBs8IQTq/KWq9ZTL/7yIm+a3hoyujvwOzUY=) -> \
+         de212fe86ba58c4c4e0474470ed37eba0dbd030da6621c89d3302c0fcc90209f \
+         (3iEv6GuljExOBHRHDtN+ug29Aw2mYhyJ0zAsD8yQIJ8=)\n",
+        "|   nul: false\n",
+        "|   line endings: LF, final newline\n",
+        "|   entropy: 3.54 bits per byte -> 4.30 bits per byte\n",
+    ];
+
+    let quiet = diff_output("tests/changes_edit", SHA2, 0);
+    assert_eq!(quiet, summary);
+
+    let verbose = diff_output("tests/changes_edit", SHA2, 1);
+    assert!(verbose.starts_with(summary));
+    for line in &metrics {
+        assert!(verbose.contains(line), "missing {:?} in {}", line, verbose);
+    }
+    assert!(!verbose.contains("asdf.txt"));
+
+    let very_verbose = diff_output("tests/changes_edit", SHA2, 2);
+    assert!(very_verbose.starts_with(
+        ".: 1 changed, 0 added, 0 removed, 2 unchanged\n\
+         | asdf.txt unchanged\n\
+         | qwer.bin unchanged\n\
+         | zxcv.txt changed: size changed from 19 to 49 bytes\n"
+    ));
+    for line in &metrics {
+        assert!(very_verbose.contains(line));
+    }
+}
+
+// Subtrees skipped by their digests are still listed in full at -vv
+#[test]
+fn output_unchanged_subtrees() {
+    let quiet = diff_output("tests/changes_readme", SHA2, 1);
+    assert!(!quiet.contains("d04"));
+
+    let very_verbose = diff_output("tests/changes_readme", SHA2, 2);
+    assert!(very_verbose.contains(
+        "| d03: 0 changed, 1 added, 0 removed, 1 unchanged\n\
+         | | README.md added\n\
+         | | asdf.txt unchanged\n\
+         | d04: 0 changed, 0 added, 0 removed, 1 unchanged\n\
+         | | asdf.txt unchanged\n"
+    ));
+}
+
+// Every changed, added and removed entry is named, with the reason
+#[test]
+fn output_entries() {
+    let cases = [
+        (
+            "tests/changes_edit_no_size_change",
+            ".: 1 changed, 0 added, 0 removed, 2 unchanged\n\
+             | zxcv.txt changed: contents differ, size unchanged\n",
+        ),
+        (
+            "tests/changes_new",
+            ".: 0 changed, 1 added, 0 removed, 3 unchanged\n\
+             | new.txt added\n",
+        ),
+        (
+            "tests/changes_delete",
+            ".: 0 changed, 0 added, 1 removed, 2 unchanged\n\
+             | asdf.txt removed\n",
+        ),
+        (
+            "tests/changes_delete_dir",
+            ".: 0 changed, 0 added, 1 removed, 2 unchanged\n\
+             | a removed (directory with 3 files)\n",
+        ),
+    ];
+    for (root_dir, expected) in &cases {
+        assert_eq!(diff_output(root_dir, SHA2, 0), *expected);
+    }
+
+    let dir = tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("before/sub")).unwrap();
+    fs::write(dir.path().join("before/sub/entry"), "file").unwrap();
+    fs::create_dir_all(dir.path().join("after/sub/entry")).unwrap();
+    fs::write(dir.path().join("after/sub/entry/inner"), "file").unwrap();
+    assert_eq!(
+        diff_output(dir.path().to_str().unwrap(), SHA2, 0),
+        ".: 1 changed, 0 added, 0 removed, 0 unchanged\n\
+         | sub: 1 changed, 0 added, 0 removed, 0 unchanged\n\
+         | | entry changed: was a file, now a directory\n"
+    );
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use integrity_checker::database::{Database, Features};
+
+mod common;
+use common::{build, BLAKE2B, SHA2};
+
+const ALL: Features = Features {
+    sha2: true,
+    blake2b: true,
+    sha256: false,
+    blocks: None,
+};
+
+const HASH_FEATURES: &[Features] = &[SHA2, BLAKE2B, ALL];
+
+#[test]
+fn identical_trees() {
+    for features in HASH_FEATURES {
+        let before = build("tests/nochanges/before", *features);
+        let after = build("tests/nochanges/after", *features);
+        assert!(before.digest().is_some());
+        assert_eq!(before.digest(), after.digest());
+    }
+}
+
+#[test]
+fn changed_trees() {
+    for dir in &[
+        "tests/changes_edit",
+        "tests/changes_edit_no_size_change",
+        "tests/changes_new",
+        "tests/changes_delete_dir",
+    ] {
+        for features in HASH_FEATURES {
+            let before = build(Path::new(dir).join("before"), *features);
+            let after = build(Path::new(dir).join("after"), *features);
+            assert_ne!(before.digest(), after.digest());
+        }
+    }
+}
+
+#[test]
+fn digest_survives_round_trip() {
+    for features in HASH_FEATURES {
+        let db = build("tests/changes_delete_dir/before", *features);
+        let mut bytes = Vec::new();
+        db.dump_json(&mut bytes, *features).unwrap();
+        let loaded = Database::load_json(&bytes[..]).unwrap();
+        assert_eq!(db, loaded);
+    }
+}
+
+// Names that are not valid UTF-8 are hashed as they are, rather than
+// after replacing the invalid bytes
+#[cfg(unix)]
+#[test]
+fn non_utf8_names() {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (one, two) = (dir.path().join("one"), dir.path().join("two"));
+    fs::create_dir(&one).unwrap();
+    fs::create_dir(&two).unwrap();
+    fs::write(one.join(OsStr::from_bytes(b"name\xff")), "contents").unwrap();
+    fs::write(two.join(OsStr::from_bytes(b"name\xfe")), "contents").unwrap();
+    for features in HASH_FEATURES {
+        let one = build(&one, *features);
+        let two = build(&two, *features);
+        assert_ne!(one.digest(), two.digest());
+    }
+}
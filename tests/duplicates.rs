@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use integrity_checker::database::{DuplicateFinder, Duplicates};
+
+use tempfile::tempdir;
+
+mod common;
+use common::{build, write, SHA2};
+
+fn paths(duplicates: &Duplicates) -> Vec<Vec<(String, PathBuf)>> {
+    duplicates
+        .groups
+        .iter()
+        .map(|group| {
+            group
+                .files
+                .iter()
+                .map(|file| (file.database.display().to_string(), file.path.clone()))
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn single_database() {
+    let dir = tempdir().unwrap();
+    write(&dir.path().join("a"), "hello");
+    write(&dir.path().join("sub/b"), "hello");
+    write(&dir.path().join("sub/c"), "hello");
+    write(&dir.path().join("d"), "different");
+    write(&dir.path().join("e"), "world"); // Same size as a
+    write(&dir.path().join("empty1"), "");
+    write(&dir.path().join("empty2"), "");
+
+    let mut finder = DuplicateFinder::new();
+    finder.add("db", &build(dir.path(), SHA2));
+    let duplicates = finder.finish();
+
+    assert_eq!(
+        paths(&duplicates),
+        vec![vec![
+            ("db".to_owned(), PathBuf::from("a")),
+            ("db".to_owned(), PathBuf::from("sub/b")),
+            ("db".to_owned(), PathBuf::from("sub/c")),
+        ]]
+    );
+    let group = &duplicates.groups[0];
+    assert_eq!(group.size, 5);
+    assert_eq!(group.algorithm, "sha2-512/256");
+    assert_eq!(group.wasted, 10);
+    assert_eq!(duplicates.wasted, 10);
+
+    let mut text = Vec::new();
+    duplicates.write_text(&mut text).unwrap();
+    assert_eq!(
+        String::from_utf8(text).unwrap(),
+        "3 copies of 5 bytes (10 bytes wasted):\n  a\n  sub/b\n  sub/c\n\
+         1 groups of duplicates, 10 bytes wasted\n"
+    );
+}
+
+#[test]
+fn across_databases() {
+    let dir = tempdir().unwrap();
+    let (one, two) = (dir.path().join("one"), dir.path().join("two"));
+    write(&one.join("small"), "abc");
+    write(&two.join("small copy"), "abc");
+    write(&one.join("large"), "abcdefghij");
+    write(&two.join("large"), "abcdefghij");
+    write(&two.join("unique"), "xyz!");
+
+    let mut finder = DuplicateFinder::new();
+    finder.add("one.json.gz", &build(&one, SHA2));
+    finder.add("two.json.gz", &build(&two, SHA2));
+    let duplicates = finder.finish();
+
+    // Most bytes wasted first
+    assert_eq!(
+        paths(&duplicates),
+        vec![
+            vec![
+                ("one.json.gz".to_owned(), PathBuf::from("large")),
+                ("two.json.gz".to_owned(), PathBuf::from("large")),
+            ],
+            vec![
+                ("one.json.gz".to_owned(), PathBuf::from("small")),
+                ("two.json.gz".to_owned(), PathBuf::from("small copy")),
+            ],
+        ]
+    );
+    assert_eq!(duplicates.wasted, 13);
+
+    let mut text = Vec::new();
+    duplicates.write_text(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("  one.json.gz: large\n  two.json.gz: large\n"));
+
+    let mut json = Vec::new();
+    duplicates.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["wasted"], 13);
+    assert_eq!(json["groups"][0]["size"], 10);
+    assert_eq!(json["groups"][0]["files"][1]["database"], "two.json.gz");
+    assert_eq!(json["groups"][1]["files"][1]["path"], "small copy");
+}
+
+#[test]
+fn no_duplicates() {
+    let dir = tempdir().unwrap();
+    write(&dir.path().join("a"), "one");
+    write(&dir.path().join("b"), "two");
+
+    let mut finder = DuplicateFinder::new();
+    finder.add("db", &build(dir.path(), SHA2));
+    let duplicates = finder.finish();
+    assert!(duplicates.groups.is_empty());
+    assert_eq!(duplicates.wasted, 0);
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::Path;
+
+use integrity_checker::database::{Database, Features, ListFormat};
+use integrity_checker::error::Error;
+
+use sha2::{Digest, Sha256};
+
+use tempfile::tempdir;
+
+mod common;
+use common::{build, SHA2};
+
+const ALL: Features = Features {
+    sha2: true,
+    blake2b: true,
+    sha256: true,
+    blocks: None,
+};
+
+fn export(db: &Database, format: ListFormat) -> String {
+    let mut bytes = Vec::new();
+    db.export(&mut bytes, format).unwrap();
+    String::from_utf8(bytes).unwrap()
+}
+
+fn sha256(path: impl AsRef<Path>) -> String {
+    Sha256::digest(fs::read(path).unwrap())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[test]
+fn sha256sum() {
+    let dir = "tests/changes_edit/before";
+    let db = build(dir, ALL);
+    let expected: String = ["asdf.txt", "qwer.bin", "zxcv.txt"]
+        .iter()
+        .map(|name| format!("{}  {}\n", sha256(Path::new(dir).join(name)), name))
+        .collect();
+    assert_eq!(export(&db, ListFormat::Sha256sum), expected);
+}
+
+#[test]
+fn bsd() {
+    let dir = "tests/changes_delete_dir/before";
+    let lines = export(&build(dir, ALL), ListFormat::Bsd);
+    let first = format!(
+        "SHA256 (a/b/c/1.txt) = {}",
+        sha256(Path::new(dir).join("a/b/c/1.txt"))
+    );
+    assert_eq!(lines.lines().next(), Some(first.as_str()));
+    assert_eq!(lines.lines().count(), 5);
+
+    // Without SHA-256, the default hash is used
+    let lines = export(&build(dir, SHA2), ListFormat::Bsd);
+    assert!(lines.lines().all(|line| line.starts_with("SHA512/256 (")));
+}
+
+#[test]
+fn b2sum() {
+    let lines = export(&build("tests/changes_edit/before", ALL), ListFormat::B2sum);
+    let hash = lines.split("  ").next().unwrap();
+    assert_eq!(hash.len(), 64);
+}
+
+#[test]
+fn missing_hash() {
+    let db = build("tests/changes_edit/before", SHA2);
+    for format in &[ListFormat::Sha256sum, ListFormat::B2sum] {
+        assert!(matches!(
+            db.export(Vec::new(), *format),
+            Err(Error::MissingHash)
+        ));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn escaping() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("back\\slash"), "a").unwrap();
+    fs::write(dir.path().join("new\nline"), "b").unwrap();
+    fs::write(dir.path().join("plain name"), "c").unwrap();
+    let db = build(dir.path(), ALL);
+
+    let lines = export(&db, ListFormat::Sha256sum);
+    let lines: Vec<_> = lines.lines().collect();
+    assert!(lines[0].starts_with('\\'));
+    assert!(lines[0].ends_with("  back\\\\slash"));
+    assert!(lines[1].starts_with('\\'));
+    assert!(lines[1].ends_with("  new\\nline"));
+    assert!(!lines[2].starts_with('\\'));
+    assert!(lines[2].ends_with("  plain name"));
+
+    let lines = export(&db, ListFormat::Bsd);
+    assert!(lines.starts_with("\\SHA256 (back\\\\slash) = "));
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::PathBuf;
+
+use integrity_checker::database::{CopyFinder, CopySource, Database};
+use integrity_checker::error::Error;
+
+use tempfile::{tempdir, TempDir};
+
+mod common;
+use common::{build, write, SHA2};
+
+// An original tree with a database, and some damage done to it since
+fn damaged_tree() -> (TempDir, PathBuf, Database) {
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("original");
+    write(&root.join("a"), "alpha");
+    write(&root.join("sub/b"), "beta");
+    write(&root.join("c"), "gamma");
+    write(&root.join("d"), "delta");
+    let database = build(&root, SHA2);
+
+    write(&root.join("a"), "alphx");
+    fs::remove_dir_all(root.join("sub")).unwrap();
+    write(&root.join("c"), "gamma, edited");
+    (dir, root, database)
+}
+
+#[test]
+fn search_directory() {
+    let (dir, root, database) = damaged_tree();
+    let backup = dir.path().join("backup");
+    write(&backup.join("renamed a"), "alpha");
+    write(&backup.join("deep/b"), "beta");
+    write(&backup.join("d"), "delta");
+
+    let mut finder = CopyFinder::damaged(&database, &root, 1).unwrap();
+    assert_eq!(finder.damaged_count(), 3);
+    finder.search_directory(&backup).unwrap();
+    let plan = finder.finish();
+    assert_eq!(
+        plan.copies,
+        vec![
+            (
+                PathBuf::from("a"),
+                CopySource::File(backup.join("renamed a"))
+            ),
+            (
+                PathBuf::from("sub/b"),
+                CopySource::File(backup.join("deep/b"))
+            ),
+        ]
+    );
+    assert_eq!(plan.missing, vec![PathBuf::from("c")]);
+
+    let mut text = Vec::new();
+    plan.write_text(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("c: no good copy found\n"));
+    assert!(text.ends_with("2 of 3 damaged files have a good copy\n"));
+}
+
+#[test]
+fn search_database() {
+    let (dir, root, database) = damaged_tree();
+    let other = dir.path().join("other");
+    write(&other.join("x/b"), "beta");
+    write(&other.join("a"), "alpha");
+    let other_db = build(&other, SHA2);
+    fs::remove_file(other.join("a")).unwrap();
+
+    let mut finder = CopyFinder::damaged(&database, &root, 1).unwrap();
+    finder.search_database("other.json.gz", &other_db);
+    // Files on disk are preferred to those listed in databases
+    finder.search_directory(&other).unwrap();
+    let plan = finder.finish();
+    assert_eq!(
+        plan.copies,
+        vec![
+            (
+                PathBuf::from("a"),
+                CopySource::Database {
+                    database: PathBuf::from("other.json.gz"),
+                    path: PathBuf::from("a"),
+                }
+            ),
+            (PathBuf::from("sub/b"), CopySource::File(other.join("x/b"))),
+        ]
+    );
+}
+
+#[test]
+fn explicit_paths() {
+    let (dir, root, database) = damaged_tree();
+    write(&dir.path().join("copy of d"), "delta");
+
+    // An intact file is found elsewhere, but never as its own copy
+    let paths = [PathBuf::from("d"), PathBuf::from("sub")];
+    let mut finder = CopyFinder::new(&database, &root, &paths).unwrap();
+    finder.search_directory(dir.path()).unwrap();
+    let plan = finder.finish();
+    assert_eq!(
+        plan.copies,
+        vec![(
+            PathBuf::from("d"),
+            CopySource::File(dir.path().join("copy of d"))
+        )]
+    );
+    assert_eq!(plan.missing, vec![PathBuf::from("sub/b")]);
+
+    match CopyFinder::new(&database, &root, &[PathBuf::from("nope")]) {
+        Err(Error::NotInDatabase(path)) => assert_eq!(path, PathBuf::from("nope")),
+        _ => panic!("expected an error for a path not in the database"),
+    }
+}
+
+#[test]
+fn script() {
+    let (dir, root, database) = damaged_tree();
+    let backup = dir.path().join("it's here");
+    write(&backup.join("b"), "beta");
+    let other_db = {
+        let other = dir.path().join("other");
+        write(&other.join("a"), "alpha");
+        build(&other, SHA2)
+    };
+
+    let mut finder = CopyFinder::damaged(&database, &root, 1).unwrap();
+    finder.search_directory(&backup).unwrap();
+    finder.search_database("other.json.gz", &other_db);
+    let mut script = Vec::new();
+    finder.finish().write_script(&mut script).unwrap();
+    let script = String::from_utf8(script).unwrap();
+
+    let root = root.display();
+    let backup = backup.display().to_string().replace('\'', "'\\''");
+    assert!(script.starts_with("#!/bin/sh\n"));
+    assert!(script.contains(&format!("\n# cp -p -- 'a' '{}/a'\n", root)));
+    assert!(script.contains(&format!(
+        "\nmkdir -p -- '{}/sub'\ncp -p -- '{}/b' '{}/sub/b'\n",
+        root, backup, root
+    )));
+    assert!(script.ends_with("# c: no good copy found\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn script_newlines() {
+    // Line breaks in names can't end the comments early
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("original");
+    write(&root.join("a\ntouch a"), "alpha");
+    write(&root.join("b\ntouch b"), "beta");
+    let database = build(&root, SHA2);
+    write(&root.join("a\ntouch a"), "alphx");
+    write(&root.join("b\ntouch b"), "betx");
+    let other_db = {
+        let other = dir.path().join("other");
+        write(&other.join("c\rtouch c\ntouch c"), "alpha");
+        build(&other, SHA2)
+    };
+
+    let mut finder = CopyFinder::damaged(&database, &root, 1).unwrap();
+    finder.search_database("other\ntouch db", &other_db);
+    let mut script = Vec::new();
+    finder.finish().write_script(&mut script).unwrap();
+    let script = String::from_utf8(script).unwrap();
+
+    assert!(!script.lines().any(|line| line.starts_with("touch")));
+    assert!(script.contains("\n# a\\ntouch a: listed in other\\ntouch db, "));
+    assert!(script.contains(&format!(
+        "\n# cp -p -- 'c\\rtouch c\\ntouch c' '{}/a\\ntouch a'\n",
+        root.display()
+    )));
+    assert!(script.ends_with("\n# b\\ntouch b: no good copy found\n"));
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::Path;
+
+use integrity_checker::database::{Database, DiffSummary, Features, Importer, ListFormat};
+use integrity_checker::error::Error;
+
+use tempfile::tempdir;
+
+const ALL: Features = Features {
+    sha2: true,
+    blake2b: true,
+    sha256: true,
+    blocks: None,
+};
+
+fn import(list: &str, format: ListFormat) -> Result<Database, Error> {
+    let mut importer = Importer::new();
+    importer.add(list.as_bytes(), format, Path::new(""))?;
+    Ok(importer.finish())
+}
+
+fn check(db: &Database, dir: impl AsRef<Path>) -> DiffSummary {
+    let threads = 1;
+    db.check(dir, Features::default(), threads, 0).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let dir = "tests/changes_delete_dir/before";
+    let db = Database::build(dir, ALL, 1, false).unwrap();
+    for format in &[ListFormat::Sha256sum, ListFormat::B2sum, ListFormat::Bsd] {
+        let mut list = Vec::new();
+        db.export(&mut list, *format).unwrap();
+        let imported = import(std::str::from_utf8(&list).unwrap(), *format).unwrap();
+        assert_eq!(check(&imported, dir), DiffSummary::NoChanges);
+        assert_eq!(
+            check(&imported, "tests/changes_delete_dir/after"),
+            DiffSummary::Changes
+        );
+    }
+}
+
+#[test]
+fn md5sum() {
+    // As written by md5sum, in text and binary mode
+    let list = "\
+b1946ac92492d2347c6235b4d2611184  asdf.txt
+# comment
+
+6f5902ac237024bdd0c176cb93063dc4 *zxcv.txt
+";
+    let db = import(list, ListFormat::Md5sum).unwrap();
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("asdf.txt"), "hello\n").unwrap();
+    fs::write(dir.path().join("zxcv.txt"), "hello world\n").unwrap();
+    assert_eq!(check(&db, dir.path()), DiffSummary::NoChanges);
+
+    fs::write(dir.path().join("zxcv.txt"), "hello wxrld\n").unwrap();
+    assert_eq!(check(&db, dir.path()), DiffSummary::Changes);
+}
+
+#[test]
+fn sfv() {
+    let list = "\
+; Generated by some tool
+a\\b c.txt 363A3020
+x.txt\tAF083B2D
+";
+    let db = import(list, ListFormat::Sfv).unwrap();
+    let dir = tempdir().unwrap();
+    fs::create_dir(dir.path().join("a")).unwrap();
+    fs::write(dir.path().join("a/b c.txt"), "hello\n").unwrap();
+    fs::write(dir.path().join("x.txt"), "hello world\n").unwrap();
+    assert_eq!(check(&db, dir.path()), DiffSummary::NoChanges);
+
+    // A change in size alone isn't reported, since lists have no sizes,
+    // but the new CRC32 doesn't match
+    fs::write(dir.path().join("x.txt"), "hello world!\n").unwrap();
+    assert_eq!(check(&db, dir.path()), DiffSummary::Changes);
+}
+
+#[test]
+fn prefix() {
+    let mut importer = Importer::new();
+    let list = "b1946ac92492d2347c6235b4d2611184  asdf.txt\n";
+    importer
+        .add(list.as_bytes(), ListFormat::Md5sum, Path::new("sub/dir"))
+        .unwrap();
+    let db = importer.finish();
+    assert!(db.lookup(Path::new("sub/dir/asdf.txt")).is_some());
+}
+
+#[test]
+fn invalid_lines() {
+    let lists = [
+        // Not hex
+        "\nb1946ac92492d2347c6235b4d261118z  asdf.txt\n",
+        // Wrong length
+        "\nb1946ac92492d2347c6235b4d26111  asdf.txt\n",
+        // One space
+        "\nb1946ac92492d2347c6235b4d2611184 asdf.txt\n",
+        // Path escapes the directory
+        "\nb1946ac92492d2347c6235b4d2611184  ../asdf.txt\n",
+        // Same path, different hash
+        "b1946ac92492d2347c6235b4d2611184  asdf.txt\n\
+         6f5902ac237024bdd0c176cb93063dc4  asdf.txt\n",
+        // A file can't also be a directory
+        "b1946ac92492d2347c6235b4d2611184  asdf\n\
+         6f5902ac237024bdd0c176cb93063dc4  asdf/x\n",
+    ];
+    for list in &lists {
+        assert!(
+            matches!(
+                import(list, ListFormat::Md5sum),
+                Err(Error::InvalidChecksumList(2))
+            ),
+            "{:?}",
+            list
+        );
+    }
+}
+
+#[test]
+fn infer_format() {
+    for (name, format) in &[
+        ("SHA256SUMS", Some(ListFormat::Sha256sum)),
+        ("archive.sha256", Some(ListFormat::Sha256sum)),
+        ("MD5SUMS", Some(ListFormat::Md5sum)),
+        ("disc1.SFV", Some(ListFormat::Sfv)),
+        ("release.mtree", Some(ListFormat::Mtree)),
+        ("notes.txt", None),
+    ] {
+        assert_eq!(ListFormat::infer_from_path(Path::new(name)), *format);
+    }
+}
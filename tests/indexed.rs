@@ -0,0 +1,232 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use integrity_checker::database::{
+    Database, DiffSummary, Encoding, Entry, Features, IndexedDatabase,
+};
+use integrity_checker::error::Error;
+
+use tempfile::tempdir;
+
+mod common;
+use common::{build, ALL_BLOCKS, BLAKE2B, SHA2};
+
+const HASH_FEATURES: &[Features] = &[SHA2, BLAKE2B, ALL_BLOCKS];
+
+fn expect_file(db: &Database, path: &str) -> integrity_checker::database::Metrics {
+    match db.lookup(Path::new(path)) {
+        Some(Entry::File(metrics)) => metrics.clone(),
+        entry => panic!("expected file at {}, got {:?}", path, entry),
+    }
+}
+
+#[test]
+fn round_trip() {
+    for features in HASH_FEATURES {
+        let db = build("tests/changes_delete_dir/before", *features);
+        let bytes = db.dump_indexed(Vec::new(), *features).unwrap();
+        assert_eq!(db, Database::load(&bytes[..]).unwrap());
+        assert_eq!(db, Database::load_indexed(&bytes[..]).unwrap());
+
+        let indexed = IndexedDatabase::open(Cursor::new(&bytes)).unwrap();
+        assert_eq!(indexed.digest(), db.digest());
+    }
+}
+
+#[test]
+fn convert_round_trip() {
+    let db = build("tests/changes_edit/before", BLAKE2B);
+    let json = db.dump_json(Vec::new(), BLAKE2B).unwrap();
+    let (indexed, _) =
+        Database::convert(&json[..], Vec::new(), Some(Encoding::Indexed), None).unwrap();
+    let (json2, encoding) = Database::convert(&indexed[..], Vec::new(), None, None).unwrap();
+    assert_eq!(encoding, Encoding::Json);
+    assert_eq!(json, json2);
+}
+
+// Enough files to need several blocks, spread over directories whose
+// names sort differently as strings than as paths
+fn many_files() -> tempfile::TempDir {
+    let dir = tempdir().unwrap();
+    for d in &["a", "a.b", "a-c"] {
+        fs::create_dir(dir.path().join(d)).unwrap();
+        for i in 0..1000 {
+            fs::write(dir.path().join(d).join(format!("{}", i)), i.to_string()).unwrap();
+        }
+    }
+    dir
+}
+
+#[test]
+fn lookup_across_blocks() {
+    let dir = many_files();
+    let db = build(dir.path(), SHA2);
+    let bytes = db.dump_indexed(Vec::new(), SHA2).unwrap();
+
+    let mut indexed = IndexedDatabase::open(Cursor::new(&bytes)).unwrap();
+    for path in &["a/0", "a/999", "a.b/500", "a-c/1", "a-c/999"] {
+        assert_eq!(
+            indexed.lookup(Path::new(path)).unwrap(),
+            Some(expect_file(&db, path))
+        );
+    }
+    for path in &["", "a", "a/1000", "0", "b/0", "a.b/500/x"] {
+        assert_eq!(indexed.lookup(Path::new(path)).unwrap(), None);
+    }
+    assert_eq!(db, indexed.into_database().unwrap());
+}
+
+#[test]
+fn lookup_other_encodings() {
+    let db = build("tests/changes_edit/before", SHA2);
+    for encoding in &[Encoding::Json, Encoding::Cbor, Encoding::Indexed] {
+        let bytes = db.dump(Vec::new(), SHA2, *encoding).unwrap();
+        let metrics =
+            Database::lookup_file(Cursor::new(&bytes), None, Path::new("zxcv.txt")).unwrap();
+        assert_eq!(metrics, Some(expect_file(&db, "zxcv.txt")));
+        let metrics = Database::lookup_file(Cursor::new(&bytes), None, Path::new("nope")).unwrap();
+        assert_eq!(metrics, None);
+    }
+}
+
+#[test]
+fn corrupted_block() {
+    let dir = many_files();
+    let db = build(dir.path(), SHA2);
+    let mut bytes = db.dump_indexed(Vec::new(), SHA2).unwrap();
+
+    // Corrupt the first block, just past the magic and gzip header
+    bytes[8 + 16] ^= 1;
+
+    let mut indexed = IndexedDatabase::open(Cursor::new(&bytes)).unwrap();
+    assert!(indexed.lookup(Path::new("a/0")).is_err());
+    assert!(indexed.lookup(Path::new("a-c/999")).unwrap().is_some());
+    assert!(Database::load(&bytes[..]).is_err());
+}
+
+#[test]
+fn corrupted_index() {
+    let db = build("tests/changes_edit/before", SHA2);
+    let mut bytes = db.dump_indexed(Vec::new(), SHA2).unwrap();
+
+    // The checksum sits just before the trailer; corrupt its hash
+    let key = b"\"sha2-512/256\":\"";
+    let start = (0..bytes.len() - key.len())
+        .rev()
+        .find(|&i| &bytes[i..i + key.len()] == key)
+        .unwrap()
+        + key.len();
+    bytes[start] = if bytes[start] == b'A' { b'B' } else { b'A' };
+    match IndexedDatabase::open(Cursor::new(&bytes)) {
+        Err(Error::ChecksumMismatch) => (),
+        result => panic!("expected checksum mismatch, got {:?}", result.err()),
+    }
+}
+
+#[test]
+fn verify_file() {
+    let db = build("tests/changes_edit/before", SHA2);
+    let bytes = db.dump_indexed(Vec::new(), SHA2).unwrap();
+    for (root, path, summary, expected) in &[
+        (
+            "tests/changes_edit/before",
+            "zxcv.txt",
+            DiffSummary::NoChanges,
+            "zxcv.txt unchanged\n",
+        ),
+        (
+            "tests/changes_edit/after",
+            "zxcv.txt",
+            DiffSummary::Changes,
+            "zxcv.txt changed: size changed from 19 to 49 bytes\n",
+        ),
+        (
+            "tests/changes_edit/after",
+            "asdf.txt",
+            DiffSummary::NoChanges,
+            "asdf.txt unchanged\n",
+        ),
+        (
+            "tests/changes_delete/after",
+            "asdf.txt",
+            DiffSummary::Changes,
+            "asdf.txt removed\n",
+        ),
+        (
+            "tests/changes_edit/after",
+            "nope",
+            DiffSummary::NoChanges,
+            "nope is in neither the database nor the directory\n",
+        ),
+    ] {
+        let mut output = Vec::new();
+        let result = Database::verify_file(
+            Cursor::new(&bytes),
+            None,
+            root,
+            Path::new(path),
+            &mut output,
+            0,
+        );
+        assert_eq!(&result.unwrap(), summary);
+        assert_eq!(&String::from_utf8(output).unwrap(), expected);
+    }
+}
+
+// Writes an indexed database around the given (valid) blocks. The
+// checksums hold only sizes, so they match whatever the blocks contain.
+fn hand_built(blocks: &[serde_json::Value]) -> Vec<u8> {
+    let mut bytes = b"ICKINDEX".to_vec();
+    let mut index = Vec::new();
+    for block in blocks {
+        let block_json = serde_json::to_vec(block).unwrap();
+        let block_gz = common::gzip(&block_json);
+        index.push(serde_json::json!({
+            "first": block[0][0],
+            "offset": bytes.len(),
+            "length": block_gz.len(),
+            "checksum": { "size": block_json.len() },
+        }));
+        bytes.extend_from_slice(&block_gz);
+    }
+    let index_json = serde_json::to_vec(&serde_json::json!({ "blocks": index })).unwrap();
+    let index_gz = common::gzip(&index_json);
+    let checksum_json =
+        serde_json::to_vec(&serde_json::json!({ "size": index_json.len() })).unwrap();
+    let index_offset = bytes.len() as u64;
+    bytes.extend_from_slice(&index_gz);
+    bytes.extend_from_slice(&checksum_json);
+    bytes.extend_from_slice(&index_offset.to_be_bytes());
+    bytes.extend_from_slice(&(index_gz.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(&(checksum_json.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(b"ICKINDEX");
+    bytes
+}
+
+#[test]
+fn invalid_tree() {
+    let db = build("tests/changes_edit/before", SHA2);
+    let metrics = serde_json::to_value(expect_file(&db, "zxcv.txt")).unwrap();
+    let file = |path: &str| serde_json::json!([path, metrics]);
+
+    let valid = hand_built(&[serde_json::json!([file("a"), file("b")])]);
+    assert!(Database::load_indexed(&valid[..]).is_ok());
+
+    for blocks in &[
+        vec![serde_json::json!([file("a"), file("a")])],
+        vec![
+            serde_json::json!([file("a"), file("b")]),
+            serde_json::json!([file("b")]),
+        ],
+        vec![serde_json::json!([file("b"), file("a")])],
+        vec![serde_json::json!([file("a"), file("a/b")])],
+        vec![serde_json::json!([file(""), file("a")])],
+        vec![serde_json::json!([file("a/../b")])],
+    ] {
+        match Database::load_indexed(&hand_built(blocks)[..]) {
+            Err(Error::ParseError) => (),
+            result => panic!("expected parse error, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use integrity_checker::database::{Database, Encoding, Features, IndexedDatabase};
+use integrity_checker::error::Error;
+use integrity_checker::mac::{MacKey, BLAKE2B, HMAC_SHA2};
+
+use tempfile::tempdir;
+
+mod common;
+use common::{build, NONE, SHA2};
+
+fn key(byte: u8) -> MacKey {
+    MacKey::new(vec![byte; 32]).unwrap()
+}
+
+const BLAKE2B_ONLY: Features = Features {
+    sha2: false,
+    blake2b: true,
+    sha256: false,
+    blocks: None,
+};
+
+const ENCODINGS: &[Encoding] = &[Encoding::Json, Encoding::Cbor, Encoding::Indexed];
+
+fn mac_algorithm(checksum: &impl serde::Serialize) -> Option<String> {
+    let value = serde_json::to_value(checksum).unwrap();
+    value["mac"]["algorithm"].as_str().map(str::to_owned)
+}
+
+#[test]
+fn keyed_round_trip() {
+    for (features, algorithm) in &[
+        (SHA2, HMAC_SHA2),
+        (BLAKE2B_ONLY, BLAKE2B),
+        (NONE, HMAC_SHA2),
+    ] {
+        for encoding in ENCODINGS {
+            let db = build("tests/changes_edit/before", *features);
+            let (bytes, checksum) = db
+                .dump_with_checksum(Vec::new(), *features, *encoding, Some(&key(1)))
+                .unwrap();
+            assert_eq!(mac_algorithm(&checksum).as_deref(), Some(*algorithm));
+
+            assert_eq!(db, Database::load_keyed(&bytes[..], &key(1)).unwrap());
+            let (loaded, loaded_checksum) =
+                Database::load_with_checksum(&bytes[..], Some(&key(1))).unwrap();
+            assert_eq!(db, loaded);
+            assert_eq!(checksum, loaded_checksum);
+
+            // Readers without the key still see an intact database
+            assert_eq!(db, Database::load(&bytes[..]).unwrap());
+        }
+    }
+}
+
+#[test]
+fn wrong_key() {
+    for encoding in ENCODINGS {
+        let db = build("tests/changes_edit/before", SHA2);
+        let (bytes, _) = db
+            .dump_with_checksum(Vec::new(), SHA2, *encoding, Some(&key(1)))
+            .unwrap();
+        match Database::load_keyed(&bytes[..], &key(2)) {
+            Err(Error::MacMismatch) => (),
+            result => panic!("expected MAC mismatch, got {:?}", result),
+        }
+    }
+
+    let db = build("tests/changes_edit/before", SHA2);
+    let (bytes, _) = db
+        .dump_with_checksum(Vec::new(), SHA2, Encoding::Indexed, Some(&key(1)))
+        .unwrap();
+    match IndexedDatabase::open_keyed(Cursor::new(&bytes), Some(&key(2))) {
+        Err(Error::MacMismatch) => (),
+        result => panic!("expected MAC mismatch, got {:?}", result.err()),
+    }
+    assert!(IndexedDatabase::open_keyed(Cursor::new(&bytes), Some(&key(1))).is_ok());
+}
+
+#[test]
+fn missing_mac() {
+    for encoding in ENCODINGS {
+        let db = build("tests/changes_edit/before", SHA2);
+        let bytes = db.dump(Vec::new(), SHA2, *encoding).unwrap();
+        match Database::load_keyed(&bytes[..], &key(1)) {
+            Err(Error::MissingMac) => (),
+            result => panic!("expected missing MAC, got {:?}", result),
+        }
+    }
+}
+
+#[test]
+fn lookup_file_keyed() {
+    let path = Path::new("zxcv.txt");
+    for encoding in ENCODINGS {
+        let db = build("tests/changes_edit/before", SHA2);
+        let (bytes, _) = db
+            .dump_with_checksum(Vec::new(), SHA2, *encoding, Some(&key(1)))
+            .unwrap();
+        let metrics = Database::lookup_file(Cursor::new(&bytes), Some(&key(1)), path).unwrap();
+        assert!(metrics.is_some());
+        match Database::lookup_file(Cursor::new(&bytes), Some(&key(2)), path) {
+            Err(Error::MacMismatch) => (),
+            result => panic!("expected MAC mismatch, got {:?}", result),
+        }
+
+        let mut output = Vec::new();
+        let root = "tests/changes_edit/before";
+        match Database::verify_file(
+            Cursor::new(&bytes),
+            Some(&key(2)),
+            root,
+            path,
+            &mut output,
+            0,
+        ) {
+            Err(Error::MacMismatch) => (),
+            result => panic!("expected MAC mismatch, got {:?}", result),
+        }
+
+        let bytes = db.dump(Vec::new(), SHA2, *encoding).unwrap();
+        match Database::lookup_file(Cursor::new(&bytes), Some(&key(1)), path) {
+            Err(Error::MissingMac) => (),
+            result => panic!("expected missing MAC, got {:?}", result),
+        }
+    }
+}
+
+#[test]
+fn convert_keeps_mac() {
+    let db = build("tests/changes_edit/before", BLAKE2B_ONLY);
+    let (json, _) = db
+        .dump_with_checksum(Vec::new(), BLAKE2B_ONLY, Encoding::Json, Some(&key(1)))
+        .unwrap();
+    let (cbor, encoding) = Database::convert(&json[..], Vec::new(), None, Some(&key(1))).unwrap();
+    assert_eq!(encoding, Encoding::Cbor);
+    assert_eq!(db, Database::load_keyed(&cbor[..], &key(1)).unwrap());
+}
+
+#[test]
+fn key_files() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("key");
+
+    fs::write(&path, "0123456789abcdef\n").unwrap();
+    let from_file = MacKey::load(&path).unwrap();
+    let from_bytes = MacKey::new(b"0123456789abcdef".to_vec()).unwrap();
+
+    let db = build("tests/changes_edit/before", SHA2);
+    let (bytes, _) = db
+        .dump_with_checksum(Vec::new(), SHA2, Encoding::Json, Some(&from_file))
+        .unwrap();
+    assert_eq!(db, Database::load_keyed(&bytes[..], &from_bytes).unwrap());
+
+    for len in &[0, 15, 65] {
+        match MacKey::new(vec![0; *len]) {
+            Err(Error::InvalidKey) => (),
+            _ => panic!("accepted a key of {} bytes", len),
+        }
+    }
+    assert!(MacKey::new(vec![0; 64]).is_ok());
+}
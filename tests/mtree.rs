@@ -0,0 +1,174 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use integrity_checker::database::{Database, DiffSummary, Features, Importer, ListFormat};
+use integrity_checker::error::Error;
+
+use tempfile::tempdir;
+
+const SHA256: Features = Features {
+    sha2: true,
+    blake2b: false,
+    sha256: true,
+    blocks: None,
+};
+
+fn import(spec: impl std::io::Read) -> Result<Database, Error> {
+    let mut importer = Importer::new();
+    importer.add(spec, ListFormat::Mtree, Path::new(""))?;
+    Ok(importer.finish())
+}
+
+fn check(db: &Database, dir: impl AsRef<Path>) -> DiffSummary {
+    let threads = 1;
+    db.check(dir, Features::default(), threads, 0).unwrap()
+}
+
+fn assert_fixture(spec: &str) {
+    let db = import(File::open(spec).unwrap()).unwrap();
+    assert!(db.lookup(Path::new("a/b/c/1.txt")).is_some());
+    assert!(db.lookup(Path::new("d/5.txt")).is_some());
+    assert_eq!(
+        check(&db, "tests/changes_delete_dir/before"),
+        DiffSummary::NoChanges
+    );
+    assert_eq!(
+        check(&db, "tests/changes_delete_dir/after"),
+        DiffSummary::Changes
+    );
+}
+
+#[test]
+fn freebsd() {
+    // Hierarchical form, as written by mtree -c
+    assert_fixture("tests/mtree/freebsd.mtree");
+}
+
+#[test]
+fn metadata() {
+    // The size, mode, owner and time of each file are kept, and written
+    // back out
+    let db = import(File::open("tests/mtree/bsdtar.mtree").unwrap()).unwrap();
+    let mut spec = Vec::new();
+    db.export(&mut spec, ListFormat::Mtree).unwrap();
+    let spec = String::from_utf8(spec).unwrap();
+    assert!(spec.contains(
+        "./d/4.txt type=file size=2 mode=0664 uid=0 gid=0 uname=root gname=root md5digest="
+    ));
+
+    let db = import(File::open("tests/mtree/freebsd.mtree").unwrap()).unwrap();
+    let mut spec = Vec::new();
+    db.export(&mut spec, ListFormat::Mtree).unwrap();
+    let spec = String::from_utf8(spec).unwrap();
+    assert!(spec.contains(
+        "./d/4.txt type=file size=2 mode=0644 uid=1001 gid=1001 time=1686676080.000000000 md5digest="
+    ));
+}
+
+#[test]
+fn size() {
+    // A file whose size differs is changed, whatever its digest
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("x"), "ab").unwrap();
+    let spec = "x size=3 md5digest=187ef4436122d1cc2f40dc2b92f0eba0\n";
+    let db = import(spec.as_bytes()).unwrap();
+    assert_eq!(check(&db, dir.path()), DiffSummary::Changes);
+    let spec = spec.replace("size=3", "size=2");
+    let db = import(spec.as_bytes()).unwrap();
+    assert_eq!(check(&db, dir.path()), DiffSummary::NoChanges);
+}
+
+#[test]
+fn bsdtar() {
+    // Full paths, as written by bsdtar --format=mtree
+    assert_fixture("tests/mtree/bsdtar.mtree");
+}
+
+#[cfg(unix)]
+#[test]
+fn round_trip() {
+    let dir = tempdir().unwrap();
+    fs::create_dir(dir.path().join("sp ace")).unwrap();
+    fs::write(dir.path().join("sp ace/a#b=c*"), "a").unwrap();
+    fs::write(dir.path().join("back\\slash"), "b").unwrap();
+    let db = Database::build(dir.path(), SHA256, 1, false).unwrap();
+
+    let mut spec = Vec::new();
+    assert_eq!(db.export(&mut spec, ListFormat::Mtree).unwrap(), 2);
+    let spec = String::from_utf8(spec).unwrap();
+    assert!(spec.starts_with("#mtree\n. type=dir\n"));
+    assert!(spec.contains("./back\\134slash type=file size=1 sha256digest="));
+    assert!(spec.contains("./sp\\040ace type=dir\n"));
+    assert!(spec.contains("./sp\\040ace/a\\043b\\075c\\052 type=file size=1 "));
+
+    let imported = import(spec.as_bytes()).unwrap();
+    assert_eq!(check(&imported, dir.path()), DiffSummary::NoChanges);
+}
+
+#[test]
+fn missing_digest() {
+    // A database built without SHA-256 has no digest mtree knows
+    let db = Database::build(
+        "tests/changes_delete_dir/before",
+        Features::default(),
+        1,
+        false,
+    )
+    .unwrap();
+    assert!(matches!(
+        db.export(Vec::new(), ListFormat::Mtree),
+        Err(Error::MissingHash)
+    ));
+}
+
+#[test]
+fn keywords() {
+    let spec = "\
+/set type=file mode=0644
+. type=dir
+link type=link link=target sha256digest=00
+    x\\stab md5=b1946ac92492d2347c6235b4d2611184
+/unset all
+nodigest size=6
+sub type=dir
+    y md5digest=6f5902ac237024bdd0c176cb93063dc4
+..
+";
+    let mut importer = Importer::new();
+    assert_eq!(
+        importer
+            .add(spec.as_bytes(), ListFormat::Mtree, Path::new(""))
+            .unwrap(),
+        2
+    );
+    assert_eq!(importer.skipped(), 1);
+    let db = importer.finish();
+    assert!(db.lookup(Path::new("x tab")).is_some());
+    assert!(db.lookup(Path::new("sub/y")).is_some());
+    // Links aren't recorded, nor files without digests
+    assert!(db.lookup(Path::new("link")).is_none());
+    assert!(db.lookup(Path::new("nodigest")).is_none());
+}
+
+#[test]
+fn invalid_specs() {
+    let specs = [
+        // Leaves the root
+        "#mtree\n..\n",
+        // Bad digest, on a continued line
+        "#mtree\nx type=file \\\n    sha256digest=abc\n",
+        // Path escapes the root
+        "#mtree\n../x type=file sha1digest=da39a3ee5e6b4b0d3255bfef95601890afd80709\n",
+        // Bad escape
+        "#mtree\nx\\q type=file sha1digest=da39a3ee5e6b4b0d3255bfef95601890afd80709\n",
+        // Unfinished continuation
+        "#mtree\nx type=file \\\n",
+    ];
+    for spec in &specs {
+        assert!(
+            matches!(import(spec.as_bytes()), Err(Error::InvalidChecksumList(2))),
+            "{:?}",
+            spec
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use std::convert::TryInto;
+use std::fs;
+
+use integrity_checker::database::Features;
+use integrity_checker::error::Error;
+use integrity_checker::parity::{parity_path, update_parity, Parity};
+
+use serde_json::Value;
+
+use sha2::{Digest, Sha512_256};
+
+use tempfile::tempdir;
+
+mod common;
+use common::build;
+
+fn json(path: &str) -> Vec<u8> {
+    let features = Features::default();
+    build(path, features)
+        .dump_json(Vec::new(), features)
+        .unwrap()
+}
+
+// Parity with enough shards to damage some of them
+fn parity(database: &[u8]) -> Parity {
+    Parity::with_shards(database, 16, 8, 2).unwrap()
+}
+
+fn reload(parity: &Parity) -> Parity {
+    let mut bytes = Vec::new();
+    parity.save(&mut bytes).unwrap();
+    Parity::load(&bytes[..]).unwrap()
+}
+
+#[test]
+fn intact() {
+    let database = json("tests/changes_delete_dir/before");
+    let parity = Parity::create(&database).unwrap();
+    assert_eq!(reload(&parity), parity);
+    let report = parity.check(&database);
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(parity.repair(&database).unwrap(), database);
+}
+
+#[test]
+fn repair() {
+    let database = json("tests/changes_delete_dir/before");
+    let parity = reload(&parity(&database));
+
+    // Two damaged shards in one group, one in another
+    let mut damaged = database.clone();
+    damaged[3] ^= 1;
+    damaged[20] ^= 0xff;
+    damaged[200] = damaged[200].wrapping_add(1);
+    let report = parity.check(&damaged);
+    assert_eq!(report.damaged, vec![0, 1, 12]);
+    assert!(report.is_repairable());
+    assert_eq!(parity.repair(&damaged).unwrap(), database);
+
+    // Three damaged shards in one group are too many
+    damaged[40] ^= 1;
+    let report = parity.check(&damaged);
+    assert_eq!(report.unrepairable, vec![0]);
+    assert!(matches!(parity.repair(&damaged), Err(Error::Unrepairable)));
+}
+
+#[test]
+fn size_change() {
+    let database = json("tests/changes_delete_dir/before");
+    let parity = parity(&database);
+
+    let truncated = &database[..database.len() - 5];
+    let report = parity.check(truncated);
+    assert!(!report.is_ok());
+    assert_eq!(report.actual_size, database.len() as u64 - 5);
+    assert_eq!(parity.repair(truncated).unwrap(), database);
+
+    let mut extended = database.clone();
+    extended.extend_from_slice(b"junk");
+    let report = parity.check(&extended);
+    assert!(report.damaged.is_empty());
+    assert!(!report.is_ok());
+    assert_eq!(parity.repair(&extended).unwrap(), database);
+}
+
+#[test]
+fn damaged_parity() {
+    let database = json("tests/changes_delete_dir/before");
+    let mut bytes = Vec::new();
+    parity(&database).save(&mut bytes).unwrap();
+
+    // Either copy of the metadata suffices
+    let mut front = bytes.clone();
+    front[20] ^= 1;
+    let mut back = bytes.clone();
+    let len = back.len();
+    back[len - 30] ^= 1;
+    for bytes in &[front, back] {
+        let parity = Parity::load(&bytes[..]).unwrap();
+        let report = parity.check(&database);
+        assert!(report.damaged_metadata);
+        assert!(!report.is_ok());
+        assert_eq!(parity.repair(&database).unwrap(), database);
+    }
+
+    // Damaged parity shards count against the damage that can be repaired
+    let mut shards = bytes.clone();
+    shards[len / 2] ^= 1;
+    let parity = Parity::load(&shards[..]).unwrap();
+    assert_eq!(parity.check(&database).damaged_parity, 1);
+
+    let mut both = bytes.clone();
+    both[20] ^= 1;
+    both[len - 30] ^= 1;
+    assert!(matches!(Parity::load(&both[..]), Err(Error::DamagedParity)));
+}
+
+// Parity whose metadata is intact (so that it passes its own hash), but
+// has a layout edited by forge
+fn forged(database: &[u8], forge: impl FnOnce(&mut Value), hashes: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    parity(database).save(&mut bytes).unwrap();
+    let magic = &bytes[..9];
+    let metadata = &bytes[17..];
+    let layout_len = u64::from_be_bytes(metadata[..8].try_into().unwrap()) as usize;
+    let mut layout: Value = serde_json::from_slice(&metadata[8..8 + layout_len]).unwrap();
+    forge(&mut layout);
+
+    let layout = serde_json::to_vec(&layout).unwrap();
+    let mut metadata = Vec::new();
+    metadata.extend((layout.len() as u64).to_be_bytes());
+    metadata.extend(layout);
+    metadata.extend(vec![0; hashes * 32]);
+    let hash = Sha512_256::digest(&metadata);
+    metadata.extend(hash);
+
+    let len = (metadata.len() as u64).to_be_bytes();
+    let mut forged = Vec::new();
+    forged.extend(magic);
+    forged.extend(len);
+    forged.extend(&metadata);
+    forged.extend(&metadata);
+    forged.extend(len);
+    forged.extend(magic);
+    forged
+}
+
+#[test]
+fn overflowing_layout() {
+    let database = json("tests/changes_delete_dir/before");
+
+    // One data shard and two parity shards, each nearly as large as
+    // can be addressed
+    let huge = forged(
+        &database,
+        |layout| {
+            layout["shard-size"] = (usize::MAX / 2).into();
+            layout["data-shards"] = 1.into();
+            layout["parity-shards"] = 2.into();
+        },
+        3,
+    );
+    assert!(matches!(Parity::load(&huge[..]), Err(Error::DamagedParity)));
+
+    // Too many shards to hash
+    let many = forged(
+        &database,
+        |layout| {
+            layout["shard-size"] = 1.into();
+            layout["size"] = u64::MAX.into();
+        },
+        0,
+    );
+    assert!(matches!(Parity::load(&many[..]), Err(Error::DamagedParity)));
+}
+
+#[test]
+fn rebuilt_database() {
+    // Parity left from an earlier database would only undo the changes
+    // made since, so it is removed when rebuilding without it
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db.json.gz");
+    let before = json("tests/changes_edit/before");
+    let after = json("tests/changes_edit/after");
+
+    fs::write(&path, &before).unwrap();
+    update_parity(&path, true).unwrap();
+    let stale = Parity::load(&fs::read(parity_path(&path)).unwrap()[..]).unwrap();
+    assert!(stale.check(&before).is_ok());
+    assert!(stale.check(&after).is_mismatched());
+
+    fs::write(&path, &after).unwrap();
+    update_parity(&path, false).unwrap();
+    assert!(!parity_path(&path).exists());
+    // Nothing to remove is fine too
+    update_parity(&path, false).unwrap();
+
+    update_parity(&path, true).unwrap();
+    let parity = Parity::load(&fs::read(parity_path(&path)).unwrap()[..]).unwrap();
+    assert!(parity.check(&after).is_ok());
+}
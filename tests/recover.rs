@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use integrity_checker::database::{Database, Encoding, Lost, Problem};
+
+mod common;
+use common::{build, gunzip, gzip, SHA2};
+
+fn find(data: &[u8], needle: &[u8]) -> usize {
+    data.windows(needle.len())
+        .position(|w| w == needle)
+        .unwrap()
+}
+
+#[test]
+fn intact() {
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    for encoding in &[Encoding::Json, Encoding::Cbor, Encoding::Indexed] {
+        let bytes = db.dump(Vec::new(), SHA2, *encoding).unwrap();
+        let (recovered, report) = Database::recover(&bytes[..], None).unwrap();
+        assert_eq!(report.encoding, Some(*encoding));
+        assert!(!report.partial, "{}", report);
+        assert_eq!(report.files, 5);
+        assert!(report.unverified.is_empty());
+        assert_eq!(recovered, db);
+    }
+}
+
+#[test]
+fn truncated_json() {
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    let data = gunzip(&db.dump_json(Vec::new(), SHA2).unwrap());
+    let truncated = gzip(&data[..find(&data, b"\"3.txt\"")]);
+
+    let (recovered, report) = Database::recover(&truncated[..], None).unwrap();
+    assert!(report.partial);
+    assert_eq!(report.files, 2);
+    assert!(recovered.lookup(Path::new("a/b/c/2.txt")).is_some());
+    assert!(recovered.lookup(Path::new("a/b/c/3.txt")).is_none());
+    assert_eq!(
+        report.lost[0],
+        Lost::After {
+            directory: PathBuf::from("a/b/c"),
+            last: Some(PathBuf::from("2.txt")),
+        }
+    );
+    assert_eq!(
+        report.lost.last(),
+        Some(&Lost::After {
+            directory: PathBuf::new(),
+            last: None,
+        })
+    );
+}
+
+#[test]
+fn damaged_json_checksum() {
+    // The entries are still recovered, but can't be checked
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    let mut data = gunzip(&db.dump_json(Vec::new(), SHA2).unwrap());
+    data[0] = b'x';
+    let damaged = gzip(&data);
+
+    let (recovered, report) = Database::recover(&damaged[..], None).unwrap();
+    assert_eq!(report.encoding, Some(Encoding::Json));
+    assert!(report.partial);
+    assert!(matches!(report.problems[..], [Problem::Checksum { .. }]));
+    assert!(report.lost.is_empty());
+    assert_eq!(report.files, 5);
+    assert_eq!(report.unverified.len(), 5);
+    assert_eq!(recovered, db);
+}
+
+#[test]
+fn damaged_json_hash() {
+    // A flipped bit in a hash still parses, so none of the files can be
+    // trusted once the checksum fails to match
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    let mut data = gunzip(&db.dump_json(Vec::new(), SHA2).unwrap());
+    let at = find(&data, b"\"3.txt\"");
+    let at = at + find(&data[at..], b"\"sha2-512/256\":\"") + 16;
+    data[at] = if data[at] == b'A' { b'B' } else { b'A' };
+    let damaged = gzip(&data);
+
+    let (recovered, report) = Database::recover(&damaged[..], None).unwrap();
+    assert!(report.partial);
+    assert!(matches!(
+        report.problems[..],
+        [Problem::HashMismatch { .. }]
+    ));
+    assert!(report.lost.is_empty());
+    assert_eq!(report.files, 5);
+    assert_ne!(recovered, db);
+    assert_eq!(report.unverified.len(), 5);
+    assert!(report.unverified.contains(&PathBuf::from("a/b/c/3.txt")));
+    assert!(report.to_string().contains("unverified: a/b/c/3.txt\n"));
+}
+
+#[test]
+fn truncated_cbor() {
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    let data = gunzip(&db.dump_cbor(Vec::new(), SHA2).unwrap());
+    let truncated = gzip(&data[..find(&data, b"3.txt")]);
+
+    let (recovered, report) = Database::recover(&truncated[..], None).unwrap();
+    assert_eq!(report.encoding, Some(Encoding::Cbor));
+    assert!(report.partial);
+    assert_eq!(report.files, 2);
+    assert!(recovered.lookup(Path::new("a/b/c/1.txt")).is_some());
+    assert!(recovered.lookup(Path::new("d")).is_none());
+}
+
+#[test]
+fn indexed() {
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    let bytes = db.dump_indexed(Vec::new(), SHA2).unwrap();
+
+    // A damaged block loses its files
+    let mut damaged = bytes.clone();
+    damaged[8 + 20] ^= 1;
+    let (_, report) = Database::recover(&damaged[..], None).unwrap();
+    assert!(report.partial);
+    assert_eq!(report.lost, vec![Lost::Between(None, None)]);
+    assert_eq!(report.unverified.len(), report.files as usize);
+
+    // A damaged index still leaves the blocks
+    let mut damaged = bytes.clone();
+    let at = bytes.len() - 40;
+    damaged[at] ^= 1;
+    let (recovered, report) = Database::recover(&damaged[..], None).unwrap();
+    assert!(report.partial);
+    assert_eq!(report.files, 5);
+    assert_eq!(recovered, db);
+}
+
+#[test]
+fn partial_output() {
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    let data = gunzip(&db.dump_json(Vec::new(), SHA2).unwrap());
+    let truncated = gzip(&data[..find(&data, b"\"3.txt\"")]);
+    let (recovered, report) = Database::recover(&truncated[..], None).unwrap();
+
+    let (bytes, checksum) = recovered
+        .dump_partial(Vec::new(), report.features, Encoding::Json, None)
+        .unwrap();
+    assert!(checksum.is_partial());
+    let (loaded, checksum) = Database::load_with_checksum(&bytes[..], None).unwrap();
+    assert!(checksum.is_partial());
+    assert_eq!(loaded, recovered);
+
+    let report = Database::self_check(&bytes[..], None).unwrap();
+    assert!(report.partial);
+    assert!(report.is_ok());
+
+    // Recovering again keeps the mark
+    let (_, report) = Database::recover(&bytes[..], None).unwrap();
+    assert!(report.partial);
+    assert!(report.problems.is_empty());
+    assert!(report.unverified.is_empty());
+}
@@ -0,0 +1,393 @@
+use std::collections::BTreeSet;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use integrity_checker::database::{Database, Encoding, Position, Problem};
+use integrity_checker::mac::MacKey;
+
+use serde_json::{json, Value};
+
+use sha2::{Digest, Sha512_256};
+
+mod common;
+use common::{build, gunzip, gzip, validate_schema, ALL_BLOCKS, SHA2};
+
+fn problems(bytes: &[u8]) -> Vec<Problem> {
+    Database::self_check(bytes, None).unwrap().problems
+}
+
+// Splits a JSON database into its checksum and contents
+fn split(bytes: &[u8]) -> (String, String) {
+    let data = String::from_utf8(gunzip(bytes)).unwrap();
+    let (header, body) = data.split_once('\n').unwrap();
+    (header.to_owned(), body.to_owned())
+}
+
+// Re-encodes edited contents, with a checksum that matches them
+fn rechecksum(body: &str) -> Vec<u8> {
+    let hash = ::base64::encode(Sha512_256::digest(body.as_bytes()));
+    let header = format!("{{\"sha2-512/256\":\"{}\",\"size\":{}}}", hash, body.len());
+    gzip(format!("{}\n{}", header, body).as_bytes())
+}
+
+fn json(path: &str) -> Vec<u8> {
+    build(path, SHA2).dump_json(Vec::new(), SHA2).unwrap()
+}
+
+#[test]
+fn intact() {
+    let db = build("tests/changes_delete_dir/before", ALL_BLOCKS);
+    for encoding in &[Encoding::Json, Encoding::Cbor, Encoding::Indexed] {
+        let bytes = db.dump(Vec::new(), ALL_BLOCKS, *encoding).unwrap();
+        let report = Database::self_check(&bytes[..], None).unwrap();
+        assert_eq!(report.encoding, Some(*encoding));
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+}
+
+#[test]
+fn checksum_mismatch() {
+    let bytes = json("tests/changes_edit/before");
+    let (header, body) = split(&bytes);
+    let edited = gzip(format!("{}\n{} ", header, body).as_bytes());
+    let problems = problems(&edited);
+    assert!(problems.iter().any(|p| matches!(
+        p,
+        Problem::HashMismatch {
+            algorithm: "sha2-512/256",
+            ..
+        }
+    )));
+    assert!(problems.contains(&Problem::SizeMismatch {
+        expected: body.len() as u64,
+        actual: body.len() as u64 + 1,
+    }));
+}
+
+#[test]
+fn damaged_gzip() {
+    let bytes = json("tests/changes_edit/before");
+    let problems = problems(&bytes[..bytes.len() - 10]);
+    assert!(matches!(problems[0], Problem::Gzip { .. }));
+
+    let mut trailing = bytes.clone();
+    trailing.extend_from_slice(b"junk");
+    assert_eq!(
+        self::problems(&trailing),
+        vec![Problem::TrailingData { bytes: 4 }]
+    );
+}
+
+#[test]
+fn parse_position() {
+    let bytes = json("tests/changes_edit/before");
+    let (_, body) = split(&bytes);
+    let broken = rechecksum(&body.replacen("\"size\":", "\"size\"", 1));
+    let column = body.find("\"size\":").unwrap() + "\"size\":".len();
+    match &problems(&broken)[..] {
+        [Problem::Parse {
+            position: Some(Position::Line { line, column: c }),
+            ..
+        }] => {
+            assert_eq!(*line, 2);
+            assert_eq!(*c, column);
+        }
+        problems => panic!("expected a parse error, got {:?}", problems),
+    }
+}
+
+#[test]
+fn schema_violations() {
+    let bytes = json("tests/changes_edit/before");
+    let (_, body) = split(&bytes);
+
+    // Duplicate names
+    let duplicate = rechecksum(&body.replace("\"zxcv.txt\"", "\"asdf.txt\""));
+    assert!(problems(&duplicate).contains(&Problem::Schema {
+        path: PathBuf::new(),
+        message: "duplicate entry \"asdf.txt\"".to_owned(),
+    }));
+
+    // Truncated hash and unknown field
+    let start = body.find("\"sha2-512/256\":\"").unwrap() + 16;
+    let start = body[start..].find("\"sha2-512/256\":\"").unwrap() + start + 16;
+    let mut short = body.clone();
+    short.replace_range(start..start + 44, "AAAA");
+    let short = short.replacen("\"nul\":", "\"null\":false,\"nul\":", 1);
+    let problems = problems(&rechecksum(&short));
+    assert!(
+        problems.iter().any(|p| matches!(
+            p,
+            Problem::Schema { message, .. }
+                if message == "sha2-512/256: expected 44 characters, got 4"
+        )),
+        "{:?}",
+        problems
+    );
+    assert!(problems.iter().any(|p| matches!(
+        p,
+        Problem::Schema { message, .. } if message == "unknown field \"null\""
+    )));
+
+    // Invalid names
+    let invalid = rechecksum(&body.replace("\"zxcv.txt\"", "\"../zxcv.txt\""));
+    assert!(self::problems(&invalid).iter().any(|p| matches!(
+        p,
+        Problem::Schema { message, .. } if message.starts_with("invalid entry name")
+    )));
+}
+
+#[test]
+fn digest_mismatch() {
+    let bytes = json("tests/changes_delete_dir/before");
+    let (_, body) = split(&bytes);
+    let edited = rechecksum(&body.replacen("\"size\":2", "\"size\":3", 1));
+    assert_eq!(
+        problems(&edited),
+        vec![Problem::Digest {
+            path: PathBuf::from("a/b/c"),
+        }]
+    );
+}
+
+#[test]
+fn indexed_block() {
+    let db = build("tests/changes_delete_dir/before", SHA2);
+    let mut bytes = db.dump_indexed(Vec::new(), SHA2).unwrap();
+    bytes[8 + 20] ^= 1;
+    let problems = problems(&bytes);
+    assert!(!problems.is_empty());
+    assert!(problems
+        .iter()
+        .all(|p| matches!(p, Problem::Block { block: 0, .. })));
+    assert!(Database::self_check(Cursor::new(&bytes[..8]), None)
+        .unwrap()
+        .problems
+        .contains(&Problem::Layout {
+            message: "the trailer is missing".to_owned()
+        }));
+}
+
+#[test]
+fn mac() {
+    let key = MacKey::new(vec![1; 32]).unwrap();
+    let other = MacKey::new(vec![2; 32]).unwrap();
+    let db = build("tests/changes_edit/before", SHA2);
+    for encoding in &[Encoding::Json, Encoding::Cbor, Encoding::Indexed] {
+        let plain = db.dump(Vec::new(), SHA2, *encoding).unwrap();
+        let report = Database::self_check(&plain[..], Some(&key)).unwrap();
+        assert_eq!(report.problems, vec![Problem::MissingMac]);
+
+        let (keyed, _) = db
+            .dump_with_checksum(Vec::new(), SHA2, *encoding, Some(&key))
+            .unwrap();
+        assert!(Database::self_check(&keyed[..], Some(&key))
+            .unwrap()
+            .is_ok());
+        let report = Database::self_check(&keyed[..], Some(&other)).unwrap();
+        assert_eq!(report.problems, vec![Problem::MacMismatch]);
+    }
+}
+
+// selfcheck interprets the JSON schemas itself, so it is run alongside a
+// reference validator over the same documents: a valid one using every field in the schemas, and
+// variants of it with each value removed, retyped or out of range, and
+// with an unknown field added to each object
+
+fn hash(len: usize) -> Value {
+    Value::from(::base64::encode(vec![0; len]))
+}
+
+fn valid_checksum(body: &str) -> Value {
+    json!({
+        "sha2-512/256": ::base64::encode(Sha512_256::digest(body.as_bytes())),
+        "blake2b": hash(32),
+        "size": body.len(),
+        "mac": { "algorithm": "hmac-sha2-512/256", "tag": hash(32) },
+        "partial": false,
+    })
+}
+
+fn valid_database() -> Value {
+    json!({ "Directory": {
+        ".": {
+            "sha2-512/256": hash(32),
+            "blake2b": hash(32),
+            "sha256": hash(32),
+            "files": 1,
+            "directories": 1,
+        },
+        "empty": { "Directory": {} },
+        "file": { "File": {
+            "sha2-512/256": hash(32),
+            "blake2b": hash(32),
+            "sha256": hash(32),
+            "md5": hash(16),
+            "sha1": hash(20),
+            "crc32": hash(4),
+            "size": 20,
+            "nul": false,
+            "nonascii": false,
+            "line_endings": { "lf": 1, "crlf": 0, "cr": 0, "final_newline": true },
+            "normalized": hash(32),
+            "entropy": 2000,
+            "format": "data",
+            "blocks": { "size": 16, "sha2-512/256": [hash(32)], "blake2b": [hash(32)] },
+            "zeros": { "blocks": 0, "longest_run": 0, "holes": 0 },
+            "hashes_only": false,
+            "size_known": true,
+            "archive": {
+                "mode": 420,
+                "uid": 0,
+                "gid": 0,
+                "user": "root",
+                "group": "root",
+                "mtime": 0,
+                "link": "target",
+            },
+        } },
+    } })
+}
+
+// Every value reachable from the root, as a JSON pointer
+fn pointers(value: &Value, pointer: String, result: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                pointers(
+                    value,
+                    format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1")),
+                    result,
+                );
+            }
+        }
+        Value::Array(items) => {
+            for (i, value) in items.iter().enumerate() {
+                pointers(value, format!("{}/{}", pointer, i), result);
+            }
+        }
+        _ => {}
+    }
+    result.push(pointer);
+}
+
+fn variants(valid: &Value) -> Vec<Value> {
+    let mut all = Vec::new();
+    pointers(valid, String::new(), &mut all);
+    let mut result = vec![valid.clone()];
+    for pointer in all {
+        let original = valid.pointer(&pointer).unwrap();
+        let mut replacements = vec![
+            Value::Null,
+            json!(-1),
+            json!(8001),
+            json!(0),
+            json!("bogus"),
+        ];
+        replacements.push(if original.is_string() {
+            json!(1)
+        } else {
+            json!("x")
+        });
+        for replacement in replacements {
+            let mut variant = valid.clone();
+            *variant.pointer_mut(&pointer).unwrap() = replacement;
+            result.push(variant);
+        }
+        if let Some((parent, key)) = pointer.rsplit_once('/') {
+            let mut variant = valid.clone();
+            if let Some(map) = variant.pointer_mut(parent).unwrap().as_object_mut() {
+                map.remove(&key.replace("~1", "/").replace("~0", "~"));
+                result.push(variant);
+            }
+        }
+        if original.is_object() {
+            let mut variant = valid.clone();
+            let map = variant
+                .pointer_mut(&pointer)
+                .unwrap()
+                .as_object_mut()
+                .unwrap();
+            map.insert("bogus".to_owned(), json!(1));
+            result.push(variant);
+        }
+    }
+    result
+}
+
+fn rejected(problems: &[Problem]) -> bool {
+    problems.iter().any(|p| {
+        matches!(
+            p,
+            Problem::Schema { .. } | Problem::Parse { .. } | Problem::Checksum { .. }
+        )
+    })
+}
+
+// Names of the fields the schema describes
+fn schema_fields(schema: &Value, result: &mut BTreeSet<String>) {
+    match schema {
+        Value::Object(map) => {
+            for (key, value) in map {
+                if let Some(properties) = value.as_object().filter(|_| key == "properties") {
+                    result.extend(properties.keys().cloned());
+                }
+                schema_fields(value, result);
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| schema_fields(item, result)),
+        _ => {}
+    }
+}
+
+fn document_fields(document: &Value, result: &mut BTreeSet<String>) {
+    match document {
+        Value::Object(map) => {
+            for (key, value) in map {
+                result.insert(key.clone());
+                document_fields(value, result);
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| document_fields(item, result)),
+        _ => {}
+    }
+}
+
+#[test]
+fn schema_agreement() {
+    let database = valid_database();
+    let body = database.to_string();
+
+    // Adding a field to a schema should also add it to these documents
+    for (valid, schema_path) in &[
+        (&database, "schema/database.json"),
+        (&valid_checksum(&body), "schema/checksum.json"),
+    ] {
+        let schema: Value = serde_json::from_slice(&std::fs::read(schema_path).unwrap()).unwrap();
+        let (mut expected, mut covered) = (BTreeSet::new(), BTreeSet::new());
+        schema_fields(&schema, &mut expected);
+        document_fields(valid, &mut covered);
+        let missing: Vec<_> = expected.difference(&covered).collect();
+        assert!(
+            missing.is_empty(),
+            "{} fields not covered: {:?}",
+            schema_path,
+            missing
+        );
+    }
+
+    for variant in variants(&database) {
+        let body = variant.to_string();
+        let header = valid_checksum(&body).to_string();
+        let problems = problems(&gzip(format!("{}\n{}", header, body).as_bytes()));
+        let valid = validate_schema(&variant, "schema/database.json");
+        assert_eq!(!rejected(&problems), valid, "{}: {:?}", body, problems);
+    }
+
+    for variant in variants(&valid_checksum(&body)) {
+        let header = variant.to_string();
+        let problems = problems(&gzip(format!("{}\n{}", header, body).as_bytes()));
+        let valid = validate_schema(&variant, "schema/checksum.json");
+        assert_eq!(!rejected(&problems), valid, "{}: {:?}", header, problems);
+    }
+}
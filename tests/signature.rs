@@ -0,0 +1,142 @@
+use integrity_checker::database::{Database, DatabaseChecksum, Encoding};
+use integrity_checker::error::Error;
+use integrity_checker::signature::{signature_path, PublicKey, SecretKey, Signature};
+
+use tempfile::tempdir;
+
+mod common;
+use common::{build, BLAKE2B, NONE, SHA2};
+
+const ENCODINGS: &[Encoding] = &[Encoding::Json, Encoding::Cbor, Encoding::Indexed];
+
+#[test]
+fn sign_and_verify() {
+    let key = SecretKey::generate().unwrap();
+    for features in &[SHA2, BLAKE2B] {
+        for encoding in ENCODINGS {
+            let db = build("tests/changes_edit/before", *features);
+            let (bytes, checksum) = db
+                .dump_with_checksum(Vec::new(), *features, *encoding, None)
+                .unwrap();
+            let signature = Signature::sign(&checksum, &key).unwrap();
+
+            let (loaded, loaded_checksum) = Database::load_with_checksum(&bytes[..], None).unwrap();
+            assert_eq!(db, loaded);
+            signature
+                .verify(&loaded_checksum, &key.public_key())
+                .unwrap();
+            assert_eq!(signature.signer().unwrap(), key.public_key());
+        }
+    }
+}
+
+#[test]
+fn wrong_key() {
+    let key = SecretKey::generate().unwrap();
+    let other = SecretKey::generate().unwrap();
+    let db = build("tests/changes_edit/before", SHA2);
+    let (_, checksum) = db
+        .dump_with_checksum(Vec::new(), SHA2, Encoding::Json, None)
+        .unwrap();
+    let signature = Signature::sign(&checksum, &other).unwrap();
+    match signature.verify(&checksum, &key.public_key()) {
+        Err(Error::SignatureMismatch) => (),
+        result => panic!("expected signature mismatch, got {:?}", result),
+    }
+}
+
+#[test]
+fn rebuilt_database() {
+    // Rebuilding the database after changing files doesn't preserve
+    // the signature
+    let key = SecretKey::generate().unwrap();
+    let before = build("tests/changes_edit/before", SHA2);
+    let after = build("tests/changes_edit/after", SHA2);
+    let (_, checksum) = before
+        .dump_with_checksum(Vec::new(), SHA2, Encoding::Json, None)
+        .unwrap();
+    let signature = Signature::sign(&checksum, &key).unwrap();
+    let (_, checksum) = after
+        .dump_with_checksum(Vec::new(), SHA2, Encoding::Json, None)
+        .unwrap();
+    match signature.verify(&checksum, &key.public_key()) {
+        Err(Error::SignatureMismatch) => (),
+        result => panic!("expected signature mismatch, got {:?}", result),
+    }
+}
+
+#[test]
+fn hashless_database() {
+    // A database built without hashes is still checksummed with one, so
+    // its signature covers the contents
+    let key = SecretKey::generate().unwrap();
+    let db = build("tests/changes_edit/before", NONE);
+    let (_, checksum) = db
+        .dump_with_checksum(Vec::new(), NONE, Encoding::Json, None)
+        .unwrap();
+    let value = serde_json::to_value(&checksum).unwrap();
+    assert!(value.get("sha2-512/256").is_some());
+    Signature::sign(&checksum, &key).unwrap();
+
+    let after = build("tests/changes_edit/after", NONE);
+    let (_, after_checksum) = after
+        .dump_with_checksum(Vec::new(), NONE, Encoding::Json, None)
+        .unwrap();
+    let signature = Signature::sign(&checksum, &key).unwrap();
+    match signature.verify(&after_checksum, &key.public_key()) {
+        Err(Error::SignatureMismatch) => (),
+        result => panic!("expected signature mismatch, got {:?}", result),
+    }
+
+    // A checksum of only the size is neither signed nor verified
+    let size_only: DatabaseChecksum = serde_json::from_str(r#"{"size": 1024}"#).unwrap();
+    match Signature::sign(&size_only, &key) {
+        Err(Error::MissingHash) => (),
+        result => panic!("expected missing hash, got {:?}", result),
+    }
+    match signature.verify(&size_only, &key.public_key()) {
+        Err(Error::MissingHash) => (),
+        result => panic!("expected missing hash, got {:?}", result),
+    }
+}
+
+#[test]
+fn key_and_signature_files() {
+    let dir = tempdir().unwrap();
+    let secret_path = dir.path().join("key");
+    let public_path = dir.path().join("key.pub");
+    let db_path = dir.path().join("db.json.gz");
+
+    let key = SecretKey::generate().unwrap();
+    key.save(&secret_path, false).unwrap();
+    key.public_key().save(&public_path, false).unwrap();
+    assert!(key.save(&secret_path, false).is_err());
+
+    let loaded = SecretKey::load(&secret_path).unwrap();
+    let public = PublicKey::load(&public_path).unwrap();
+    assert_eq!(loaded.public_key(), public);
+
+    match Signature::load(signature_path(&db_path)) {
+        Err(Error::MissingSignature) => (),
+        result => panic!("expected missing signature, got {:?}", result),
+    }
+
+    let db = build("tests/changes_edit/before", SHA2);
+    let (_, checksum) = db
+        .dump_with_checksum(Vec::new(), SHA2, Encoding::Json, None)
+        .unwrap();
+    Signature::sign(&checksum, &loaded)
+        .unwrap()
+        .save(signature_path(&db_path))
+        .unwrap();
+    assert!(dir.path().join("db.json.gz.sig").exists());
+    Signature::load(signature_path(&db_path))
+        .unwrap()
+        .verify(&checksum, &public)
+        .unwrap();
+
+    match PublicKey::load(&secret_path) {
+        Err(_) => (),
+        Ok(_) => panic!("loaded a secret key as a public key"),
+    }
+}
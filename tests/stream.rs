@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use integrity_checker::database::{
+    Database, DirectoryDigest, Encoding, Entry, EntryVisitor, Features, Metrics,
+};
+use integrity_checker::error::Error;
+
+mod common;
+use common::{build, gunzip, gzip, ALL_BLOCKS, BLAKE2B, SHA2};
+
+fn dump(db: &Database, features: Features, encoding: Encoding) -> Vec<u8> {
+    db.dump(Vec::new(), features, encoding).unwrap()
+}
+
+const HASH_FEATURES: &[Features] = &[SHA2, BLAKE2B, ALL_BLOCKS];
+const ENCODINGS: &[Encoding] = &[Encoding::Json, Encoding::Cbor];
+
+#[derive(Default)]
+struct Recorder {
+    open: Vec<PathBuf>,
+    directories: Vec<PathBuf>,
+    digests: Vec<(PathBuf, DirectoryDigest)>,
+    files: Vec<(PathBuf, Metrics)>,
+}
+
+impl EntryVisitor for Recorder {
+    fn enter_directory(&mut self, path: &Path) {
+        self.open.push(path.to_owned());
+        self.directories.push(path.to_owned());
+    }
+
+    fn visit_digest(&mut self, path: &Path, digest: &DirectoryDigest) {
+        self.digests.push((path.to_owned(), digest.clone()));
+    }
+
+    fn leave_directory(&mut self, path: &Path) {
+        assert_eq!(self.open.pop().as_deref(), Some(path));
+    }
+
+    fn visit_file(&mut self, path: &Path, metrics: &Metrics) {
+        assert_eq!(self.open.last().map(|p| p.as_path()), path.parent());
+        self.files.push((path.to_owned(), metrics.clone()));
+    }
+}
+
+#[test]
+fn visit_matches_load() {
+    for features in HASH_FEATURES {
+        for encoding in ENCODINGS {
+            let db = build("tests/changes_delete_dir/before", *features);
+            let bytes = dump(&db, *features, *encoding);
+
+            let mut recorder = Recorder::default();
+            Database::visit(&bytes[..], None, &mut recorder).unwrap();
+            assert!(recorder.open.is_empty());
+            assert_eq!(recorder.directories[0], Path::new(""));
+            assert_eq!(recorder.digests[0].0, Path::new(""));
+            assert_eq!(Some(&recorder.digests[0].1), db.digest());
+            assert!(recorder.directories.len() > 1);
+            assert_eq!(recorder.digests.len(), recorder.directories.len());
+
+            assert!(!recorder.files.is_empty());
+            for (path, metrics) in &recorder.files {
+                match db.lookup(path) {
+                    Some(Entry::File(expected)) => assert_eq!(expected, metrics),
+                    entry => panic!("expected file at {:?}, got {:?}", path, entry),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn stream_load_round_trip() {
+    for features in HASH_FEATURES {
+        for encoding in ENCODINGS {
+            let db = build("tests/changes_edit_blocks/before", *features);
+            let bytes = dump(&db, *features, *encoding);
+            assert_eq!(db, Database::load(&bytes[..]).unwrap());
+        }
+    }
+}
+
+fn corrupt(bytes: &[u8], index: usize) -> Vec<u8> {
+    let mut contents = gunzip(bytes);
+    let index = contents.len() - index;
+    contents[index] ^= 0x80;
+    gzip(&contents)
+}
+
+#[test]
+fn corruption_is_reported_as_mismatch() {
+    for encoding in ENCODINGS {
+        let db = build("tests/changes_edit/before", SHA2);
+        let bytes = dump(&db, SHA2, *encoding);
+
+        // Corrupting the final byte may or may not leave the database
+        // decodable, but it must be detected either way
+        for index in 1..4 {
+            let corrupted = corrupt(&bytes, index);
+            match Database::load(&corrupted[..]) {
+                Err(Error::ChecksumMismatch) => (),
+                result => panic!("expected checksum mismatch, got {:?}", result),
+            }
+            match Database::visit(&corrupted[..], None, &mut Recorder::default()) {
+                Err(Error::ChecksumMismatch) => (),
+                result => panic!("expected checksum mismatch, got {:?}", result),
+            }
+        }
+    }
+}
+
+#[test]
+fn encoding_mismatch() {
+    let db = build("tests/changes_edit/before", SHA2);
+    let json = dump(&db, SHA2, Encoding::Json);
+    let cbor = dump(&db, SHA2, Encoding::Cbor);
+    assert!(matches!(
+        Database::load_cbor(&json[..]),
+        Err(Error::ParseError)
+    ));
+    assert!(matches!(
+        Database::load_json(&cbor[..]),
+        Err(Error::ParseError)
+    ));
+}
@@ -1,9 +1,9 @@
-use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 
-use integrity_checker::database::{Database, Features};
+use integrity_checker::database::{Database, Encoding, Features};
 use integrity_checker::error::Error;
+use integrity_checker::mac::MacKey;
 
 use flate2::read::GzDecoder;
 
@@ -11,18 +11,8 @@ use serde_json::Value;
 
 use tempfile::tempfile;
 
-use valico::json_schema;
-
-fn validate_schema(data: &[u8], schema_path: impl AsRef<Path>) -> Result<bool, Error> {
-    let instance: Value = serde_json::from_slice(data)?;
-
-    let f = File::open(schema_path)?;
-    let schema: Value = serde_json::from_reader(f)?;
-
-    let mut scope = json_schema::Scope::new();
-    let schema = scope.compile_and_return(schema, true).unwrap();
-    Ok(schema.validate(&instance).is_valid())
-}
+mod common;
+use common::{validate_schema, ALL_FEATURES, BLOCK_FEATURES, SHA256};
 
 fn validate(path: impl AsRef<Path>, features: Features) -> Result<bool, Error> {
     let threads = 1;
@@ -34,6 +24,10 @@ fn validate(path: impl AsRef<Path>, features: Features) -> Result<bool, Error> {
     let f = tempfile()?;
     let mut f = db.dump_json(f, features)?;
     f.seek(SeekFrom::Start(0))?;
+    validate_dump(f)
+}
+
+fn validate_dump(f: impl Read) -> Result<bool, Error> {
     let mut d = GzDecoder::new(f);
     let mut bytes = Vec::new();
     d.read_to_end(&mut bytes)?;
@@ -46,29 +40,12 @@ fn validate(path: impl AsRef<Path>, features: Features) -> Result<bool, Error> {
         None => return Err(Error::ParseError),
     };
 
-    Ok(validate_schema(&bytes[..index], "schema/checksum.json")?
-        && validate_schema(&bytes[index + 1..], "schema/database.json")?)
+    let checksum: Value = serde_json::from_slice(&bytes[..index])?;
+    let database: Value = serde_json::from_slice(&bytes[index + 1..])?;
+    Ok(validate_schema(&checksum, "schema/checksum.json")
+        && validate_schema(&database, "schema/database.json"))
 }
 
-const NONE: Features = Features {
-    sha2: false,
-    blake2b: false,
-};
-const SHA2: Features = Features {
-    sha2: true,
-    blake2b: false,
-};
-const BLAKE2B: Features = Features {
-    sha2: false,
-    blake2b: true,
-};
-const ALL: Features = Features {
-    sha2: true,
-    blake2b: true,
-};
-
-const ALL_FEATURES: &[Features] = &[NONE, SHA2, BLAKE2B, ALL];
-
 #[test]
 fn no_changes() {
     for features in ALL_FEATURES {
@@ -85,6 +62,12 @@ fn changes_edit() {
     }
 }
 
+#[test]
+fn sha256() {
+    assert!(validate("tests/changes_edit/before", SHA256).unwrap());
+    assert!(validate("tests/changes_edit/after", SHA256).unwrap());
+}
+
 #[test]
 fn changes_edit_no_size_change() {
     for features in ALL_FEATURES {
@@ -156,3 +139,103 @@ fn suspicious_nonascii() {
         assert!(validate("tests/suspicious_nonascii/after", *features).unwrap());
     }
 }
+
+#[test]
+fn converted_line_endings() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/converted_line_endings/before", *features).unwrap());
+        assert!(validate("tests/converted_line_endings/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn converted_line_endings_split() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/converted_line_endings_split/before", *features).unwrap());
+        assert!(validate("tests/converted_line_endings_split/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn changes_line_endings_same_size() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/changes_line_endings_same_size/before", *features).unwrap());
+        assert!(validate("tests/changes_line_endings_same_size/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn suspicious_entropy() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/suspicious_entropy/before", *features).unwrap());
+        assert!(validate("tests/suspicious_entropy/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn mass_entropy() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/mass_entropy/before", *features).unwrap());
+        assert!(validate("tests/mass_entropy/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn mass_extension() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/mass_extension/before", *features).unwrap());
+        assert!(validate("tests/mass_extension/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn mass_notes() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/mass_notes/before", *features).unwrap());
+        assert!(validate("tests/mass_notes/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn changes_readme() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/changes_readme/before", *features).unwrap());
+        assert!(validate("tests/changes_readme/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn suspicious_format() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/suspicious_format/before", *features).unwrap());
+        assert!(validate("tests/suspicious_format/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn changes_edit_blocks() {
+    for features in ALL_FEATURES.iter().chain(BLOCK_FEATURES) {
+        assert!(validate("tests/changes_edit_blocks/before", *features).unwrap());
+        assert!(validate("tests/changes_edit_blocks/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn suspicious_zeros() {
+    for features in ALL_FEATURES {
+        assert!(validate("tests/suspicious_zeros/before", *features).unwrap());
+        assert!(validate("tests/suspicious_zeros/after", *features).unwrap());
+    }
+}
+
+#[test]
+fn keyed_checksum() {
+    let key = MacKey::new(vec![7; 32]).unwrap();
+    for features in ALL_FEATURES {
+        let db = Database::build("tests/changes_edit/before", *features, 1, false).unwrap();
+        let (bytes, _) = db
+            .dump_with_checksum(Vec::new(), *features, Encoding::Json, Some(&key))
+            .unwrap();
+        assert!(validate_dump(&bytes[..]).unwrap());
+    }
+}